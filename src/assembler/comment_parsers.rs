@@ -0,0 +1,99 @@
+use nom::{
+    branch::alt,
+    bytes::complete::{is_not, tag, take_until},
+    character::complete::multispace1,
+    combinator::{opt, recognize},
+    multi::many0,
+    sequence::{delimited, pair},
+    IResult,
+};
+
+/// A `; ...` or `#! ...` comment running to the end of the line, or a `/* ... */` block comment. The `!` keeps
+/// line comments apart from `#` immediates such as `#10`
+pub fn comment(input: &str) -> IResult<&str, &str> {
+    alt((
+        recognize(pair(alt((tag(";"), tag("#!"))), opt(is_not("\r\n")))),
+        recognize(delimited(tag("/*"), take_until("*/"), tag("*/"))),
+    ))(input)
+}
+
+/// Any run of whitespace and comments, including none at all
+pub fn whitespace(input: &str) -> IResult<&str, &str> {
+    recognize(many0(alt((multispace1, comment))))(input)
+}
+
+/// Whether a `/* ... */` block comment is still open at the end of `line`, given whether one was open at its start.
+/// Text in quotes and line comments can't open one. For the passes that read the source a line at a time before it
+/// is parsed
+pub fn ends_in_block_comment(line: &str, mut in_comment: bool) -> bool {
+    let mut quoted = false;
+    let mut i = 0;
+    while i < line.len() {
+        let rest = &line[i..];
+        if in_comment {
+            match rest.find("*/") {
+                Some(end) => {
+                    in_comment = false;
+                    i += end + 2;
+                    continue;
+                }
+                None => return true,
+            }
+        }
+        if rest.starts_with('\'') {
+            quoted = !quoted;
+        } else if !quoted {
+            if rest.starts_with("/*") {
+                in_comment = true;
+                i += 2;
+                continue;
+            }
+            if rest.starts_with(';') || rest.starts_with("#!") {
+                return false;
+            }
+        }
+        i += rest.chars().next().map_or(1, char::len_utf8);
+    }
+    in_comment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_comments() {
+        assert_eq!(comment("; a comment\nhlt"), Ok(("\nhlt", "; a comment")));
+        assert_eq!(comment("#! also a comment"), Ok(("", "#! also a comment")));
+        assert_eq!(comment(";"), Ok(("", ";")));
+        assert!(comment("#10").is_err());
+    }
+
+    #[test]
+    fn test_block_comment() {
+        assert_eq!(
+            comment("/* one\ntwo */ hlt"),
+            Ok((" hlt", "/* one\ntwo */"))
+        );
+        assert!(comment("/* never closed").is_err());
+    }
+
+    #[test]
+    fn test_ends_in_block_comment() {
+        assert!(ends_in_block_comment("hlt /* until", false));
+        assert!(ends_in_block_comment(".include 'x.iasm'", true));
+        assert!(!ends_in_block_comment("still */ hlt", true));
+        assert!(!ends_in_block_comment("/* a */ hlt /* b */", false));
+        assert!(!ends_in_block_comment("hi: .asciiz '/*'", false));
+        assert!(!ends_in_block_comment("hlt ; /* not a block", false));
+    }
+
+    #[test]
+    fn test_whitespace() {
+        assert_eq!(
+            whitespace("  ; one\n /* two */\n#! three\nhlt"),
+            Ok(("hlt", "  ; one\n /* two */\n#! three\n"))
+        );
+        assert_eq!(whitespace("#5"), Ok(("#5", "")));
+    }
+}
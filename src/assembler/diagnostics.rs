@@ -0,0 +1,154 @@
+use crate::assembler::sources::Sources;
+use crate::assembler::span::Span;
+use crate::assembler::AssemblerError;
+
+/// Renders an error the way rustc does: the message, where it is, and the offending source line with the span
+/// underlined, followed by the error's notes. `source` must be the text the error's span was taken from
+pub fn render(source: &str, error: &AssemblerError) -> String {
+    render_with(error, &|span| (None, source, span))
+}
+
+/// Renders an error in the combined text of `sources`, naming the file each span is in
+pub fn render_in(sources: &Sources, error: &AssemblerError) -> String {
+    render_with(error, &|span| {
+        let (file, span) = sources.locate(span);
+        (Some(file.name.as_str()), file.text.as_str(), span)
+    })
+}
+
+/// Renders an error, with `locate` giving the name of the file each span is in, its text and the span in it
+fn render_with<'a>(
+    error: &AssemblerError,
+    locate: &dyn Fn(Span) -> (Option<&'a str>, &'a str, Span),
+) -> String {
+    let mut out = format!("error: {}\n", error);
+    if let Some(span) = error.span() {
+        out.push_str(&snippet(locate(span)));
+    }
+    for (note, span) in error.notes() {
+        out.push_str(&format!("note: {}\n", note));
+        out.push_str(&snippet(locate(span)));
+    }
+    out
+}
+
+/// The location of `span` and its source line with the span underlined
+fn snippet((name, source, span): (Option<&str>, &str, Span)) -> String {
+    let mut out = String::new();
+
+    let line_start = source[..span.start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = source[span.start..]
+        .find('\n')
+        .map_or(source.len(), |i| span.start + i);
+    let line = source[line_start..line_end].trim_end_matches('\r');
+
+    // Keep tabs in the padding so the carets line up with the text above them however tabs are displayed
+    let padding: String = source[line_start..span.start]
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    // Spans running past the end of the line are only underlined up to it
+    let underlined = source[span.start..span.end.clamp(span.start, line_end)]
+        .chars()
+        .count()
+        .max(1);
+
+    let number = span.line.to_string();
+    let gutter = " ".repeat(number.len());
+    let file = name.map_or(String::new(), |name| format!("{}:", name));
+    out.push_str(&format!(
+        "{}--> {}{}:{}\n",
+        gutter, file, span.line, span.column
+    ));
+    out.push_str(&format!("{} |\n", gutter));
+    out.push_str(&format!("{} | {}\n", number, line));
+    out.push_str(&format!(
+        "{} | {}{}\n",
+        gutter,
+        padding,
+        "^".repeat(underlined)
+    ));
+    out
+}
+
+/// Renders every error, separated by blank lines
+pub fn render_all(source: &str, errors: &[AssemblerError]) -> String {
+    errors
+        .iter()
+        .map(|error| render(source, error))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Renders every error in the combined text of `sources`, separated by blank lines
+pub fn render_all_in(sources: &Sources, errors: &[AssemblerError]) -> String {
+    errors
+        .iter()
+        .map(|error| render_in(sources, error))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_underlines_span() {
+        let source = ".code\n  lod $0 #1\nhlt\n";
+        let error = AssemblerError::UnknownOpcode {
+            span: Span::new(source, 8, 11),
+        };
+        assert_eq!(
+            render(source, &error),
+            "error: unknown opcode\n --> 2:3\n  |\n2 |   lod $0 #1\n  |   ^^^\n"
+        );
+    }
+
+    #[test]
+    fn test_render_keeps_tabs() {
+        let source = "\thlt $1";
+        let error = AssemblerError::UnknownOpcode {
+            span: Span::new(source, 5, 7),
+        };
+        assert_eq!(render(source, &error).lines().last(), Some("  | \t    ^^"));
+    }
+
+    #[test]
+    fn test_render_notes() {
+        let source = ".macro two a, b\n.endm\ntwo $1\n";
+        let error = AssemblerError::WrongMacroArgumentCount {
+            name: "two".to_string(),
+            expected: 2,
+            found: 1,
+            span: Span::new(source, 22, 28),
+            definition: Span::new(source, 0, 15),
+        };
+        assert_eq!(
+            render(source, &error),
+            "error: the macro `two` takes 2 arguments, found 1\n --> 3:1\n  |\n3 | two $1\n  | ^^^^^^\n\
+             note: the macro `two` is defined here\n --> 1:1\n  |\n1 | .macro two a, b\n  | ^^^^^^^^^^^^^^^\n"
+        );
+    }
+
+    #[test]
+    fn test_render_in_names_file() {
+        let sources = Sources::from_text("main.iasm", ".code\nlod $0\n");
+        let error = AssemblerError::UnknownOpcode {
+            span: Span::new(&sources.text, 6, 9),
+        };
+        assert_eq!(
+            render_in(&sources, &error),
+            "error: unknown opcode\n --> main.iasm:2:1\n  |\n2 | lod $0\n  | ^^^\n"
+        );
+    }
+
+    #[test]
+    fn test_render_without_span() {
+        let error = AssemblerError::InsufficientSections;
+        assert_eq!(
+            render("", &error),
+            "error: a program needs both a `.data` and a `.code` section\n"
+        );
+    }
+}
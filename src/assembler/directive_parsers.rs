@@ -3,17 +3,18 @@ use crate::assembler::operand_parser::{integer_operand, operand};
 use crate::assembler::register_parser::register;
 use crate::assembler::Token;
 
-use nom::character::complete::{alpha1, multispace0};
-use nom::character::streaming::space0;
+use crate::assembler::span::token;
+
+use nom::character::complete::{alpha1, alphanumeric1};
 use nom::{
     branch::alt,
     bytes::complete::tag,
-    character::complete::digit1,
-    combinator::{eof, map, map_res, opt, value},
-    sequence::{delimited, preceded, tuple},
+    combinator::{map, not, opt},
+    sequence::{preceded, terminated, tuple},
     IResult,
 };
 
+use super::label_parsers::identifier;
 use super::instruction_parsers::AssemblerInstruction;
 use super::label_parsers::label_declaration;
 
@@ -25,37 +26,102 @@ fn directive_declaration(input: &str) -> IResult<&str, Token> {
 
 fn directive_combined(input: &str) -> IResult<&str, AssemblerInstruction> {
     map(
-        delimited(
-            multispace0,
-            tuple((
-                opt(label_declaration),
-                directive_declaration,
-                opt(operand),
-                opt(operand),
-                opt(operand),
-            )),
-            multispace0,
-        ),
-        |(label, directive, o1, o2, o3)| AssemblerInstruction {
-            opcode: None,
-            directive: Some(directive),
-            label: label,
-            operand1: o1,
-            operand2: o2,
-            operand3: o3,
+        tuple((
+            opt(token(input, label_declaration)),
+            token(input, directive_declaration),
+            opt(token(input, operand)),
+            opt(token(input, operand)),
+            opt(token(input, operand)),
+        )),
+        |(label, directive, o1, o2, o3)| {
+            AssemblerInstruction::from_tokens(label, None, Some(directive), [o1, o2, o3])
+        },
+    )(input)
+}
+
+/// `.equ NAME, value` or `.set NAME, value`, which declare a constant. The comma is optional
+fn constant_directive(input: &str) -> IResult<&str, AssemblerInstruction> {
+    map(
+        tuple((
+            token(
+                input,
+                map(
+                    terminated(
+                        preceded(tag("."), alt((tag("equ"), tag("set")))),
+                        not(alphanumeric1),
+                    ),
+                    |name: &str| Token::Directive {
+                        name: name.to_string(),
+                    },
+                ),
+            ),
+            token(
+                input,
+                map(identifier, |name: &str| Token::ConstantDeclaration {
+                    name: name.to_string(),
+                }),
+            ),
+            opt(token(input, tag(","))),
+            token(input, operand),
+        )),
+        |(directive, name, _, value)| {
+            AssemblerInstruction::from_tokens(
+                None,
+                None,
+                Some(directive),
+                [Some(name), Some(value), None],
+            )
+        },
+    )(input)
+}
+
+/// `.global name` or `.extern name`, which export a label from a relocatable object or import one into it
+fn symbol_directive(input: &str) -> IResult<&str, AssemblerInstruction> {
+    map(
+        tuple((
+            token(
+                input,
+                map(
+                    terminated(
+                        preceded(tag("."), alt((tag("global"), tag("extern")))),
+                        not(alphanumeric1),
+                    ),
+                    |name: &str| Token::Directive {
+                        name: name.to_string(),
+                    },
+                ),
+            ),
+            token(
+                input,
+                map(identifier, |name: &str| Token::LabelUsage {
+                    name: name.to_string(),
+                }),
+            ),
+        )),
+        |(directive, name)| {
+            AssemblerInstruction::from_tokens(
+                None,
+                None,
+                Some(directive),
+                [Some(name), None, None],
+            )
         },
     )(input)
 }
 
 pub fn directive(input: &str) -> IResult<&str, AssemblerInstruction> {
-    alt((directive_combined,))(input)
+    alt((constant_directive, symbol_directive, directive_combined))(input)
 }
 
 
 #[test]
 fn test_string_directive() {
-    let result = directive_combined("test: .asciiz 'Hello'");
-    assert_eq!(result.is_ok(), true);
+    use super::instruction_parsers::InstructionSpans;
+    use super::span::Span;
+
+    let source = "test: .asciiz 'Hello'";
+    let result = directive_combined(source);
+    assert!(result.is_ok());
     let (_, directive) = result.unwrap();
 
     // Yes, this is the what the result should be
@@ -72,7 +138,68 @@ fn test_string_directive() {
                 }),
             operand1: Some(Token::IrString { name: "Hello".to_string() }),
             operand2: None,
-            operand3: None };
+            operand3: None,
+            spans: InstructionSpans {
+                instruction: Span::new(source, 0, 21),
+                label: Some(Span::new(source, 0, 5)),
+                directive: Some(Span::new(source, 6, 13)),
+                operands: [Some(Span::new(source, 14, 21)), None, None],
+                ..Default::default()
+            } };
 
     assert_eq!(directive, correct_instruction);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_constant_directive() {
+    use super::expression::{Expression, Operator};
+
+    let (rest, equ) = directive(".equ BUF_SIZE, #256*2").unwrap();
+    assert_eq!(rest, "");
+    assert_eq!(
+        equ.directive,
+        Some(Token::Directive {
+            name: "equ".to_string()
+        })
+    );
+    assert_eq!(
+        equ.operand1,
+        Some(Token::ConstantDeclaration {
+            name: "BUF_SIZE".to_string()
+        })
+    );
+    assert_eq!(
+        equ.operand2,
+        Some(Token::Expression {
+            expression: Expression::Binary {
+                operator: Operator::Multiply,
+                left: Box::new(Expression::Number(256)),
+                right: Box::new(Expression::Number(2)),
+            }
+        })
+    );
+
+    // The comma is optional
+    let (_, set) = directive(".set SIZE @end").unwrap();
+    assert_eq!(
+        set.operand2,
+        Some(Token::LabelUsage {
+            name: "end".to_string()
+        })
+    );
+}
+
+#[test]
+fn test_symbol_directive() {
+    let (rest, global) = directive(".global main").unwrap();
+    assert_eq!(rest, "");
+    assert_eq!(global.get_directive_name(), Some("global".to_string()));
+    assert_eq!(
+        global.operand1,
+        Some(Token::LabelUsage {
+            name: "main".to_string()
+        })
+    );
+    let (_, extern_) = directive(".extern print").unwrap();
+    assert_eq!(extern_.get_directive_name(), Some("extern".to_string()));
+}
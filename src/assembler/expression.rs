@@ -0,0 +1,295 @@
+use nom::{
+    branch::alt,
+    bytes::complete::tag,
+    character::complete::one_of,
+    combinator::map,
+    sequence::{delimited, pair, preceded},
+    IResult,
+};
+
+use crate::assembler::label_parsers::identifier;
+use crate::assembler::operand_parser::integer_literal;
+use crate::assembler::symbols::SymbolTable;
+
+/// A value computed while assembling, such as `BUFSIZE*2+1` or `@end-@start`
+#[derive(Debug, PartialEq, Clone)]
+pub enum Expression {
+    Number(i64),
+    /// A constant declared with `.equ` or `.set`
+    Constant(String),
+    /// The offset of a label
+    Label(String),
+    Negate(Box<Expression>),
+    Binary {
+        operator: Operator,
+        left: Box<Expression>,
+        right: Box<Expression>,
+    },
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+}
+
+/// A name an expression refers to
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Name<'a> {
+    Constant(&'a str),
+    Label(&'a str),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ExpressionError {
+    Undefined { name: String },
+    Overflow,
+    DivisionByZero,
+}
+
+impl Expression {
+    /// Computes the value, asking `resolve` for the value of every name. Arithmetic is on 64 bits and overflowing
+    /// it is an error; the result is checked against where it is used by the caller
+    pub fn evaluate<F>(&self, resolve: &mut F) -> Result<i64, ExpressionError>
+    where
+        F: FnMut(Name) -> Result<i64, ExpressionError>,
+    {
+        match self {
+            Expression::Number(value) => Ok(*value),
+            Expression::Constant(name) => resolve(Name::Constant(name)),
+            Expression::Label(name) => resolve(Name::Label(name)),
+            Expression::Negate(operand) => operand
+                .evaluate(resolve)?
+                .checked_neg()
+                .ok_or(ExpressionError::Overflow),
+            Expression::Binary {
+                operator,
+                left,
+                right,
+            } => {
+                let left = left.evaluate(resolve)?;
+                let right = right.evaluate(resolve)?;
+                let result = match operator {
+                    Operator::Add => left.checked_add(right),
+                    Operator::Subtract => left.checked_sub(right),
+                    Operator::Multiply => left.checked_mul(right),
+                    Operator::Divide | Operator::Remainder if right == 0 => {
+                        return Err(ExpressionError::DivisionByZero)
+                    }
+                    Operator::Divide => left.checked_div(right),
+                    Operator::Remainder => left.checked_rem(right),
+                };
+                result.ok_or(ExpressionError::Overflow)
+            }
+        }
+    }
+
+    /// The labels used in the expression, in order
+    pub fn labels(&self) -> Vec<&str> {
+        match self {
+            Expression::Number(_) | Expression::Constant(_) => vec![],
+            Expression::Label(name) => vec![name],
+            Expression::Negate(operand) => operand.labels(),
+            Expression::Binary { left, right, .. } => {
+                let mut labels = left.labels();
+                labels.extend(right.labels());
+                labels
+            }
+        }
+    }
+
+    /// Splits an expression of the form `@label`, `@label+x`, `x+@label` or `@label-x`, where `x` uses no labels,
+    /// into the label and `x`. These are the expressions whose value moves with the label when code is relocated
+    pub fn label_term(&self) -> Option<(&str, Expression)> {
+        match self {
+            Expression::Label(name) => Some((name, Expression::Number(0))),
+            Expression::Binary {
+                operator: operator @ (Operator::Add | Operator::Subtract),
+                left,
+                right,
+            } => {
+                let (labelled, other, operator) =
+                    match (left.labels().is_empty(), right.labels().is_empty()) {
+                        (false, true) => (left, right, *operator),
+                        (true, false) if *operator == Operator::Add => (right, left, Operator::Add),
+                        _ => return None,
+                    };
+                let (name, addend) = labelled.label_term()?;
+                Some((
+                    name,
+                    Expression::Binary {
+                        operator,
+                        left: Box::new(addend),
+                        right: other.clone(),
+                    },
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    /// Computes the value with labels and constants taken from `symbols`
+    pub fn evaluate_with(&self, symbols: &SymbolTable) -> Result<i64, ExpressionError> {
+        self.evaluate(&mut |name| {
+            let value = match name {
+                Name::Constant(name) => symbols.constant_value(name),
+                Name::Label(name) => symbols.symbol_value(name).map(|offset| offset as i64),
+            };
+            value.ok_or_else(|| ExpressionError::Undefined {
+                name: match name {
+                    Name::Constant(name) | Name::Label(name) => name.to_string(),
+                },
+            })
+        })
+    }
+}
+
+/// An expression with `+`, `-`, `*`, `/`, `%`, unary `-` and parentheses, over integer literals, constant names and
+/// `@label`s. It can't contain whitespace, since whitespace separates operands
+pub fn expression(input: &str) -> IResult<&str, Expression> {
+    let (input, first) = term(input)?;
+    fold(input, first, "+-", term)
+}
+
+fn term(input: &str) -> IResult<&str, Expression> {
+    let (input, first) = unary(input)?;
+    fold(input, first, "*/%", unary)
+}
+
+/// Applies the left-associative operators in `operators` to `first` and whatever `operand` parses next
+fn fold<'a>(
+    mut input: &'a str,
+    mut left: Expression,
+    operators: &'static str,
+    operand: fn(&'a str) -> IResult<&'a str, Expression>,
+) -> IResult<&'a str, Expression> {
+    while let Ok((rest, (symbol, right))) = pair(one_of(operators), operand)(input) {
+        let operator = match symbol {
+            '+' => Operator::Add,
+            '-' => Operator::Subtract,
+            '*' => Operator::Multiply,
+            '/' => Operator::Divide,
+            _ => Operator::Remainder,
+        };
+        left = Expression::Binary {
+            operator,
+            left: Box::new(left),
+            right: Box::new(right),
+        };
+        input = rest;
+    }
+    Ok((input, left))
+}
+
+fn unary(input: &str) -> IResult<&str, Expression> {
+    alt((
+        map(integer_literal, Expression::Number),
+        map(preceded(tag("-"), unary), |e| {
+            Expression::Negate(Box::new(e))
+        }),
+        atom,
+    ))(input)
+}
+
+fn atom(input: &str) -> IResult<&str, Expression> {
+    alt((
+        map(preceded(tag("@"), identifier), |name: &str| {
+            Expression::Label(name.to_string())
+        }),
+        map(identifier, |name| Expression::Constant(name.to_string())),
+        delimited(tag("("), expression, tag(")")),
+    ))(input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::symbols::{Symbol, SymbolType};
+
+    fn evaluate(source: &str, symbols: &SymbolTable) -> Result<i64, ExpressionError> {
+        let (rest, e) = expression(source).unwrap();
+        assert_eq!(rest, "");
+        e.evaluate_with(symbols)
+    }
+
+    #[test]
+    fn test_precedence_and_associativity() {
+        let symbols = SymbolTable::new();
+        assert_eq!(evaluate("2+3*4", &symbols), Ok(14));
+        assert_eq!(evaluate("(2+3)*4", &symbols), Ok(20));
+        assert_eq!(evaluate("10-4-3", &symbols), Ok(3));
+        assert_eq!(evaluate("-(2+3)%3", &symbols), Ok(-2));
+        assert_eq!(evaluate("0x10*-2", &symbols), Ok(-32));
+        assert_eq!(evaluate("'a'-'A'", &symbols), Ok(32));
+    }
+
+    #[test]
+    fn test_names() {
+        let mut symbols = SymbolTable::new();
+        symbols.add_symbol(Symbol::constant("BUF_SIZE".to_string(), 256));
+        symbols.add_symbol(Symbol::new("start".to_string(), SymbolType::Label, 100));
+        symbols.add_symbol(Symbol::new("end".to_string(), SymbolType::Label, 140));
+        assert_eq!(evaluate("BUF_SIZE*2+1", &symbols), Ok(513));
+        assert_eq!(evaluate("@end-@start", &symbols), Ok(40));
+        assert_eq!(evaluate("@start+8", &symbols), Ok(108));
+        assert_eq!(
+            evaluate("start", &symbols),
+            Err(ExpressionError::Undefined {
+                name: "start".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_arithmetic_errors() {
+        let symbols = SymbolTable::new();
+        assert_eq!(
+            evaluate("0x7FFF_FFFF_FFFF_FFFF+1", &symbols),
+            Err(ExpressionError::Overflow)
+        );
+        assert_eq!(
+            evaluate("1/(2-2)", &symbols),
+            Err(ExpressionError::DivisionByZero)
+        );
+        assert_eq!(
+            evaluate("1%0", &symbols),
+            Err(ExpressionError::DivisionByZero)
+        );
+    }
+
+    #[test]
+    fn test_label_term() {
+        let symbols = SymbolTable::new();
+        let split = |source: &str| {
+            let (_, e) = expression(source).unwrap();
+            e.label_term()
+                .map(|(name, addend)| (name.to_string(), addend.evaluate_with(&symbols)))
+        };
+        assert_eq!(split("@start"), Some(("start".to_string(), Ok(0))));
+        assert_eq!(split("@start+4*2-1"), Some(("start".to_string(), Ok(7))));
+        assert_eq!(split("8+@start"), Some(("start".to_string(), Ok(8))));
+        assert_eq!(split("@start-(1+2)"), Some(("start".to_string(), Ok(-3))));
+        assert_eq!(split("8-@start"), None);
+        assert_eq!(split("@end-@start"), None);
+        assert_eq!(split("@start*2"), None);
+        assert_eq!(split("1+2"), None);
+    }
+
+    #[test]
+    fn test_stops_at_whitespace() {
+        assert_eq!(
+            expression("1+2 $3"),
+            Ok((
+                " $3",
+                Expression::Binary {
+                    operator: Operator::Add,
+                    left: Box::new(Expression::Number(1)),
+                    right: Box::new(Expression::Number(2)),
+                }
+            ))
+        );
+    }
+}
@@ -1,20 +1,43 @@
 use nom::{
     branch::alt,
-    bytes::complete::tag,
-    character::complete::digit1,
-    combinator::{eof, map, map_res, opt, value},
-    sequence::{delimited, preceded, tuple},
+    combinator::{map, opt},
+    sequence::{terminated, tuple},
     IResult,
 };
 
+use crate::assembler::comment_parsers::whitespace;
 use crate::assembler::directive_parsers::directive;
+use crate::assembler::expression::Expression;
 use crate::assembler::opcode_parsers::*;
-use crate::assembler::operand_parser::{integer_operand, operand};
-use crate::assembler::register_parser::register;
-use crate::assembler::Token;
+use crate::assembler::operand_parser::operand;
+use crate::assembler::span::{token, LineIndex, Span};
+use crate::assembler::{AssemblerError, Token};
+use crate::instruction::{Instruction, Opcode, OperandKind};
+use std::ops::RangeInclusive;
 
 use super::{label_parsers::label_declaration, SymbolTable};
 
+/// The values a 16-bit immediate can have. Both signed and unsigned values are accepted, and encoded as their 16-bit
+/// pattern
+const IMM16_RANGE: RangeInclusive<i64> = i16::MIN as i64..=u16::MAX as i64;
+
+/// The values `load` accepts. Signed and unsigned 32-bit values are loaded as their 32-bit pattern
+const LOAD_RANGE: RangeInclusive<i64> = i32::MIN as i64..=u32::MAX as i64;
+
+/// The values `load` can load with a single instruction, as it sign-extends its immediate
+const SHORT_LOAD_RANGE: RangeInclusive<i64> = i16::MIN as i64..=i16::MAX as i64;
+
+/// The quarters of its register `fset` can replace, counting 16 bits each from the lowest
+const FSET_QUARTER_RANGE: RangeInclusive<i64> = 0..=3;
+
+/// The values the immediate operand at `slot` of `code` can have in a single instruction
+fn immediate_range(code: Opcode, slot: usize) -> RangeInclusive<i64> {
+    match (code, slot) {
+        (Opcode::FSET, 2) => FSET_QUARTER_RANGE,
+        _ => IMM16_RANGE,
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct AssemblerInstruction {
     pub opcode: Option<Token>,
@@ -23,31 +46,290 @@ pub struct AssemblerInstruction {
     pub operand1: Option<Token>,
     pub operand2: Option<Token>,
     pub operand3: Option<Token>,
+    pub spans: InstructionSpans,
 }
 
+/// Where an instruction and each of its tokens were found in the source
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct InstructionSpans {
+    pub instruction: Span,
+    pub label: Option<Span>,
+    pub opcode: Option<Span>,
+    pub directive: Option<Span>,
+    pub operands: [Option<Span>; 3],
+}
+
+pub type SpannedToken = Option<(Token, Span)>;
+
 impl AssemblerInstruction {
-    pub fn to_bytes(&self, symbols: &SymbolTable) -> Vec<u8> {
-        let mut results = vec![];
-        match self.opcode {
-            Some(Token::Op { code }) => match code {
-                _ => {
-                    results.push(code as u8);
+    /// Puts an instruction together from the tokens the parsers found, spanning from the first token to the last
+    pub fn from_tokens(
+        label: SpannedToken,
+        opcode: SpannedToken,
+        directive: SpannedToken,
+        operands: [SpannedToken; 3],
+    ) -> AssemblerInstruction {
+        let mut spans = InstructionSpans::default();
+        let all_spans = [&label, &opcode, &directive, &operands[0], &operands[1], &operands[2]];
+        let mut present = all_spans
+            .iter()
+            .filter_map(|t| t.as_ref().map(|(_, span)| *span));
+        if let Some(first) = present.next() {
+            spans.instruction = first.to(present.next_back().unwrap_or(first));
+        }
+        spans.label = label.as_ref().map(|(_, span)| *span);
+        spans.opcode = opcode.as_ref().map(|(_, span)| *span);
+        spans.directive = directive.as_ref().map(|(_, span)| *span);
+        for (slot, operand) in operands.iter().enumerate() {
+            spans.operands[slot] = operand.as_ref().map(|(_, span)| *span);
+        }
+
+        let [operand1, operand2, operand3] = operands.map(|t| t.map(|(token, _)| token));
+        AssemblerInstruction {
+            label: label.map(|(token, _)| token),
+            opcode: opcode.map(|(token, _)| token),
+            directive: directive.map(|(token, _)| token),
+            operand1,
+            operand2,
+            operand3,
+            spans,
+        }
+    }
+
+    /// Moves spans found relative to the start of this instruction to where the instruction sits in `source`
+    pub fn relocate(&mut self, source: &str, offset: usize, lines: &LineIndex) {
+        let relocate = |span: &mut Span| {
+            *span = lines.span(source, span.start + offset, span.end + offset);
+        };
+        relocate(&mut self.spans.instruction);
+        for span in [
+            &mut self.spans.label,
+            &mut self.spans.opcode,
+            &mut self.spans.directive,
+        ]
+        .into_iter()
+        .chain(self.spans.operands.iter_mut())
+        .flatten()
+        {
+            relocate(span);
+        }
+    }
+
+    /// Turns a jump through a register that is given a label instead, such as `jeq @loop`, into the form of the jump
+    /// that takes the label directly
+    fn select_direct_form(&mut self) {
+        if let (
+            Some(Token::Op { code }),
+            Some(Token::LabelUsage { .. } | Token::Expression { .. }),
+        ) = (&mut self.opcode, &self.operand1)
+        {
+            if let Some(direct) = code.direct_form() {
+                *code = direct;
+            }
+        }
+    }
+
+    /// Checks the instruction against the operands its opcode takes, returning every mismatch found
+    pub fn check_operands(&self) -> Vec<AssemblerError> {
+        let code = match self.opcode {
+            Some(Token::Op { code }) => code,
+            _ => return vec![],
+        };
+        let info = match code.info() {
+            Some(info) => info,
+            None => {
+                return vec![AssemblerError::UnknownOpcode {
+                    span: self.spans.opcode.unwrap_or(self.spans.instruction),
+                }]
+            }
+        };
+
+        let mut errors = vec![];
+        let operands = self.operands();
+        if operands.len() != info.operands.len() {
+            errors.push(AssemblerError::WrongOperandCount {
+                opcode: code,
+                expected: info.operands.len(),
+                found: operands.len(),
+                span: self.spans.instruction,
+            });
+        }
+        for (slot, (operand, expected)) in operands.iter().zip(info.operands).enumerate() {
+            let span = self.spans.operands[slot].unwrap_or(self.spans.instruction);
+            let range = if code == Opcode::LOAD {
+                LOAD_RANGE
+            } else {
+                immediate_range(code, slot)
+            };
+            if let (OperandKind::Imm16, Token::IntegerOperand { value }) = (expected, operand) {
+                if !range.contains(value) {
+                    errors.push(AssemblerError::IntegerOutOfRange {
+                        value: (*value).into(),
+                        min: (*range.start()).into(),
+                        max: (*range.end()).into(),
+                        span,
+                    });
                 }
-            },
-            _ => {
-                println!("Non-opcode found in opcode field");
-                std::process::exit(1);
             }
+            let matches = matches!(
+                (expected, operand),
+                (OperandKind::Register, Token::Register { .. })
+                    | (OperandKind::FloatRegister, Token::FloatRegister { .. })
+                    | (OperandKind::Float, Token::IntegerOperand { .. })
+                    | (OperandKind::Float, Token::FloatOperand { .. })
+                    | (OperandKind::Imm16, Token::IntegerOperand { .. })
+                    | (OperandKind::Imm16, Token::LabelUsage { .. })
+                    | (OperandKind::Imm16, Token::Expression { .. })
+                    | (OperandKind::Label, Token::LabelUsage { .. })
+                    | (OperandKind::Label, Token::Expression { .. })
+            );
+            if !matches {
+                errors.push(AssemblerError::WrongOperandKind {
+                    opcode: code,
+                    position: slot + 1,
+                    expected: *expected,
+                    span,
+                });
+            }
+        }
+        errors
+    }
+
+    /// The operands that are present, in order
+    pub fn operands(&self) -> Vec<&Token> {
+        [&self.operand1, &self.operand2, &self.operand3]
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    /// Whether this is a `load` assembled as `load` of the low half of its value followed by `lui` of the high half.
+    /// Only integers that fit in a sign-extended 16 bits take a single `load`: labels, constants and expressions
+    /// take both, since their value is not known yet when the offsets of the labels are worked out. The assembler
+    /// turns the ones it already knows into integers first, see `Assembler::fold_load_operand`
+    pub fn is_long_load(&self) -> bool {
+        match (&self.opcode, &self.operand2) {
+            (Some(Token::Op { code: Opcode::LOAD }), Some(Token::IntegerOperand { value })) => {
+                !SHORT_LOAD_RANGE.contains(value)
+            }
+            (Some(Token::Op { code: Opcode::LOAD }), Some(_)) => true,
+            _ => false,
+        }
+    }
+
+    /// The number of bytes the instruction assembles to
+    pub fn width(&self) -> usize {
+        let width = |code: Opcode| code.info().map_or(1, |info| info.width);
+        match &self.opcode {
+            _ if self.is_long_load() => width(Opcode::LOAD) + width(Opcode::LUI),
+            _ if self.is_long_float_load() => 4 * width(Opcode::FSET),
+            Some(Token::Op { code }) => width(*code),
+            _ => 0,
+        }
+    }
+
+    /// Whether this is a `loadf` of a number that is not a 16-bit integer, which is assembled as an `fset` of each
+    /// 16 bits of the number instead
+    pub fn is_long_float_load(&self) -> bool {
+        let value = match (&self.opcode, &self.operand2) {
+            (Some(Token::Op { code: Opcode::LOADF }), Some(Token::FloatOperand { value })) => *value,
+            (Some(Token::Op { code: Opcode::LOADF }), Some(Token::IntegerOperand { value })) => {
+                *value as f64
+            }
+            _ => return false,
         };
+        // Comparing the bits keeps `-0.0` apart from `0`
+        (value as i16 as f64).to_bits() != value.to_bits()
+    }
 
-        for operand in vec![&self.operand1, &self.operand2, &self.operand3] {
-            match operand {
-                Some(t) => AssemblerInstruction::extract_operand(t, &mut results, symbols),
-                None => {}
+    /// Encodes the instruction using the operand layout and width the opcode table gives for its opcode
+    pub fn to_bytes(&self, symbols: &SymbolTable) -> Result<Vec<u8>, AssemblerError> {
+        let code = match self.opcode {
+            Some(Token::Op { code }) => code,
+            _ => {
+                return Err(AssemblerError::NonOpcodeInOpcodeField {
+                    span: self.spans.opcode.unwrap_or(self.spans.instruction),
+                })
             }
+        };
+        if self.is_long_load() {
+            return self.long_load_to_bytes(symbols);
+        }
+        if self.is_long_float_load() {
+            return self.long_float_load_to_bytes(symbols);
         }
 
-        return results;
+        let mut instruction = Instruction::new(code);
+        for (slot, t) in self.operands().into_iter().enumerate() {
+            let span = self.spans.operands[slot].unwrap_or(self.spans.instruction);
+            let range = immediate_range(code, slot);
+            instruction.operands[slot] =
+                AssemblerInstruction::extract_operand(t, span, range, symbols)?;
+        }
+
+        Ok(instruction.encode())
+    }
+
+    /// Encodes a `load` too wide for one instruction as `load` then `lui`
+    fn long_load_to_bytes(&self, symbols: &SymbolTable) -> Result<Vec<u8>, AssemblerError> {
+        let (register, operand) = match (&self.operand1, &self.operand2) {
+            (Some(register), Some(operand)) => (register, operand),
+            _ => return Err(AssemblerError::InvalidOperand { span: self.spans.instruction }),
+        };
+        let span = self.spans.operands[0].unwrap_or(self.spans.instruction);
+        let register = AssemblerInstruction::extract_operand(register, span, IMM16_RANGE, symbols)?;
+        let span = self.spans.operands[1].unwrap_or(self.spans.instruction);
+        let value = match operand {
+            Token::IntegerOperand { value } => *value,
+            Token::LabelUsage { name } => match symbols.symbol_value(name) {
+                Some(value) => value as i64,
+                None => {
+                    return Err(AssemblerError::UndefinedSymbol {
+                        name: name.clone(),
+                        span,
+                    })
+                }
+            },
+            Token::Expression { expression } => expression
+                .evaluate_with(symbols)
+                .map_err(|e| AssemblerError::from_expression_error(e, span))?,
+            _ => return Err(AssemblerError::InvalidOperand { span }),
+        };
+        if !LOAD_RANGE.contains(&value) {
+            return Err(AssemblerError::IntegerOutOfRange {
+                value: value.into(),
+                min: (*LOAD_RANGE.start()).into(),
+                max: (*LOAD_RANGE.end()).into(),
+                span,
+            });
+        }
+
+        let mut low = Instruction::new(Opcode::LOAD);
+        low.operands = [register, value as u16, 0];
+        let mut high = Instruction::new(Opcode::LUI);
+        high.operands = [register, (value >> 16) as u16, 0];
+        let mut bytes = low.encode();
+        bytes.append(&mut high.encode());
+        Ok(bytes)
+    }
+
+    /// Encodes a `loadf` of a number that is not a 16-bit integer as four `fset`s
+    fn long_float_load_to_bytes(&self, symbols: &SymbolTable) -> Result<Vec<u8>, AssemblerError> {
+        let (register, value) = match (&self.operand1, &self.operand2) {
+            (Some(register), Some(Token::FloatOperand { value })) => (register, *value),
+            (Some(register), Some(Token::IntegerOperand { value })) => (register, *value as f64),
+            _ => return Err(AssemblerError::InvalidOperand { span: self.spans.instruction }),
+        };
+        let span = self.spans.operands[0].unwrap_or(self.spans.instruction);
+        let register = AssemblerInstruction::extract_operand(register, span, IMM16_RANGE, symbols)?;
+        let bits = value.to_bits();
+        let mut bytes = vec![];
+        for index in 0..4 {
+            let mut set = Instruction::new(Opcode::FSET);
+            set.operands = [register, (bits >> (16 * index)) as u16, index];
+            bytes.append(&mut set.encode());
+        }
+        Ok(bytes)
     }
 
     pub fn get_string_constant(&self) -> Option<String> {
@@ -58,12 +340,9 @@ impl AssemblerInstruction {
         }
     }
 
-    pub fn get_i32_constant(&self) -> Option<i32> {
-        if let Some(Token::IntegerOperand { value }) = &self.operand1 {
-            Some(*value)
-        } else {
-            None
-        }
+    /// The value of an `.integer` or the like, which may be an expression
+    pub fn get_integer_constant(&self) -> Option<Expression> {
+        self.operand1.as_ref().and_then(Token::to_expression)
     }
 
     pub fn is_opcode(&self) -> bool {
@@ -98,111 +377,86 @@ impl AssemblerInstruction {
         }
     }
 
-    fn extract_operand(t: &Token, results: &mut Vec<u8>, symbols: &SymbolTable) {
+    /// The value an operand token is encoded as, if its value is in `range`. `span` is where the operand is, for
+    /// errors
+    fn extract_operand(
+        t: &Token,
+        span: Span,
+        range: RangeInclusive<i64>,
+        symbols: &SymbolTable,
+    ) -> Result<u16, AssemblerError> {
         match t {
-            Token::Register { reg_num } => {
-                results.push(*reg_num);
-            }
-            Token::IntegerOperand { value } => {
-                let converted = *value as u16;
-                let byte1 = converted;
-                let byte2 = converted >> 8;
-                results.push(byte2 as u8);
-                results.push(byte1 as u8);
-            }
-            Token::LabelUsage { name } => {
-                if let Some(value) = symbols.symbol_value(&name) {
-                    let byte1 = value;
-                    let byte2 = value >> 8;
-                    results.push(byte2 as u8);
-                    results.push(byte1 as u8);
-                } else {
-                    println!("No value found for {:?}", name);
-                    std::process::exit(1);
-                }
-            }
-            _ => {
-                println!("Opcode found in operand field");
-                std::process::exit(1);
+            Token::Register { reg_num } | Token::FloatRegister { reg_num } => Ok(*reg_num as u16),
+            Token::IntegerOperand { value } => Self::fit(*value, range, span),
+            // Only a `loadf` of a 16-bit integer gets here
+            Token::FloatOperand { value } => Ok(*value as i16 as u16),
+            Token::LabelUsage { name } => match symbols.symbol_value(name) {
+                Some(value) => Self::fit(value as i64, range, span),
+                None => Err(AssemblerError::UndefinedSymbol {
+                    name: name.clone(),
+                    span,
+                }),
+            },
+            Token::Expression { expression } => {
+                let value = expression
+                    .evaluate_with(symbols)
+                    .map_err(|e| AssemblerError::from_expression_error(e, span))?;
+                Self::fit(value, range, span)
             }
+            _ => Err(AssemblerError::InvalidOperand { span }),
         }
     }
-}
-
-// no operand
-fn instruction_zero(input: &str) -> IResult<&str, AssemblerInstruction> {
-    map(opcode_load, |opcode| AssemblerInstruction {
-        label: None,
-        opcode: Some(opcode),
-        directive: None,
-        operand1: None,
-        operand2: None,
-        operand3: None,
-    })(input)
-}
-
-// two operand
-fn instruction_two(input: &str) -> IResult<&str, AssemblerInstruction> {
-    map(
-        tuple((opcode_load, register, integer_operand)),
-        |(opcode, operand1, operand2)| AssemblerInstruction {
-            label: None,
-            opcode: Some(opcode),
-            directive: None,
-            operand1: Some(operand1),
-            operand2: Some(operand2),
-            operand3: None,
-        },
-    )(input)
-}
 
-// three operand
-fn instruction_three(input: &str) -> IResult<&str, AssemblerInstruction> {
-    map(
-        tuple((opcode_load, register, register, register)),
-        |(opcode, operand1, operand2, operand3)| AssemblerInstruction {
-            label: None,
-            opcode: Some(opcode),
-            directive: None,
-            operand1: Some(operand1),
-            operand2: Some(operand2),
-            operand3: Some(operand3),
-        },
-    )(input)
+    /// `value` as a 16-bit operand, if it is in `range`
+    fn fit(value: i64, range: RangeInclusive<i64>, span: Span) -> Result<u16, AssemblerError> {
+        if !range.contains(&value) {
+            return Err(AssemblerError::IntegerOutOfRange {
+                value: value.into(),
+                min: (*range.start()).into(),
+                max: (*range.end()).into(),
+                span,
+            });
+        }
+        Ok(value as u16)
+    }
 }
 
 fn instruction_combined(input: &str) -> IResult<&str, AssemblerInstruction> {
     map(
         tuple((
-            opt(label_declaration),
-            opcode_load,
-            opt(operand),
-            opt(operand),
-            opt(operand),
+            opt(token(input, label_declaration)),
+            token(input, opcode_load),
+            opt(token(input, operand)),
+            opt(token(input, operand)),
+            opt(token(input, operand)),
         )),
-        |(label, opcode, operand1, operand2, operand3)| AssemblerInstruction {
-            label,
-            opcode: Some(opcode),
-            directive: None,
-            operand1,
-            operand2,
-            operand3,
+        |(label, opcode, operand1, operand2, operand3)| {
+            let mut instruction = AssemblerInstruction::from_tokens(
+                label,
+                Some(opcode),
+                None,
+                [operand1, operand2, operand3],
+            );
+            instruction.select_direct_form();
+            instruction
         },
     )(input)
 }
 
 pub fn instruction(input: &str) -> IResult<&str, AssemblerInstruction> {
-    alt((instruction_combined, directive))(input)
+    terminated(alt((instruction_combined, directive)), whitespace)(input)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::assembler::symbols::{Symbol, SymbolType};
     use crate::instruction::Opcode;
 
     #[test]
     fn test_parse_instruction_form_one() {
-        let result = instruction("load $0 #100\n");
+        let source = "load $0 #100\n";
+        let result = instruction(source);
         assert_eq!(
             result,
             Ok((
@@ -213,15 +467,114 @@ mod tests {
                     directive: None,
                     operand1: Some(Token::Register { reg_num: 0 }),
                     operand2: Some(Token::IntegerOperand { value: 100 }),
-                    operand3: None
+                    operand3: None,
+                    spans: InstructionSpans {
+                        instruction: Span::new(source, 0, 12),
+                        opcode: Some(Span::new(source, 0, 4)),
+                        operands: [
+                            Some(Span::new(source, 5, 7)),
+                            Some(Span::new(source, 8, 12)),
+                            None
+                        ],
+                        ..Default::default()
+                    }
                 }
             ))
         );
     }
 
+    #[test]
+    fn test_call_and_ret_to_bytes() {
+        let mut symbols = SymbolTable::new();
+        symbols.add_symbol(Symbol::new("print".to_string(), SymbolType::Label, 300));
+        let (_, call) = instruction("call @print\n").unwrap();
+        assert_eq!(call.to_bytes(&symbols).unwrap(), vec![21, 1, 44]);
+        let (_, ret) = instruction("ret\n").unwrap();
+        assert_eq!(ret.to_bytes(&symbols).unwrap(), vec![22]);
+    }
+
+    #[test]
+    fn test_to_bytes_undefined_symbol() {
+        let symbols = SymbolTable::new();
+        let (_, call) = instruction("call @missing\n").unwrap();
+        assert_eq!(
+            call.to_bytes(&symbols),
+            Err(AssemblerError::UndefinedSymbol {
+                name: "missing".to_string(),
+                span: Span::new("call @missing", 5, 13),
+            })
+        );
+    }
+
+    #[test]
+    fn test_to_bytes_integer_out_of_range() {
+        let source = "sb $1 $2 #70000";
+        let (_, store) = instruction(source).unwrap();
+        assert_eq!(
+            store.to_bytes(&SymbolTable::new()),
+            Err(AssemblerError::IntegerOutOfRange {
+                value: 70000,
+                min: i16::MIN.into(),
+                max: u16::MAX.into(),
+                span: Span::new(source, 9, 15),
+            })
+        );
+    }
+
+    #[test]
+    fn test_to_bytes_label_out_of_range() {
+        let mut symbols = SymbolTable::new();
+        symbols.add_symbol(Symbol::new("end".to_string(), SymbolType::Label, 0x1_0000));
+        let (_, jump) = instruction("jmpi @end\n").unwrap();
+        assert_eq!(
+            jump.to_bytes(&symbols),
+            Err(AssemblerError::IntegerOutOfRange {
+                value: 0x1_0000,
+                min: i16::MIN.into(),
+                max: u16::MAX.into(),
+                span: Span::new("jmpi @end", 5, 9),
+            })
+        );
+    }
+
+    #[test]
+    fn test_to_bytes_pads_to_opcode_width() {
+        let symbols = SymbolTable::new();
+        let (_, eq) = instruction("eq $0 $1\n").unwrap();
+        assert_eq!(eq.to_bytes(&symbols).unwrap(), vec![9, 0, 1, 0]);
+        let (_, nop) = instruction("nop\n").unwrap();
+        assert_eq!(nop.to_bytes(&symbols).unwrap(), vec![16, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_heap_access_to_bytes() {
+        let symbols = SymbolTable::new();
+        let (_, store) = instruction("sw $1 $2 #-4\n").unwrap();
+        assert_eq!(store.to_bytes(&symbols).unwrap(), vec![30, 1, 2, 255, 252]);
+        let (_, load) = instruction("lb $3 $2 #8\n").unwrap();
+        assert_eq!(load.to_bytes(&symbols).unwrap(), vec![25, 3, 2, 0, 8]);
+    }
+
+    #[test]
+    fn test_direct_branch_forms() {
+        let mut symbols = SymbolTable::new();
+        symbols.add_symbol(Symbol::new("loop".to_string(), SymbolType::Label, 300));
+        for (source, bytes) in [
+            ("jeq @loop\n", vec![49, 1, 44]),
+            ("jmp @loop+1\n", vec![48, 1, 45]),
+            ("jle @loop\n", vec![54, 1, 44]),
+            ("jneq $3\n", vec![43, 3]),
+        ] {
+            let (_, i) = instruction(source).unwrap();
+            assert_eq!(i.check_operands(), vec![], "{}", source);
+            assert_eq!(i.to_bytes(&symbols).unwrap(), bytes, "{}", source);
+        }
+    }
+
     #[test]
     fn test_parse_instruction_form_two() {
-        let result = instruction("hlt\n");
+        let source = "hlt\n";
+        let result = instruction(source);
         assert_eq!(
             result,
             Ok((
@@ -232,9 +585,125 @@ mod tests {
                     directive: None,
                     operand1: None,
                     operand2: None,
-                    operand3: None
+                    operand3: None,
+                    spans: InstructionSpans {
+                        instruction: Span::new(source, 0, 3),
+                        opcode: Some(Span::new(source, 0, 3)),
+                        ..Default::default()
+                    }
                 }
             ))
         );
     }
+
+    #[test]
+    fn test_check_operands_accepts_valid_instructions() {
+        for source in ["load $0 #100", "load $1 @hello", "add $0 $1 $2", "hlt", "call @print", "prts #3"] {
+            let (_, i) = instruction(source).unwrap();
+            assert_eq!(i.check_operands(), vec![], "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_check_operands_wrong_kind() {
+        let source = "add $0 #5 $1";
+        let (_, i) = instruction(source).unwrap();
+        assert_eq!(
+            i.check_operands(),
+            vec![AssemblerError::WrongOperandKind {
+                opcode: Opcode::ADD,
+                position: 2,
+                expected: OperandKind::Register,
+                span: Span::new(source, 7, 9),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_check_operands_wrong_count() {
+        let source = "hlt $1 $2 $3";
+        let (_, i) = instruction(source).unwrap();
+        assert_eq!(
+            i.check_operands(),
+            vec![AssemblerError::WrongOperandCount {
+                opcode: Opcode::HLT,
+                expected: 0,
+                found: 3,
+                span: Span::new(source, 0, 12),
+            }]
+        );
+
+        let (_, i) = instruction("add $0 $1").unwrap();
+        assert_eq!(i.check_operands().len(), 1);
+    }
+
+    #[test]
+    fn test_check_operands_unknown_opcode() {
+        let source = "lod $0 #1";
+        let (_, i) = instruction(source).unwrap();
+        assert_eq!(
+            i.check_operands(),
+            vec![AssemblerError::UnknownOpcode {
+                span: Span::new(source, 0, 3)
+            }]
+        );
+    }
+
+    #[test]
+    fn test_check_operands_integer_range() {
+        let source = "prts #0x1_0000";
+        let (_, i) = instruction(source).unwrap();
+        assert_eq!(
+            i.check_operands(),
+            vec![AssemblerError::IntegerOutOfRange {
+                value: 0x10000,
+                min: -32768,
+                max: 65535,
+                span: Span::new(source, 5, 14)
+            }]
+        );
+        let source = "load $0 #0x1_0000_0000";
+        let (_, i) = instruction(source).unwrap();
+        assert_eq!(
+            i.check_operands(),
+            vec![AssemblerError::IntegerOutOfRange {
+                value: 0x1_0000_0000,
+                min: i32::MIN.into(),
+                max: u32::MAX.into(),
+                span: Span::new(source, 8, 22)
+            }]
+        );
+        // `fset` only has four quarters of its register to replace, however the quarter is written
+        let source = "fset $f0 #1 #4";
+        let (_, i) = instruction(source).unwrap();
+        let error = AssemblerError::IntegerOutOfRange {
+            value: 4,
+            min: 0,
+            max: 3,
+            span: Span::new(source, 12, 14),
+        };
+        assert_eq!(i.check_operands(), vec![error.clone()]);
+        assert_eq!(i.to_bytes(&SymbolTable::new()), Err(error));
+        let source = "fset $f0 #1 #2+2";
+        let (_, i) = instruction(source).unwrap();
+        assert!(matches!(
+            i.to_bytes(&SymbolTable::new()),
+            Err(AssemblerError::IntegerOutOfRange { value: 4, .. })
+        ));
+
+        let symbols = SymbolTable::new();
+        for (source, bytes) in [
+            ("load $0 #-32768", vec![0, 0, 128, 0]),
+            ("load $0 #'A'", vec![0, 0, 0, 65]),
+            // Values that don't fit in a sign-extended 16 bits are loaded with `load` and `lui`
+            ("load $0 #0xFFFF", vec![0, 0, 255, 255, 31, 0, 0, 0]),
+            ("load $1 #-32769", vec![0, 1, 127, 255, 31, 1, 255, 255]),
+            ("load $2 #0x12345678", vec![0, 2, 0x56, 0x78, 31, 2, 0x12, 0x34]),
+            ("load $3 #0xFFFFFFFF", vec![0, 3, 255, 255, 31, 3, 255, 255]),
+        ] {
+            let (_, i) = instruction(source).unwrap();
+            assert_eq!(i.check_operands(), vec![]);
+            assert_eq!(i.to_bytes(&symbols).unwrap(), bytes);
+        }
+    }
 }
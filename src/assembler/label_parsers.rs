@@ -1,9 +1,10 @@
 use nom::{
     branch::alt,
     bytes::complete::tag,
-    character::complete::{alphanumeric1, digit1, multispace0, space0},
-    combinator::{eof, map, map_res, opt, value},
-    sequence::{delimited, preceded, terminated, tuple},
+    character::complete::{alpha1, alphanumeric1, multispace0},
+    combinator::{map, recognize},
+    multi::many0,
+    sequence::{pair, preceded, terminated},
     IResult,
 };
 
@@ -13,9 +14,17 @@ use crate::assembler::operand_parser::{integer_operand, operand};
 use crate::assembler::register_parser::register;
 use crate::assembler::Token;
 
+/// The name of a label or a constant: a letter or `_`, then letters, digits and `_`
+pub fn identifier(input: &str) -> IResult<&str, &str> {
+    recognize(pair(
+        alt((alpha1, tag("_"))),
+        many0(alt((alphanumeric1, tag("_")))),
+    ))(input)
+}
+
 pub fn label_declaration(input: &str) -> IResult<&str, Token> {
     map(
-        terminated(alphanumeric1, preceded(tag(":"), multispace0)),
+        terminated(identifier, tag(":")),
         |name: &str| Token::LabelDeclaration {
             name: name.to_string(),
         },
@@ -24,7 +33,7 @@ pub fn label_declaration(input: &str) -> IResult<&str, Token> {
 
 pub fn label_usage(input: &str) -> IResult<&str, Token> {
     map(
-        preceded(tag("@"), preceded(multispace0, alphanumeric1)),
+        preceded(tag("@"), preceded(multispace0, identifier)),
         |name: &str| Token::LabelUsage {
             name: name.to_string(),
         },
@@ -36,7 +45,7 @@ mod tests {
     #[test]
     fn test_parse_label_declaration() {
         let result = label_declaration("test:");
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (_, token) = result.unwrap();
         assert_eq!(
             token,
@@ -45,13 +54,22 @@ mod tests {
             }
         );
         let result = label_declaration("test");
-        assert_eq!(result.is_ok(), false);
+        assert!(result.is_err());
+        // Labels are named like constants
+        let (_, token) = label_declaration("_loop_2:").unwrap();
+        assert_eq!(
+            token,
+            Token::LabelDeclaration {
+                name: "_loop_2".to_string()
+            }
+        );
+        assert!(label_declaration("2loop:").is_err());
     }
 
     #[test]
     fn test_parse_label_usage() {
         let result = label_usage("@test");
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (_, token) = result.unwrap();
         assert_eq!(
             token,
@@ -60,6 +78,6 @@ mod tests {
             }
         );
         let result = label_usage("test");
-        assert_eq!(result.is_ok(), false);
+        assert!(result.is_err());
     }
 }
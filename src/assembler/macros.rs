@@ -0,0 +1,760 @@
+//! Macros, expanded in the source text before it is parsed:
+//!
+//! ```text
+//! .macro countdown reg, from
+//! start\@: load \reg #\from
+//! loop\@: dec \reg
+//! .endm
+//! ```
+//!
+//! `\name` is replaced by the argument given for the parameter `name`, and `\@` by a number that is different for
+//! every expansion, so labels declared in a macro can be made local to one expansion. A macro is called by writing
+//! its name where an opcode would go, with its arguments separated by whitespace or commas: `countdown $0, #10`,
+//! so a macro can't have the name of an opcode.
+//! A label in front of the call goes on the first line of the expansion.
+//!
+//! The expanded text remembers where each of its parts came from, so errors found in it can be moved back to the
+//! original source, with notes pointing at the macro definition and call site.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::ops::Range;
+
+use crate::assembler::comment_parsers::ends_in_block_comment;
+use crate::assembler::label_parsers::identifier;
+use crate::assembler::span::Span;
+use crate::assembler::AssemblerError;
+use crate::instruction::Opcode;
+
+/// How deep macros can call other macros before it is taken to be endless recursion
+pub const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, PartialEq, Clone)]
+struct Macro {
+    params: Vec<String>,
+    /// The lines between `.macro` and `.endm`, with their offsets in the source and whether they start inside a block
+    /// comment
+    body: Vec<(String, usize, bool)>,
+    /// The `.macro` line
+    definition: Span,
+}
+
+/// A piece of expanded text. Byte `i` of `text` comes from `origin + i` in the source, or from the end of the
+/// `origin_len` source bytes it replaces
+#[derive(Debug, PartialEq, Clone)]
+struct Piece {
+    text: String,
+    origin: usize,
+    origin_len: usize,
+}
+
+impl Piece {
+    fn origin_of(&self, i: usize) -> usize {
+        self.origin + i.min(self.origin_len)
+    }
+}
+
+/// A line being expanded, made of pieces from different places in the source
+type Line = Vec<Piece>;
+
+/// One macro expansion: which macro, where it is called and what expansion the call is itself in
+#[derive(Debug, PartialEq, Clone)]
+struct Context {
+    name: String,
+    call: Span,
+    definition: Span,
+    parent: Option<usize>,
+}
+
+/// Where a piece of the expanded text starts, and the expansion it is part of
+#[derive(Debug, PartialEq, Clone)]
+struct Segment {
+    start: usize,
+    piece: Piece,
+    context: Option<usize>,
+}
+
+/// Source text with its macros expanded
+#[derive(Debug, PartialEq, Clone)]
+pub struct Expansion {
+    pub text: String,
+    segments: Vec<Segment>,
+    contexts: Vec<Context>,
+}
+
+impl Expansion {
+    /// Moves an error found in the expanded text back to `source`, wrapping it in the macro expansions it is in
+    pub fn map_error(&self, source: &str, mut error: AssemblerError) -> AssemblerError {
+        let span = match error.span() {
+            Some(span) => span,
+            None => return error,
+        };
+        let index = self
+            .segments
+            .partition_point(|s| s.start <= span.start)
+            .saturating_sub(1);
+        let segment = match self.segments.get(index) {
+            Some(segment) => segment,
+            None => return error,
+        };
+
+        let start = segment.piece.origin_of(span.start - segment.start);
+        // The end is only moved through its own piece when that is still on the same source line, after the start.
+        // Otherwise the span runs to the end of the line it starts on
+        let end_index = self
+            .segments
+            .partition_point(|s| s.start < span.end)
+            .saturating_sub(1)
+            .max(index);
+        let end_segment = &self.segments[end_index];
+        let end = end_segment
+            .piece
+            .origin_of(span.end - end_segment.start.min(span.end));
+        let end = if end_segment.context == segment.context
+            && end >= start
+            && !source[start..end].contains('\n')
+        {
+            end
+        } else {
+            source[start..]
+                .find('\n')
+                .map_or(source.len(), |i| start + i)
+        };
+
+        if let Some(moved) = error.span_mut() {
+            *moved = Span::new(source, start, end.max(start));
+        }
+        self.wrap(error, segment.context)
+    }
+
+    /// Wraps `error` in the expansion `context` and every expansion around it
+    fn wrap(&self, mut error: AssemblerError, mut context: Option<usize>) -> AssemblerError {
+        while let Some(index) = context {
+            let c = &self.contexts[index];
+            error = AssemblerError::InMacroExpansion {
+                error: Box::new(error),
+                name: c.name.clone(),
+                call: c.call,
+                definition: c.definition,
+            };
+            context = c.parent;
+        }
+        error
+    }
+
+    fn push(&mut self, line: Line, context: Option<usize>) {
+        for piece in line {
+            self.segments.push(Segment {
+                start: self.text.len(),
+                piece: piece.clone(),
+                context,
+            });
+            self.text.push_str(&piece.text);
+        }
+    }
+}
+
+struct Expander<'a> {
+    source: &'a str,
+    macros: HashMap<String, Macro>,
+    expansion: Expansion,
+    /// How many expansions there have been, for `\@`
+    count: usize,
+    errors: Vec<AssemblerError>,
+}
+
+/// Expands every macro in `source`. All the errors in macro definitions and calls are returned together
+pub fn expand(source: &str) -> Result<Expansion, Vec<AssemblerError>> {
+    let mut expander = Expander {
+        source,
+        macros: HashMap::new(),
+        expansion: Expansion {
+            text: String::new(),
+            segments: vec![],
+            contexts: vec![],
+        },
+        count: 0,
+        errors: vec![],
+    };
+
+    let lines = expander.collect_definitions();
+    for (line, commented) in lines {
+        expander.expand_line(line, commented, None, 0);
+    }
+
+    if expander.errors.is_empty() {
+        Ok(expander.expansion)
+    } else {
+        Err(expander.errors)
+    }
+}
+
+impl<'a> Expander<'a> {
+    /// Takes the macro definitions out of the source, returning the lines outside of them with whether they start
+    /// inside a block comment
+    fn collect_definitions(&mut self) -> Vec<(Line, bool)> {
+        let mut lines = vec![];
+        let mut current: Option<(String, Macro)> = None;
+        let mut offset = 0;
+        let mut in_comment = false;
+        for raw in self.source.split_inclusive('\n') {
+            let line_offset = offset;
+            offset += raw.len();
+            let line = raw.strip_suffix('\n').unwrap_or(raw);
+            let commented = in_comment;
+            in_comment = ends_in_block_comment(line, in_comment);
+            // A commented out `.macro` or `.endm` is just text
+            let (word, word_start) = if commented { ("", 0) } else { first_word(line) };
+            let line_span = Span::new(
+                self.source,
+                line_offset + word_start,
+                line_offset + line.len(),
+            );
+
+            match (word, &mut current) {
+                (".macro", Some(_)) => self.errors.push(AssemblerError::ParseError {
+                    error: "macros can't be defined inside other macros".to_string(),
+                    span: line_span,
+                }),
+                (".macro", None) => {
+                    current = self.definition_header(line, line_offset, word_start, line_span)
+                }
+                (".endm", None) => self
+                    .errors
+                    .push(AssemblerError::UnexpectedEndMacro { span: line_span }),
+                (".endm", Some(_)) => {
+                    let (name, definition) = current.take().unwrap();
+                    // A definition with a bad `.macro` line has no name, and is only read to skip its body
+                    if name.is_empty() {
+                        continue;
+                    }
+                    match self.macros.entry(name) {
+                        Entry::Occupied(entry) => {
+                            self.errors.push(AssemblerError::MacroAlreadyDefined {
+                                name: entry.key().clone(),
+                                span: definition.definition,
+                            })
+                        }
+                        Entry::Vacant(entry) => {
+                            entry.insert(definition);
+                        }
+                    }
+                }
+                (_, Some((_, definition))) => {
+                    definition
+                        .body
+                        .push((line.to_string(), line_offset, commented));
+                }
+                (_, None) => lines.push((
+                    vec![
+                        Piece {
+                            text: line.to_string(),
+                            origin: line_offset,
+                            origin_len: line.len(),
+                        },
+                        Piece {
+                            text: raw[line.len()..].to_string(),
+                            origin: line_offset + line.len(),
+                            origin_len: raw.len() - line.len(),
+                        },
+                    ],
+                    commented,
+                )),
+            }
+        }
+
+        if let Some((name, definition)) = current {
+            self.errors.push(AssemblerError::UnterminatedMacro {
+                name,
+                span: definition.definition,
+            });
+        }
+        lines
+    }
+
+    /// Reads `.macro name param, param` and checks the body only uses those parameters once it is complete
+    fn definition_header(
+        &mut self,
+        line: &str,
+        line_offset: usize,
+        word_start: usize,
+        span: Span,
+    ) -> Option<(String, Macro)> {
+        let rest = &line[word_start + ".macro".len()..];
+        let words = split_arguments(rest);
+        let mut names = words.iter().map(|range| &rest[range.clone()]);
+        let name = names.next().filter(|name| is_identifier(name));
+        let params: Vec<String> = names.map(|name| name.to_string()).collect();
+        match name {
+            // A macro named like an opcode would hide it, or never be called
+            Some(name) if Opcode::from(name) != Opcode::IGL => {
+                self.errors.push(AssemblerError::MacroNamedLikeOpcode {
+                    name: name.to_string(),
+                    span,
+                });
+                Some((
+                    String::new(),
+                    Macro {
+                        params: vec![],
+                        body: vec![],
+                        definition: span,
+                    },
+                ))
+            }
+            Some(name) if params.iter().all(|p| is_identifier(p)) => Some((
+                name.to_string(),
+                Macro {
+                    params,
+                    body: vec![],
+                    definition: span,
+                },
+            )),
+            _ => {
+                self.errors.push(AssemblerError::ParseError {
+                    error: "expected a macro name and its parameters".to_string(),
+                    span: Span::new(
+                        self.source,
+                        line_offset + word_start,
+                        line_offset + line.len(),
+                    ),
+                });
+                // Still skip the body, so it is not mistaken for code
+                Some((
+                    String::new(),
+                    Macro {
+                        params: vec![],
+                        body: vec![],
+                        definition: span,
+                    },
+                ))
+            }
+        }
+    }
+
+    /// Writes `line` to the expansion, expanding it first if it calls a macro. A line that starts inside a block
+    /// comment, being `commented`, never does
+    fn expand_line(&mut self, line: Line, commented: bool, context: Option<usize>, depth: usize) {
+        let text = line_text(&line);
+        let call = match parse_call(&text, &self.macros).filter(|_| !commented) {
+            Some(call) => call,
+            None => {
+                self.expansion.push(line, context);
+                return;
+            }
+        };
+        let name = text[call.name.clone()].to_string();
+        let definition = self.macros[&name].clone();
+        let call_span = self.span_of(&line, call.name.start..call.end);
+
+        if depth >= MAX_DEPTH {
+            let error = AssemblerError::MacroRecursionLimit {
+                name,
+                span: call_span,
+            };
+            self.errors.push(self.expansion.wrap(error, context));
+            return;
+        }
+        if call.arguments.len() != definition.params.len() {
+            let error = AssemblerError::WrongMacroArgumentCount {
+                name,
+                expected: definition.params.len(),
+                found: call.arguments.len(),
+                span: call_span,
+                definition: definition.definition,
+            };
+            self.errors.push(self.expansion.wrap(error, context));
+            return;
+        }
+
+        self.count += 1;
+        // Nested calls bump the count while this expansion is still being written
+        let number = self.count;
+        self.expansion.contexts.push(Context {
+            name,
+            call: call_span,
+            definition: definition.definition,
+            parent: context,
+        });
+        let inner = Some(self.expansion.contexts.len() - 1);
+        let rest = slice_line(&line, call.end..text.len());
+
+        for (index, (body, origin, commented)) in definition.body.iter().enumerate() {
+            let mut expanded = vec![];
+            if index == 0 {
+                if let Some(label) = &call.label {
+                    expanded.extend(slice_line(&line, label.clone()));
+                    expanded.push(Piece {
+                        text: " ".to_string(),
+                        origin: 0,
+                        origin_len: 0,
+                    });
+                    // The space should map to just after the label
+                    let last = expanded.len() - 2;
+                    let after_label = expanded[last].origin + expanded[last].origin_len;
+                    expanded.last_mut().unwrap().origin = after_label;
+                }
+            }
+            match self.substitute(body, *origin, &definition, &line, &call.arguments, number) {
+                Ok(pieces) => expanded.extend(pieces),
+                Err(error) => {
+                    self.errors.push(self.expansion.wrap(error, inner));
+                    continue;
+                }
+            }
+            if index + 1 < definition.body.len() {
+                expanded.push(Piece {
+                    text: "\n".to_string(),
+                    origin: origin + body.len(),
+                    origin_len: 0,
+                });
+            } else {
+                // The last line keeps the rest of the calling line, such as its comment and newline
+                expanded.extend(rest.clone());
+            }
+            self.expand_line(expanded, *commented, inner, depth + 1);
+        }
+        if definition.body.is_empty() {
+            if let Some(label) = &call.label {
+                self.expansion
+                    .push(slice_line(&line, label.clone()), context);
+            }
+            self.expansion.push(rest, context);
+        }
+    }
+
+    /// Replaces `\name` in a line of a macro body with the argument for `name`, and `\@` with the expansion's number
+    fn substitute(
+        &self,
+        body: &str,
+        origin: usize,
+        definition: &Macro,
+        call: &Line,
+        arguments: &[Range<usize>],
+        number: usize,
+    ) -> Result<Line, AssemblerError> {
+        let mut pieces = vec![];
+        let mut copied = 0;
+        let mut rest = body;
+        while let Some(backslash) = rest.find('\\') {
+            let at = body.len() - rest.len() + backslash;
+            let after = &body[at + 1..];
+            let (replacement, length) = if after.starts_with('@') {
+                (
+                    vec![Piece {
+                        text: number.to_string(),
+                        origin: origin + at,
+                        origin_len: 2,
+                    }],
+                    2,
+                )
+            } else {
+                let name_length = after
+                    .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                    .unwrap_or(after.len());
+                let name = &after[..name_length];
+                if name.is_empty() {
+                    rest = &body[at + 1..];
+                    continue;
+                }
+                match definition.params.iter().position(|p| p == name) {
+                    Some(index) => (slice_line(call, arguments[index].clone()), name_length + 1),
+                    None => {
+                        return Err(AssemblerError::UndefinedMacroParameter {
+                            name: name.to_string(),
+                            span: Span::new(
+                                self.source,
+                                origin + at,
+                                origin + at + name_length + 1,
+                            ),
+                        })
+                    }
+                }
+            };
+            pieces.push(Piece {
+                text: body[copied..at].to_string(),
+                origin: origin + copied,
+                origin_len: at - copied,
+            });
+            pieces.extend(replacement);
+            copied = at + length;
+            rest = &body[copied..];
+        }
+        pieces.push(Piece {
+            text: body[copied..].to_string(),
+            origin: origin + copied,
+            origin_len: body.len() - copied,
+        });
+        Ok(pieces)
+    }
+
+    /// The span in the source of `range` in the text of `line`
+    fn span_of(&self, line: &Line, range: Range<usize>) -> Span {
+        let pieces = slice_line(line, range);
+        let start = pieces.first().map_or(0, |p| p.origin);
+        let end = pieces.last().map_or(start, |p| p.origin + p.origin_len);
+        Span::new(self.source, start, end.max(start))
+    }
+}
+
+/// A macro call: the label in front of it, the macro name, the arguments and where the call ends, all as ranges in
+/// the line's text
+struct Call {
+    label: Option<Range<usize>>,
+    name: Range<usize>,
+    arguments: Vec<Range<usize>>,
+    end: usize,
+}
+
+fn parse_call(text: &str, macros: &HashMap<String, Macro>) -> Option<Call> {
+    let (word, mut start) = first_word(text);
+    let mut label = None;
+    let mut name = word;
+    if let Some(label_name) = word.strip_suffix(':') {
+        if !is_identifier(label_name) {
+            return None;
+        }
+        label = Some(start..start + word.len());
+        let (next, next_start) = first_word(&text[start + word.len()..]);
+        name = next;
+        start += word.len() + next_start;
+    }
+    if !macros.contains_key(name) {
+        return None;
+    }
+
+    let after = start + name.len();
+    let end = after
+        + text[after..after + comment_start(&text[after..])]
+            .trim_end()
+            .len();
+    let arguments = split_arguments(&text[after..end])
+        .into_iter()
+        .map(|range| after + range.start..after + range.end)
+        .collect();
+    Some(Call {
+        label,
+        name: start..after,
+        arguments,
+        end,
+    })
+}
+
+/// The first whitespace separated word of `line`, and where it starts
+fn first_word(line: &str) -> (&str, usize) {
+    let start = line.len() - line.trim_start().len();
+    let word = line[start..].split_whitespace().next().unwrap_or("");
+    (word, start)
+}
+
+/// Where a comment starts in `text`, outside of quotes, or its length if there is none
+fn comment_start(text: &str) -> usize {
+    let mut quoted = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            '\'' => quoted = !quoted,
+            ';' if !quoted => return i,
+            '#' | '/'
+                if !quoted && (text[i..].starts_with("#!") || text[i..].starts_with("/*")) =>
+            {
+                return i
+            }
+            _ => {}
+        }
+    }
+    text.len()
+}
+
+/// The ranges of the arguments in `text`, which are separated by whitespace or commas. Quoted strings are kept whole
+fn split_arguments(text: &str) -> Vec<Range<usize>> {
+    let mut arguments = vec![];
+    let mut start = None;
+    let mut quoted = false;
+    for (i, c) in text.char_indices() {
+        let separator = !quoted && (c.is_whitespace() || c == ',');
+        if c == '\'' {
+            quoted = !quoted;
+        }
+        match (start, separator) {
+            (None, false) => start = Some(i),
+            (Some(s), true) => {
+                arguments.push(s..i);
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        arguments.push(s..text.len());
+    }
+    arguments
+}
+
+/// Whether `name` is a name as labels and constants have them
+fn is_identifier(name: &str) -> bool {
+    matches!(identifier(name), Ok(("", _)))
+}
+
+fn line_text(line: &Line) -> String {
+    line.iter().map(|piece| piece.text.as_str()).collect()
+}
+
+/// The pieces making up `range` of the line's text
+fn slice_line(line: &Line, range: Range<usize>) -> Line {
+    let mut pieces = vec![];
+    let mut start = 0;
+    for piece in line {
+        let end = start + piece.text.len();
+        let from = range.start.max(start);
+        let to = range.end.min(end);
+        if from < to {
+            pieces.push(Piece {
+                text: piece.text[from - start..to - start].to_string(),
+                origin: piece.origin_of(from - start),
+                origin_len: piece.origin_of(to - start) - piece.origin_of(from - start),
+            });
+        }
+        start = end;
+    }
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_parameters() {
+        let source = ".macro set reg, value\nload \\reg \\value\n.endm\nset $1, #5\nset $2 #'a'\n";
+        let expansion = expand(source).unwrap();
+        assert_eq!(expansion.text, "load $1 #5\nload $2 #'a'\n");
+    }
+
+    #[test]
+    fn test_expand_local_labels() {
+        let source = ".macro spin\nloop\\@: jmp $0\n.endm\nspin\nstart: spin ; twice\n";
+        let expansion = expand(source).unwrap();
+        assert_eq!(
+            expansion.text,
+            "loop1: jmp $0\nstart: loop2: jmp $0 ; twice\n"
+        );
+    }
+
+    #[test]
+    fn test_expand_nested() {
+        let source = ".macro one r\nload \\r #1\n.endm\n.macro two a, b\none \\a\none \\b\n.endm\ntwo $1 $2\n";
+        let expansion = expand(source).unwrap();
+        assert_eq!(expansion.text, "load $1 #1\nload $2 #1\n");
+    }
+
+    #[test]
+    fn test_expand_local_labels_around_nested_call() {
+        let source = ".macro inner\nhlt\n.endm\n.macro outer\nl\\@: inc $0\ninner\njmpi @l\\@\n.endm\nouter\n";
+        let expansion = expand(source).unwrap();
+        assert_eq!(expansion.text, "l1: inc $0\nhlt\njmpi @l1\n");
+    }
+
+    #[test]
+    fn test_block_comments_are_not_expanded() {
+        let source = ".macro one\nload $0 #1\n/*\none\n*/\n.endm\n/* one\none\n.macro two\n*/\none\n";
+        let expansion = expand(source).unwrap();
+        assert_eq!(
+            expansion.text,
+            "/* one\none\n.macro two\n*/\nload $0 #1\n/*\none\n*/\n"
+        );
+    }
+
+    #[test]
+    fn test_source_without_macros_is_unchanged() {
+        let source = ".data\n.code\n  load $0 #1 ; comment\nhlt";
+        assert_eq!(expand(source).unwrap().text, source);
+    }
+
+    #[test]
+    fn test_definition_errors() {
+        let source = ".macro a x\nload \\y #1\n.endm\n.endm\n.macro a\n.endm\n.macro b\nhlt\n";
+        let errors = expand(source).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                AssemblerError::UnexpectedEndMacro {
+                    span: Span::new(source, 28, 33),
+                },
+                AssemblerError::MacroAlreadyDefined {
+                    name: "a".to_string(),
+                    span: Span::new(source, 34, 42),
+                },
+                AssemblerError::UnterminatedMacro {
+                    name: "b".to_string(),
+                    span: Span::new(source, 49, 57),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_macro_named_like_opcode() {
+        let source = ".macro hlt\nnop\n.endm\nhlt\n";
+        assert_eq!(
+            expand(source).unwrap_err(),
+            vec![AssemblerError::MacroNamedLikeOpcode {
+                name: "hlt".to_string(),
+                span: Span::new(source, 0, 10),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_call_errors() {
+        let source = ".macro a x\nload \\y #1\n.endm\n.macro b x\nb \\x\n.endm\na $1\na\nb $1\n";
+        let errors = expand(source).unwrap_err();
+        let definition = Span::new(source, 0, 10);
+        assert_eq!(
+            errors[0],
+            AssemblerError::InMacroExpansion {
+                error: Box::new(AssemblerError::UndefinedMacroParameter {
+                    name: "y".to_string(),
+                    span: Span::new(source, 16, 18),
+                }),
+                name: "a".to_string(),
+                call: Span::new(source, 50, 54),
+                definition,
+            }
+        );
+        assert_eq!(
+            errors[1],
+            AssemblerError::WrongMacroArgumentCount {
+                name: "a".to_string(),
+                expected: 1,
+                found: 0,
+                span: Span::new(source, 55, 56),
+                definition,
+            }
+        );
+        assert_eq!(
+            errors[2].to_string(),
+            format!("the macro `b` is nested more than {} deep", MAX_DEPTH)
+        );
+    }
+
+    #[test]
+    fn test_map_error_back_to_source() {
+        let source = ".macro set reg, value\nload \\reg \\value\n.endm\n.data\n.code\nset $1, $5\n";
+        let expansion = expand(source).unwrap();
+        // `$5` is the wrong kind of operand for `load`, which is in the expansion at 20..22
+        assert_eq!(&expansion.text[20..22], "$5");
+        let error = AssemblerError::InvalidOperand {
+            span: Span::new(&expansion.text, 20, 22),
+        };
+        assert_eq!(
+            expansion.map_error(source, error),
+            AssemblerError::InMacroExpansion {
+                error: Box::new(AssemblerError::InvalidOperand {
+                    span: Span::new(source, 65, 67),
+                }),
+                name: "set".to_string(),
+                call: Span::new(source, 57, 67),
+                definition: Span::new(source, 0, 21),
+            }
+        );
+    }
+}
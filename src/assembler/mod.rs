@@ -1,20 +1,36 @@
+pub mod comment_parsers;
+pub mod diagnostics;
 pub mod directive_parsers;
+pub mod expression;
 pub mod instruction_parsers;
 pub mod label_parsers;
+pub mod macros;
+pub mod object;
 pub mod opcode;
 pub mod opcode_parsers;
 pub mod operand_parser;
+pub mod pie;
 pub mod program_parsers;
 pub mod register_parser;
+pub mod sources;
+pub mod span;
 pub mod symbols;
 
-use byteorder::{LittleEndian, WriteBytesExt};
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::RangeInclusive;
 
-use crate::instruction::Opcode;
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::instruction::{Opcode, OperandKind};
 
 use self::{
+    expression::{Expression, ExpressionError, Name},
     instruction_parsers::AssemblerInstruction,
-    program_parsers::{program, Program},
+    object::{Export, Object, Relocation, RelocationKind, RelocationTarget},
+    pie::SectionKind,
+    program_parsers::{parse_program, Program},
+    span::Span,
     symbols::{Symbol, SymbolTable, SymbolType},
 };
 
@@ -22,11 +38,30 @@ use self::{
 pub enum Token {
     Op { code: Opcode },
     Register { reg_num: u8 },
-    IntegerOperand { value: i32 },
+    IntegerOperand { value: i64 },
+    /// A number with a fraction or an exponent, such as `#1.5` or `#-2e10`
+    FloatOperand { value: f64 },
+    FloatRegister { reg_num: u8 },
     LabelDeclaration { name: String },
     LabelUsage { name: String },
     Directive { name: String },
     IrString { name: String },
+    /// An operand computed while assembling, such as `#BUFSIZE*2` or `@end-@start`
+    Expression { expression: Expression },
+    /// The name a `.equ` or `.set` directive declares
+    ConstantDeclaration { name: String },
+}
+
+impl Token {
+    /// An integer, label or expression operand as an expression
+    pub fn to_expression(&self) -> Option<Expression> {
+        match self {
+            Token::IntegerOperand { value } => Some(Expression::Number(*value)),
+            Token::LabelUsage { name } => Some(Expression::Label(name.clone())),
+            Token::Expression { expression } => Some(expression.clone()),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -44,13 +79,91 @@ pub enum AssemblerSection {
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum AssemblerError {
-    NoSegmentDeclarationFound { instruction: u32 },
-    StringConstantDeclaredWithoutLabel { instruction: u32 },
-    SymbolAlreadyDeclared,
-    UnknownDirectiveFound { directive: String },
-    NonOpcodeInOpcodeField,
+    /// A label was declared before any `.data` or `.code` section header
+    NoSegmentDeclarationFound { span: Span },
+    StringConstantDeclaredWithoutLabel { span: Span },
+    SymbolAlreadyDeclared { name: String, span: Span },
+    UnknownDirectiveFound { directive: String, span: Span },
+    /// The directive field holds something other than a directive name
+    InvalidDirectiveName { span: Span },
+    /// An `.asciiz` is followed by something other than a string
+    ExpectedStringConstant { span: Span },
+    NonOpcodeInOpcodeField { span: Span },
+    /// A label is used but never declared
+    UndefinedSymbol { name: String, span: Span },
+    /// Something that is not a register, integer or label is in an operand field
+    InvalidOperand { span: Span },
+    /// A constant is defined in terms of itself. The span is its declaration
+    CyclicConstant { name: String, span: Span },
+    /// An expression's value does not fit in 64 bits at some step
+    ExpressionOverflow { span: Span },
+    /// An expression divides by zero
+    DivisionByZero { span: Span },
+    /// An integer does not fit in the operand or constant it is written in. The value is wider than the range
+    /// so that literals too big for 64 bits can be reported too
+    IntegerOutOfRange {
+        value: i128,
+        min: i128,
+        max: i128,
+        span: Span,
+    },
     InsufficientSections,
-    ParseError { error: String },
+    /// Parsing stopped before the end of the source. The span runs from there to the end of the line
+    ParseError { error: String, span: Span },
+    /// The mnemonic is not one of the opcodes in `instruction::OPCODES`
+    UnknownOpcode { span: Span },
+    /// A `.macro` has no matching `.endm`. The span is the `.macro` line
+    UnterminatedMacro { name: String, span: Span },
+    /// An `.endm` outside of a macro definition
+    UnexpectedEndMacro { span: Span },
+    MacroAlreadyDefined { name: String, span: Span },
+    /// A macro has the name of an opcode. The span is its `.macro` line
+    MacroNamedLikeOpcode { name: String, span: Span },
+    /// A macro body uses `\name` for a parameter the macro does not have
+    UndefinedMacroParameter { name: String, span: Span },
+    /// A macro is called with a different number of arguments than it has parameters. The span is the call
+    WrongMacroArgumentCount {
+        name: String,
+        expected: usize,
+        found: usize,
+        span: Span,
+        definition: Span,
+    },
+    /// Macros call each other too deeply, which is most likely endless recursion
+    MacroRecursionLimit { name: String, span: Span },
+    /// An error in code coming from a macro. `call` is where the macro is used and `definition` its `.macro` line
+    InMacroExpansion {
+        error: Box<AssemblerError>,
+        name: String,
+        call: Span,
+        definition: Span,
+    },
+    /// No file to `.include` was found next to the including file or in the include paths
+    IncludeNotFound { path: String, span: Span },
+    /// A file includes itself, directly or through other files
+    IncludeCycle { path: String, span: Span },
+    CannotReadInclude {
+        path: String,
+        error: String,
+        span: Span,
+    },
+    /// A relocatable object uses a label in a way the linker can't patch in, anything but a label plus or minus a
+    /// constant
+    UnrelocatableExpression { span: Span },
+    /// The instruction has a different number of operands than its opcode takes
+    WrongOperandCount {
+        opcode: Opcode,
+        expected: usize,
+        found: usize,
+        span: Span,
+    },
+    /// An operand is not of the kind its opcode takes in that position, which is counted from 1
+    WrongOperandKind {
+        opcode: Opcode,
+        position: usize,
+        expected: OperandKind,
+        span: Span,
+    },
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -61,6 +174,9 @@ pub struct Assembler {
     pub ro: Vec<u8>,
     /// The compiled bytecode generated from the assembly instructions
     pub bytecode: Vec<u8>,
+    /// Whether `assemble` writes every label into a symbols section of the image, for the debugger and disassembler
+    /// to find them by name
+    pub emit_symbols: bool,
     /// Tracks the current offset of the read-only section
     ro_offset: u32,
     /// A list of all the sections we've seen in the code
@@ -71,10 +187,263 @@ pub struct Assembler {
     current_instruction: u32,
     /// Any errors we find along the way. At the end, we'll present them to the user.
     errors: Vec<AssemblerError>,
+    /// The constants declared in the first pass, in order
+    constants: Vec<ConstantDeclaration>,
+    /// Whether a relocatable object is being assembled rather than an image
+    relocatable: bool,
+    /// The offset in the code section of the next instruction
+    code_offset: u32,
+    /// The section every label is declared in
+    label_sections: HashMap<String, SectionKind>,
+    /// The labels exported with `.global`, and where
+    globals: Vec<(String, Span)>,
+    /// The labels imported with `.extern`, and where
+    externs: Vec<(String, Span)>,
+    /// In a relocatable object, the symbols operands are encoded with in the second pass. Every label is zero in it,
+    /// since its address is only patched in by the linker
+    unrelocated_symbols: Option<SymbolTable>,
+    /// Where the linker has to patch in the address of a label
+    relocations: Vec<Relocation>,
+}
+
+impl AssemblerError {
+    /// The error for an expression at `span` that could not be evaluated
+    pub fn from_expression_error(error: ExpressionError, span: Span) -> AssemblerError {
+        match error {
+            ExpressionError::Undefined { name } => AssemblerError::UndefinedSymbol { name, span },
+            ExpressionError::Overflow => AssemblerError::ExpressionOverflow { span },
+            ExpressionError::DivisionByZero => AssemblerError::DivisionByZero { span },
+        }
+    }
+
+    /// Where in the source the error is, if it can be pinned to one place
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            AssemblerError::NoSegmentDeclarationFound { span }
+            | AssemblerError::StringConstantDeclaredWithoutLabel { span }
+            | AssemblerError::SymbolAlreadyDeclared { span, .. }
+            | AssemblerError::UnknownDirectiveFound { span, .. }
+            | AssemblerError::InvalidDirectiveName { span }
+            | AssemblerError::ExpectedStringConstant { span }
+            | AssemblerError::UnknownOpcode { span }
+            | AssemblerError::WrongOperandCount { span, .. }
+            | AssemblerError::WrongOperandKind { span, .. }
+            | AssemblerError::NonOpcodeInOpcodeField { span }
+            | AssemblerError::UndefinedSymbol { span, .. }
+            | AssemblerError::InvalidOperand { span }
+            | AssemblerError::IntegerOutOfRange { span, .. }
+            | AssemblerError::CyclicConstant { span, .. }
+            | AssemblerError::ExpressionOverflow { span }
+            | AssemblerError::DivisionByZero { span }
+            | AssemblerError::ParseError { span, .. }
+            | AssemblerError::UnterminatedMacro { span, .. }
+            | AssemblerError::UnexpectedEndMacro { span }
+            | AssemblerError::MacroAlreadyDefined { span, .. }
+            | AssemblerError::MacroNamedLikeOpcode { span, .. }
+            | AssemblerError::UndefinedMacroParameter { span, .. }
+            | AssemblerError::WrongMacroArgumentCount { span, .. }
+            | AssemblerError::MacroRecursionLimit { span, .. }
+            | AssemblerError::IncludeNotFound { span, .. }
+            | AssemblerError::IncludeCycle { span, .. }
+            | AssemblerError::CannotReadInclude { span, .. }
+            | AssemblerError::UnrelocatableExpression { span } => Some(*span),
+            AssemblerError::InMacroExpansion { error, .. } => error.span(),
+            AssemblerError::InsufficientSections => None,
+        }
+    }
+
+    /// The span returned by `span`, to move the error somewhere else in the source
+    pub fn span_mut(&mut self) -> Option<&mut Span> {
+        match self {
+            AssemblerError::NoSegmentDeclarationFound { span }
+            | AssemblerError::StringConstantDeclaredWithoutLabel { span }
+            | AssemblerError::SymbolAlreadyDeclared { span, .. }
+            | AssemblerError::UnknownDirectiveFound { span, .. }
+            | AssemblerError::InvalidDirectiveName { span }
+            | AssemblerError::ExpectedStringConstant { span }
+            | AssemblerError::UnknownOpcode { span }
+            | AssemblerError::WrongOperandCount { span, .. }
+            | AssemblerError::WrongOperandKind { span, .. }
+            | AssemblerError::NonOpcodeInOpcodeField { span }
+            | AssemblerError::UndefinedSymbol { span, .. }
+            | AssemblerError::InvalidOperand { span }
+            | AssemblerError::IntegerOutOfRange { span, .. }
+            | AssemblerError::CyclicConstant { span, .. }
+            | AssemblerError::ExpressionOverflow { span }
+            | AssemblerError::DivisionByZero { span }
+            | AssemblerError::ParseError { span, .. }
+            | AssemblerError::UnterminatedMacro { span, .. }
+            | AssemblerError::UnexpectedEndMacro { span }
+            | AssemblerError::MacroAlreadyDefined { span, .. }
+            | AssemblerError::MacroNamedLikeOpcode { span, .. }
+            | AssemblerError::UndefinedMacroParameter { span, .. }
+            | AssemblerError::WrongMacroArgumentCount { span, .. }
+            | AssemblerError::MacroRecursionLimit { span, .. }
+            | AssemblerError::IncludeNotFound { span, .. }
+            | AssemblerError::IncludeCycle { span, .. }
+            | AssemblerError::CannotReadInclude { span, .. }
+            | AssemblerError::UnrelocatableExpression { span } => Some(span),
+            AssemblerError::InMacroExpansion { error, .. } => error.span_mut(),
+            AssemblerError::InsufficientSections => None,
+        }
+    }
+
+    /// More places in the source that explain the error, each with a message
+    pub fn notes(&self) -> Vec<(String, Span)> {
+        match self {
+            AssemblerError::WrongMacroArgumentCount {
+                name, definition, ..
+            } => vec![(format!("the macro `{}` is defined here", name), *definition)],
+            AssemblerError::InMacroExpansion {
+                error,
+                name,
+                call,
+                definition,
+            } => {
+                let mut notes = error.notes();
+                notes.push((format!("in this expansion of the macro `{}`", name), *call));
+                notes.push((format!("the macro `{}` is defined here", name), *definition));
+                notes
+            }
+            _ => vec![],
+        }
+    }
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AssemblerError::NoSegmentDeclarationFound { .. } => {
+                write!(f, "label declared outside of a `.data` or `.code` section")
+            }
+            AssemblerError::StringConstantDeclaredWithoutLabel { .. } => {
+                write!(f, "constant declared without a label")
+            }
+            AssemblerError::SymbolAlreadyDeclared { name, .. } => {
+                write!(f, "`{}` is already declared", name)
+            }
+            AssemblerError::UnknownDirectiveFound { directive, .. } => {
+                write!(f, "unknown directive `.{}`", directive)
+            }
+            AssemblerError::InvalidDirectiveName { .. } => write!(f, "expected a directive name"),
+            AssemblerError::ExpectedStringConstant { .. } => {
+                write!(f, "expected a string after `.asciiz`")
+            }
+            AssemblerError::NonOpcodeInOpcodeField { .. } => write!(f, "expected an opcode"),
+            AssemblerError::UndefinedSymbol { name, .. } => write!(f, "`{}` is not declared", name),
+            AssemblerError::UnterminatedMacro { name, .. } => {
+                write!(f, "the macro `{}` has no `.endm`", name)
+            }
+            AssemblerError::UnexpectedEndMacro { .. } => {
+                write!(f, "`.endm` outside of a macro definition")
+            }
+            AssemblerError::MacroAlreadyDefined { name, .. } => {
+                write!(f, "the macro `{}` is already defined", name)
+            }
+            AssemblerError::MacroNamedLikeOpcode { name, .. } => {
+                write!(f, "`{}` is an opcode and can't be the name of a macro", name)
+            }
+            AssemblerError::UndefinedMacroParameter { name, .. } => {
+                write!(f, "the macro has no parameter `{}`", name)
+            }
+            AssemblerError::WrongMacroArgumentCount {
+                name,
+                expected,
+                found,
+                ..
+            } => write!(
+                f,
+                "the macro `{}` takes {} argument{}, found {}",
+                name,
+                expected,
+                if *expected == 1 { "" } else { "s" },
+                found
+            ),
+            AssemblerError::MacroRecursionLimit { name, .. } => write!(
+                f,
+                "the macro `{}` is nested more than {} deep",
+                name,
+                macros::MAX_DEPTH
+            ),
+            AssemblerError::InMacroExpansion { error, .. } => write!(f, "{}", error),
+            AssemblerError::IncludeNotFound { path, .. } => {
+                write!(f, "can't find `{}` to include", path)
+            }
+            AssemblerError::IncludeCycle { path, .. } => {
+                write!(f, "`{}` is included by itself", path)
+            }
+            AssemblerError::CannotReadInclude { path, error, .. } => {
+                write!(f, "can't read `{}`: {}", path, error)
+            }
+            AssemblerError::InvalidOperand { .. } => {
+                write!(f, "expected a register, an integer or a label")
+            }
+            AssemblerError::CyclicConstant { name, .. } => {
+                write!(f, "the constant `{}` is defined in terms of itself", name)
+            }
+            AssemblerError::ExpressionOverflow { .. } => {
+                write!(f, "the expression overflows 64-bit arithmetic")
+            }
+            AssemblerError::DivisionByZero { .. } => write!(f, "the expression divides by zero"),
+            AssemblerError::IntegerOutOfRange { value, min, max, .. } => write!(
+                f,
+                "{} does not fit here, the value must be between {} and {}",
+                value, min, max
+            ),
+            AssemblerError::InsufficientSections => {
+                write!(f, "a program needs both a `.data` and a `.code` section")
+            }
+            AssemblerError::ParseError { error, .. } => write!(f, "{}", error),
+            AssemblerError::UnknownOpcode { .. } => write!(f, "unknown opcode"),
+            AssemblerError::UnrelocatableExpression { .. } => write!(
+                f,
+                "an object can only use a label plus or minus a constant here"
+            ),
+            AssemblerError::WrongOperandCount {
+                opcode,
+                expected,
+                found,
+                ..
+            } => write!(
+                f,
+                "`{}` takes {} operand{}, found {}",
+                mnemonic(*opcode),
+                expected,
+                if *expected == 1 { "" } else { "s" },
+                found
+            ),
+            AssemblerError::WrongOperandKind {
+                opcode,
+                position,
+                expected,
+                ..
+            } => write!(
+                f,
+                "operand {} of `{}` must be {}",
+                position,
+                mnemonic(*opcode),
+                expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AssemblerError {}
+
+fn mnemonic(opcode: Opcode) -> &'static str {
+    opcode.info().map_or("igl", |info| info.mnemonic)
 }
 
-pub const PIE_HEADER_PREFIX: [u8; 4] = [45, 50, 49, 45];
-pub const PIE_HEADER_LENGTH: usize = 64;
+pub use self::pie::{PIE_HEADER_LENGTH, PIE_HEADER_PREFIX};
+
+/// The values an `.integer` constant can have
+const INTEGER_RANGE: RangeInclusive<i64> = i32::MIN as i64..=u32::MAX as i64;
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl Assembler {
     pub fn new() -> Assembler {
@@ -83,61 +452,95 @@ impl Assembler {
             symbols: SymbolTable::new(),
             ro: vec![],
             bytecode: vec![],
+            emit_symbols: false,
             ro_offset: 0,
             sections: vec![],
             current_section: None,
             current_instruction: 0,
             errors: vec![],
+            constants: vec![],
+            relocatable: false,
+            code_offset: 0,
+            label_sections: HashMap::new(),
+            globals: vec![],
+            externs: vec![],
+            unrelocated_symbols: None,
+            relocations: vec![],
         }
     }
 
     pub fn assemble(&mut self, raw: &str) -> Result<Vec<u8>, Vec<AssemblerError>> {
-        match program(raw) {
-            Ok((_remainder, program)) => {
-                self.process_first_phase(&program);
+        let body = self.assemble_source(raw)?;
+        // Put the header, the read-only data and the body together
+        Ok(self.write_pie(&body))
+    }
 
-                if !self.errors.is_empty() {
-                    // TODO: Can we avoid a clone here?
-                    println!(
-                        "Errors were found in the first parsing phase: {:?}",
-                        self.errors
-                    );
-                    return Err(self.errors.clone());
-                };
+    /// Assembles `raw` into a relocatable object, to be linked with others by `linker::Linker`. The labels declared
+    /// with `.global` are exported from it, and the ones declared with `.extern` imported
+    pub fn assemble_object(&mut self, raw: &str) -> Result<Object, Vec<AssemblerError>> {
+        self.relocatable = true;
+        let code = self.assemble_source(raw)?;
+        let exports = self
+            .globals
+            .iter()
+            .filter_map(|(name, _)| {
+                Some(Export {
+                    name: name.clone(),
+                    section: *self.label_sections.get(name)?,
+                    offset: self.symbols.symbol_value(name)?,
+                })
+            })
+            .collect();
+        Ok(Object {
+            ro: self.ro.clone(),
+            code,
+            exports,
+            imports: self.externs.iter().map(|(name, _)| name.clone()).collect(),
+            relocations: self.relocations.clone(),
+        })
+    }
 
-                // First get the header so we can smush it into the bytecode letter
-                let mut assembled_program = self.write_pie_header();
+    /// Runs both passes over `raw`, returning the code section
+    fn assemble_source(&mut self, raw: &str) -> Result<Vec<u8>, Vec<AssemblerError>> {
+        // Macros are expanded first, and errors in the expanded text moved back to where they are in `raw`
+        let expansion = macros::expand(raw)?;
+        self.assemble_expanded(&expansion.text).map_err(|errors| {
+            errors
+                .into_iter()
+                .map(|error| expansion.map_error(raw, error))
+                .collect()
+        })
+    }
 
-                // If we accumulated any errors in the first pass, return them and don't try to do the second pass
-                if !self.errors.is_empty() {
-                    // TODO: Can we avoid a clone here?
-                    return Err(self.errors.clone());
-                };
+    fn assemble_expanded(&mut self, raw: &str) -> Result<Vec<u8>, Vec<AssemblerError>> {
+        match parse_program(raw) {
+            Ok(mut program) => {
+                self.process_first_phase(&mut program);
+                if !self.relocatable {
+                    self.place_code_labels();
+                }
+                self.resolve_constants();
+                self.check_globals_and_externs();
 
                 // Make sure that we have at least one data section and one code section
                 if self.sections.len() != 2 {
                     // TODO: Detail out which one(s) are missing
-                    println!("Did not find at least two sections.");
                     self.errors.push(AssemblerError::InsufficientSections);
-                    // TODO: Can we avoid a clone here?
-                    return Err(self.errors.clone());
                 }
 
-                // append the strings
-                assembled_program.append(&mut self.ro);
-
-                let mut body = self.process_second_phase(&program);
+                // If we accumulated any errors in the first pass, return all of them and don't try to do the second pass
+                if !self.errors.is_empty() {
+                    // TODO: Can we avoid a clone here?
+                    return Err(self.errors.clone());
+                };
 
-                // Merge the header with the populated body vector
-                assembled_program.append(&mut body);
-                Ok(assembled_program)
-            }
-            Err(e) => {
-                println!("There was an error parsing the code: {:?}", e);
-                Err(vec![AssemblerError::ParseError {
-                    error: e.to_string(),
-                }])
+                let body = self.process_second_phase(&program);
+                if !self.errors.is_empty() {
+                    return Err(self.errors.clone());
+                }
+                Ok(body)
             }
+            Err(e) => Err(vec![e]),
         }
     }
 
@@ -150,7 +553,7 @@ impl Assembler {
             None => {
                 self.errors
                     .push(AssemblerError::StringConstantDeclaredWithoutLabel {
-                        instruction: self.current_instruction,
+                        span: i.spans.instruction,
                     });
                 return;
             }
@@ -159,25 +562,36 @@ impl Assembler {
         // Check if label is already in use (has an entry in the symbol table)
         // TODO: Is there a cleaner way to do this?
         if self.symbols.has_symbol(&name) {
-            self.errors.push(AssemblerError::SymbolAlreadyDeclared);
+            self.errors.push(AssemblerError::SymbolAlreadyDeclared {
+                name,
+                span: i.spans.label.unwrap_or(i.spans.instruction),
+            });
             return;
         }
 
-        // If we make it here, it isn't a symbol we've seen before, so stick it in the table
-        let symbol = Symbol::new(name, SymbolType::Label, (self.current_instruction * 4) + 60);
-        self.symbols.add_symbol(symbol);
+        // If we make it here, it isn't a symbol we've seen before, so stick it in the table. For now the label is the
+        // offset in its section, code labels are moved to their address in the image by `place_code_labels`
+        let (section, offset) = match self.current_section {
+            Some(AssemblerSection::Code) => (SectionKind::Code, self.code_offset),
+            _ => (SectionKind::ReadOnly, self.ro_offset),
+        };
+        self.label_sections.insert(name.clone(), section);
+        self.symbols
+            .add_symbol(Symbol::new(name, SymbolType::Label, offset));
     }
 
     /// Handles a declaration of a section header, such as:
     /// .code
-    fn process_section_header(&mut self, header_name: &str) {
+    fn process_section_header(&mut self, header_name: &str, i: &AssemblerInstruction) {
         let new_section: AssemblerSection = header_name.into();
-        // Only specific section names are allowed
+        // Only specific section names are allowed. Headers are seen in both phases, so only report this once
         if new_section == AssemblerSection::Unknown {
-            println!(
-                "Found an section header that is unknown: {:#?}",
-                header_name
-            );
+            if self.phase == AssemblerPhase::First {
+                self.errors.push(AssemblerError::UnknownDirectiveFound {
+                    directive: header_name.to_string(),
+                    span: i.spans.directive.unwrap_or(i.spans.instruction),
+                });
+            }
             return;
         }
         // TODO: Check if we really need to keep a list of all sections seen
@@ -190,7 +604,12 @@ impl Assembler {
         let directive_name = match i.get_directive_name() {
             Some(name) => name,
             None => {
-                println!("Directive has an invalid name: {:?}", i);
+                // Directives are seen in both phases, so only report this once
+                if self.phase == AssemblerPhase::First {
+                    self.errors.push(AssemblerError::InvalidDirectiveName {
+                        span: i.spans.directive.unwrap_or(i.spans.instruction),
+                    });
+                }
                 return;
             }
         };
@@ -205,23 +624,34 @@ impl Assembler {
                 "integer" => {
                     self.handle_integer(i);
                 }
+                "float" => {
+                    self.handle_float(i);
+                }
+                "equ" | "set" => {
+                    self.handle_constant(i);
+                }
+                "global" | "extern" => {
+                    self.handle_global_or_extern(&directive_name, i);
+                }
                 _ => {
-                    self.errors.push(AssemblerError::UnknownDirectiveFound {
-                        directive: directive_name.clone(),
-                    });
-                    return;
+                    if self.phase == AssemblerPhase::First {
+                        self.errors.push(AssemblerError::UnknownDirectiveFound {
+                            directive: directive_name.clone(),
+                            span: i.spans.directive.unwrap_or(i.spans.instruction),
+                        });
+                    }
                 }
             }
         } else {
             // If there were not any operands, (e.g., `.code`), then we know it is a section header
-            self.process_section_header(&directive_name);
+            self.process_section_header(&directive_name, i);
         }
     }
 
-    fn process_first_phase(&mut self, p: &Program) {
+    fn process_first_phase(&mut self, p: &mut Program) {
         // Iterate over every instruction, even though in the first phase we care
         // about labels and directives but nothing else
-        for i in &p.instructions {
+        for i in &mut p.instructions {
             if i.is_label() {
                 // TODO: Factor this out into another function? Put it in `process_label_declaration`?
                 if self.current_section.is_some() {
@@ -231,7 +661,7 @@ impl Assembler {
                     // If we have *not* hit a segment header yet, then we have a label outside of a segment,
                     // which is not allowed
                     self.errors.push(AssemblerError::NoSegmentDeclarationFound {
-                        instruction: self.current_instruction,
+                        span: i.spans.label.unwrap_or(i.spans.instruction),
                     });
                 }
             }
@@ -240,6 +670,12 @@ impl Assembler {
                 self.process_directive(i);
             }
 
+            if i.is_opcode() {
+                self.fold_load_operand(i);
+                self.errors.append(&mut i.check_operands());
+                self.code_offset += i.width() as u32;
+            }
+
             // This is used to keep track of which instruction we hit an error on
             // TODO: Do we really need to track this?
             self.current_instruction += 1;
@@ -248,10 +684,53 @@ impl Assembler {
         self.phase = AssemblerPhase::Second;
     }
 
+    /// Replaces the operand of a `load` with its value if the value is already known, so that the `load` only takes
+    /// the long form when the value doesn't fit in the short one. The known values are numbers and constants declared
+    /// before the `load` that don't use labels. Anything else keeps the long form whatever its value turns out to be:
+    /// the address of a code label waits on the length of the read-only section, labels in an object are patched in
+    /// by the linker, and a constant declared further on is only given its value after this pass
+    fn fold_load_operand(&self, i: &mut AssemblerInstruction) {
+        let expression = match (&i.opcode, &i.operand2) {
+            (Some(Token::Op { code: Opcode::LOAD }), Some(Token::Expression { expression })) => {
+                expression
+            }
+            _ => return,
+        };
+        // Without any labels in the symbol table, constants using one fail to resolve and aren't known
+        let no_labels = SymbolTable::new();
+        let mut resolved = HashMap::new();
+        let value = expression.evaluate(&mut |name| {
+            let (name, index) = match name {
+                Name::Constant(name) => (name, self.constants.iter().rposition(|c| c.name == name)),
+                Name::Label(name) => (name, None),
+            };
+            let undefined = || ExpressionError::Undefined {
+                name: name.to_string(),
+            };
+            let index = index.ok_or_else(undefined)?;
+            // Errors are reported when the constants are resolved for real
+            resolve_constant(
+                &self.constants,
+                &no_labels,
+                index,
+                &mut resolved,
+                &mut vec![],
+                &mut vec![],
+            )
+            .ok_or_else(undefined)
+        });
+        if let Ok(value) = value {
+            i.operand2 = Some(Token::IntegerOperand { value });
+        }
+    }
+
     /// Runs the second pass of the assembler
     fn process_second_phase(&mut self, p: &Program) -> Vec<u8> {
         // Restart the counting of instructions
         self.current_instruction = 0;
+        if self.relocatable {
+            self.unrelocated_symbols = Some(self.unrelocated_symbols());
+        }
         // We're going to put the bytecode meant to be executed in a separate Vec so we can do some post-processing and then merge it with the header and read-only sections
         // Examples could be optimizations, additional checks, whatever
         let mut program = vec![];
@@ -259,8 +738,15 @@ impl Assembler {
         for i in &p.instructions {
             if i.is_opcode() {
                 // Opcodes know how to properly transform themselves into 32-bits, so we can just call `to_bytes` and append to our program
-                let mut bytes = i.to_bytes(&self.symbols);
-                program.append(&mut bytes);
+                match i.to_bytes(self.operand_symbols()) {
+                    Ok(mut bytes) => {
+                        if self.relocatable {
+                            self.relocate_operands(i, program.len() as u32);
+                        }
+                        program.append(&mut bytes)
+                    }
+                    Err(e) => self.errors.push(e),
+                }
             }
             if i.is_directive() {
                 // In this phase, we can have directives but of different types than we care about in the first pass. The Directive itself can check which pass the Assembler
@@ -272,43 +758,25 @@ impl Assembler {
         program
     }
 
-    fn extract_labels(&mut self, p: &Program) {
-        let mut c = 0;
-        for i in &p.instructions {
-            if i.is_label() {
-                match i.get_label_name() {
-                    Some(name) => {
-                        let symbol = Symbol::new(name, SymbolType::Label, c);
-                        self.symbols.add_symbol(symbol);
-                    }
-                    None => {}
-                };
-            }
-            c += 4;
+    /// Lays out the read-only data and the assembled `code` as a PIE image, starting execution at the top of the code.
+    /// The symbols come last, so they don't move the code
+    fn write_pie(&self, code: &[u8]) -> Vec<u8> {
+        let mut sections = vec![(SectionKind::ReadOnly, &self.ro[..]), (SectionKind::Code, code)];
+        let symbols;
+        if self.emit_symbols {
+            symbols = pie::write_symbols(&self.symbols);
+            sections.push((SectionKind::Symbols, &symbols));
         }
+        pie::write_pie(0, 0, &sections)
     }
 
-    fn write_pie_header(&self) -> Vec<u8> {
-        let mut header = vec![];
-        for byte in PIE_HEADER_PREFIX.into_iter() {
-            header.push(byte.clone());
-        }
-        while header.len() < PIE_HEADER_LENGTH {
-            header.push(0 as u8);
+    /// How many sections `write_pie` writes, which decides where the code starts
+    fn image_section_count(&self) -> usize {
+        if self.emit_symbols {
+            3
+        } else {
+            2
         }
-
-        let mut data = vec![];
-        // number of bytes
-        data.write_u32::<LittleEndian>(self.ro.len() as u32)
-            .unwrap();
-
-        // end address I guess? need to be fixed
-        let end = PIE_HEADER_LENGTH + 8 + self.ro.len();
-        data.write_u32::<LittleEndian>(end as u32)
-            .unwrap();
-        header.append(&mut data);
-
-        header
     }
 
     /// Handles a declaration of a null-terminated string:
@@ -325,11 +793,15 @@ impl Assembler {
                 match i.get_label_name() {
                     Some(name) => {
                         self.symbols.set_symbol_offset(&name, self.ro_offset);
+                        self.label_sections.insert(name, SectionKind::ReadOnly);
                     }
                     None => {
                         // This would be someone typing:
                         // .asciiz 'Hello'
-                        println!("Found a string constant with no associated label!");
+                        self.errors
+                            .push(AssemblerError::StringConstantDeclaredWithoutLabel {
+                                span: i.spans.instruction,
+                            });
                         return;
                     }
                 };
@@ -344,49 +816,448 @@ impl Assembler {
                 self.ro_offset += 1;
             }
             None => {
-                // This just means someone typed `.asciiz` for some reason
-                println!("String constant following an .asciiz was empty");
+                // This means someone typed something like `.asciiz #5`
+                self.errors.push(AssemblerError::ExpectedStringConstant {
+                    span: i.spans.operands[0].unwrap_or(i.spans.instruction),
+                });
             }
         }
     }
 
-    fn handle_integer(&mut self, i: &AssemblerInstruction){
-        // Being a constant declaration, this is only meaningful in the first pass
-        if self.phase != AssemblerPhase::First {
-            return;
-        }
+    /// Handles a declaration of a 32-bit integer:
+    /// count: .integer #BUFSIZE*2
+    /// The space is set aside in the first pass and the value written in the second, once every label and constant
+    /// it may use is known
+    fn handle_integer(&mut self, i: &AssemblerInstruction) {
+        let name = match i.get_label_name() {
+            Some(name) => name,
+            None => {
+                // This would be someone typing:
+                // .integer #5
+                if self.phase == AssemblerPhase::First {
+                    self.errors
+                        .push(AssemblerError::StringConstantDeclaredWithoutLabel {
+                            span: i.spans.instruction,
+                        });
+                }
+                return;
+            }
+        };
+        let span = i.spans.operands[0].unwrap_or(i.spans.instruction);
+        let expression = match i.get_integer_constant() {
+            Some(expression) => expression,
+            None => {
+                if self.phase == AssemblerPhase::First {
+                    self.errors.push(AssemblerError::InvalidOperand { span });
+                }
+                return;
+            }
+        };
 
-        // In this case, operand1 will have the entire string we need to read in to RO memory
-        match i.get_i32_constant() {
-            Some(s) => {
-                match i.get_label_name() {
-                    Some(name) => {
-                        self.symbols.set_symbol_offset(&name, self.ro_offset);
+        match self.phase {
+            AssemblerPhase::First => {
+                self.symbols.set_symbol_offset(&name, self.ro_offset);
+                self.label_sections.insert(name, SectionKind::ReadOnly);
+                self.ro.extend_from_slice(&[0; 4]);
+                self.ro_offset += 4;
+            }
+            AssemblerPhase::Second => {
+                let offset = self.symbols.symbol_value(&name).unwrap_or(0);
+                if self.relocatable && !expression.labels().is_empty() {
+                    match self.relocation(
+                        &expression,
+                        SectionKind::ReadOnly,
+                        offset,
+                        RelocationKind::Word32,
+                        span,
+                    ) {
+                        Ok(relocation) => self.relocations.push(relocation),
+                        Err(e) => {
+                            self.errors.push(e);
+                            return;
+                        }
                     }
-                    None => {
-                        // This would be someone typing:
-                        // .asciiz 'Hello'
-                        println!("Found a string constant with no associated label!");
+                }
+                let value = match expression.evaluate_with(self.operand_symbols()) {
+                    Ok(value) => value,
+                    Err(e) => {
+                        self.errors
+                            .push(AssemblerError::from_expression_error(e, span));
                         return;
                     }
                 };
-                
-                let mut wtr = vec![];
-                // TODO: Remove unwrap?
-                wtr.write_i32::<LittleEndian>(s).unwrap();
-                for byte in &wtr {
-                    self.ro.push(*byte);
-                    self.ro_offset += 1;
+                // Both signed and unsigned 32-bit values are accepted, and stored as their 32-bit pattern
+                if !INTEGER_RANGE.contains(&value) {
+                    self.errors.push(AssemblerError::IntegerOutOfRange {
+                        value: value.into(),
+                        min: (*INTEGER_RANGE.start()).into(),
+                        max: (*INTEGER_RANGE.end()).into(),
+                        span,
+                    });
+                    return;
+                }
+                let offset = offset as usize;
+                LittleEndian::write_u32(&mut self.ro[offset..offset + 4], value as u32);
+            }
+        }
+    }
+
+    /// Handles a declaration of a little endian f64, such as:
+    /// pi: .float #3.14159
+    /// Its value is a number rather than an expression, so it is written in the first pass
+    fn handle_float(&mut self, i: &AssemblerInstruction) {
+        if self.phase != AssemblerPhase::First {
+            return;
+        }
+        let name = match i.get_label_name() {
+            Some(name) => name,
+            None => {
+                self.errors
+                    .push(AssemblerError::StringConstantDeclaredWithoutLabel {
+                        span: i.spans.instruction,
+                    });
+                return;
+            }
+        };
+        let value = match &i.operand1 {
+            Some(Token::FloatOperand { value }) => *value,
+            Some(Token::IntegerOperand { value }) => *value as f64,
+            _ => {
+                self.errors.push(AssemblerError::InvalidOperand {
+                    span: i.spans.operands[0].unwrap_or(i.spans.instruction),
+                });
+                return;
+            }
+        };
+        self.symbols.set_symbol_offset(&name, self.ro_offset);
+        self.label_sections.insert(name, SectionKind::ReadOnly);
+        self.ro.extend_from_slice(&value.to_le_bytes());
+        self.ro_offset += 8;
+    }
+
+    /// Handles a declaration of a constant:
+    /// .equ BUFSIZE, #256
+    /// Constants are given their values once the first pass is done, since they can use labels declared after them.
+    /// `.set` can declare a constant again, and the constant has the new value from there on. Anything using it
+    /// before its first declaration has the first value
+    fn handle_constant(&mut self, i: &AssemblerInstruction) {
+        let name_span = i.spans.operands[0].unwrap_or(i.spans.instruction);
+        let name = match &i.operand1 {
+            Some(Token::ConstantDeclaration { name }) => name.clone(),
+            _ => {
+                if self.phase == AssemblerPhase::First {
+                    self.errors
+                        .push(AssemblerError::InvalidOperand { span: name_span });
                 }
+                return;
+            }
+        };
+        if self.phase != AssemblerPhase::First {
+            // The second pass gives the constant the value of each of its declarations as it gets to them
+            let value = self
+                .constants
+                .iter()
+                .find(|c| c.name_span == name_span)
+                .and_then(|c| c.resolved);
+            if let Some(value) = value {
+                self.set_constant_value(&name, value);
             }
+            return;
+        }
+
+        let value_span = i.spans.operands[1].unwrap_or(i.spans.instruction);
+        let value = match i.operand2.as_ref().and_then(Token::to_expression) {
+            Some(value) => value,
             None => {
-                // This just means someone typed `.asciiz` for some reason
-                println!("integer constant following an .integer was empty");
+                self.errors
+                    .push(AssemblerError::InvalidOperand { span: value_span });
+                return;
+            }
+        };
+
+        // An object can't have constants that depend on where it ends up
+        if self.relocatable && !value.labels().is_empty() {
+            self.errors
+                .push(AssemblerError::UnrelocatableExpression { span: value_span });
+            return;
+        }
+
+        // Constants and labels share one namespace, and only `.set` can declare a constant that already is one
+        let redeclared = i.get_directive_name().as_deref() == Some("set")
+            && self.constants.iter().any(|c| c.name == name);
+        if self.symbols.has_symbol(&name) && !redeclared {
+            self.errors.push(AssemblerError::SymbolAlreadyDeclared {
+                name,
+                span: name_span,
+            });
+            return;
+        }
+        if !redeclared {
+            self.symbols.add_symbol(Symbol::constant(name.clone(), 0));
+        }
+        self.constants.push(ConstantDeclaration {
+            name,
+            name_span,
+            value,
+            value_span,
+            resolved: None,
+        });
+    }
+
+    /// Sets the value of a constant, both in the symbols and in the symbols operands are encoded with
+    fn set_constant_value(&mut self, name: &str, value: i64) {
+        self.symbols.set_symbol_value(name, value);
+        if let Some(symbols) = &mut self.unrelocated_symbols {
+            symbols.set_symbol_value(name, value);
+        }
+    }
+
+    /// Turns the offsets of code labels into their addresses in the image, which is what jumps and calls take. This
+    /// runs once the first pass knows the length of the read-only section, which comes before the code
+    fn place_code_labels(&mut self) {
+        let code_start = pie::sections_start(self.image_section_count()) as u32 + self.ro_offset;
+        for (name, section) in &self.label_sections {
+            if *section == SectionKind::Code {
+                let offset = self.symbols.symbol_value(name).unwrap_or(0);
+                self.symbols.set_symbol_offset(name, code_start + offset);
+            }
+        }
+    }
+
+    /// Handles a label exported from an object or imported into it:
+    /// .global main
+    /// .extern print
+    fn handle_global_or_extern(&mut self, directive: &str, i: &AssemblerInstruction) {
+        if self.phase != AssemblerPhase::First {
+            return;
+        }
+        let span = i.spans.operands[0].unwrap_or(i.spans.instruction);
+        let name = match &i.operand1 {
+            Some(Token::LabelUsage { name }) => name.clone(),
+            _ => {
+                self.errors.push(AssemblerError::InvalidOperand { span });
+                return;
+            }
+        };
+        let list = if directive == "global" {
+            &mut self.globals
+        } else {
+            &mut self.externs
+        };
+        if !list.iter().any(|(declared, _)| *declared == name) {
+            list.push((name, span));
+        }
+    }
+
+    /// Checks that every `.global` label is declared, and that no `.extern` label is. This runs once all labels are
+    /// known
+    fn check_globals_and_externs(&mut self) {
+        for (name, span) in &self.globals {
+            if self.symbols.symbol_value(name).is_none() {
+                self.errors.push(AssemblerError::UndefinedSymbol {
+                    name: name.clone(),
+                    span: *span,
+                });
+            }
+        }
+        for (name, span) in &self.externs {
+            if self.symbols.has_symbol(name) {
+                self.errors.push(AssemblerError::SymbolAlreadyDeclared {
+                    name: name.clone(),
+                    span: *span,
+                });
+            }
+        }
+    }
+
+    /// The symbols used to encode operands in the second pass
+    fn operand_symbols(&self) -> &SymbolTable {
+        self.unrelocated_symbols.as_ref().unwrap_or(&self.symbols)
+    }
+
+    /// The symbol table with every label, including the imported ones, at zero. Operands are encoded with it in an
+    /// object, so only the constant part of an expression using a label ends up in the bytecode
+    fn unrelocated_symbols(&self) -> SymbolTable {
+        let mut symbols = self.symbols.clone();
+        for symbol in &mut symbols.symbols {
+            if *symbol.symbol_type() == SymbolType::Label {
+                *symbol = Symbol::new(symbol.name().to_string(), SymbolType::Label, 0);
+            }
+        }
+        for (name, _) in &self.externs {
+            symbols.add_symbol(Symbol::new(name.clone(), SymbolType::Label, 0));
+        }
+        symbols
+    }
+
+    /// Records a relocation for every operand of `i` that uses a label. `offset` is where `i` is in the code section
+    fn relocate_operands(&mut self, i: &AssemblerInstruction, offset: u32) {
+        let info = match &i.opcode {
+            Some(Token::Op { code }) => code.info(),
+            _ => None,
+        };
+        let info = match info {
+            Some(info) => info,
+            None => return,
+        };
+        for (slot, operand) in i.operands().into_iter().enumerate() {
+            let expression = match operand.to_expression() {
+                Some(expression) if !expression.labels().is_empty() => expression,
+                _ => continue,
+            };
+            let span = i.spans.operands[slot].unwrap_or(i.spans.instruction);
+            let offset = offset + info.operand_offset(slot) as u32;
+            // A long `load` has the low half of the address in its `load` and the high half in the `lui` after it
+            let kind = if i.is_long_load() {
+                RelocationKind::Low16
+            } else {
+                RelocationKind::Imm16
+            };
+            match self.relocation(&expression, SectionKind::Code, offset, kind, span) {
+                Ok(relocation) => {
+                    if i.is_long_load() {
+                        self.relocations.push(Relocation {
+                            offset: offset + info.width as u32,
+                            kind: RelocationKind::High16,
+                            ..relocation.clone()
+                        });
+                    }
+                    self.relocations.push(relocation);
+                }
+                Err(e) => self.errors.push(e),
+            }
+        }
+    }
+
+    /// The relocation writing the value of `expression` at `offset` in `section`
+    fn relocation(
+        &self,
+        expression: &Expression,
+        section: SectionKind,
+        offset: u32,
+        kind: RelocationKind,
+        span: Span,
+    ) -> Result<Relocation, AssemblerError> {
+        let (name, addend) = expression
+            .label_term()
+            .ok_or(AssemblerError::UnrelocatableExpression { span })?;
+        let addend = addend
+            .evaluate_with(&self.symbols)
+            .map_err(|e| AssemblerError::from_expression_error(e, span))?;
+        // Labels of this object are relocated relative to the start of their section
+        let (target, addend) = match self.label_sections.get(name) {
+            Some(kind) => (
+                RelocationTarget::Section(*kind),
+                addend + self.symbols.symbol_value(name).unwrap_or(0) as i64,
+            ),
+            None => (RelocationTarget::Import(name.to_string()), addend),
+        };
+        Ok(Relocation {
+            section,
+            offset,
+            kind,
+            target,
+            addend: i32::try_from(addend)
+                .map_err(|_| AssemblerError::ExpressionOverflow { span })?,
+        })
+    }
+
+    /// Gives every constant its value. This runs between the passes, when the offsets of all labels are known
+    fn resolve_constants(&mut self) {
+        let mut resolved = HashMap::new();
+        for index in 0..self.constants.len() {
+            resolve_constant(
+                &self.constants,
+                &self.symbols,
+                index,
+                &mut resolved,
+                &mut vec![],
+                &mut self.errors,
+            );
+        }
+        // A constant declared more than once starts out with its first value
+        for index in (0..self.constants.len()).rev() {
+            let constant = &mut self.constants[index];
+            constant.resolved = resolved.get(&index).copied().flatten();
+            if let Some(value) = constant.resolved {
+                self.symbols.set_symbol_value(&constant.name, value);
             }
         }
     }
 }
 
+/// A constant from a `.equ` or `.set` directive, waiting for the first pass to end so it can be evaluated
+#[derive(Debug, PartialEq, Clone)]
+struct ConstantDeclaration {
+    name: String,
+    name_span: Span,
+    value: Expression,
+    value_span: Span,
+    /// The value, once `resolve_constants` has worked it out
+    resolved: Option<i64>,
+}
+
+/// Evaluates the constant at `index`, evaluating the constants it uses first. `resolved` holds the value of every
+/// constant evaluated so far, or `None` if it failed, and `resolving` the ones being evaluated further up, to find
+/// cycles. Each failure is reported to `errors` once, and constants using a failed one fail without another error
+fn resolve_constant(
+    constants: &[ConstantDeclaration],
+    symbols: &SymbolTable,
+    index: usize,
+    resolved: &mut HashMap<usize, Option<i64>>,
+    resolving: &mut Vec<usize>,
+    errors: &mut Vec<AssemblerError>,
+) -> Option<i64> {
+    if let Some(value) = resolved.get(&index) {
+        return *value;
+    }
+    let constant = &constants[index];
+    if resolving.contains(&index) {
+        errors.push(AssemblerError::CyclicConstant {
+            name: constant.name.clone(),
+            span: constant.name_span,
+        });
+        return None;
+    }
+
+    resolving.push(index);
+    let mut dependency_failed = false;
+    let result = constant.value.evaluate(&mut |name| match name {
+        Name::Label(label) => symbols
+            .symbol_value(label)
+            .map(|offset| offset as i64)
+            .ok_or_else(|| ExpressionError::Undefined {
+                name: label.to_string(),
+            }),
+        Name::Constant(name) => {
+            let undefined = || ExpressionError::Undefined {
+                name: name.to_string(),
+            };
+            // A constant declared again with `.set` has the value of its last declaration before this one
+            let other = constants[..index]
+                .iter()
+                .rposition(|c| c.name == name)
+                .or_else(|| constants.iter().position(|c| c.name == name))
+                .ok_or_else(undefined)?;
+            let value = resolve_constant(constants, symbols, other, resolved, resolving, errors);
+            dependency_failed |= value.is_none();
+            value.ok_or_else(undefined)
+        }
+    });
+    resolving.pop();
+
+    let value = match result {
+        Ok(value) => Some(value),
+        Err(_) if dependency_failed => None,
+        Err(e) => {
+            errors.push(AssemblerError::from_expression_error(e, constant.value_span));
+            None
+        }
+    };
+    resolved.insert(index, value);
+    value
+}
+
 impl From<&str> for AssemblerSection {
     fn from(header_name: &str) -> Self {
         match header_name {
@@ -398,6 +1269,8 @@ impl From<&str> for AssemblerSection {
 }
 
 mod tests {
+    use super::program_parsers::program;
+    use super::*;
     use crate::vm::VM;
 
     use super::*;
@@ -409,11 +1282,11 @@ mod tests {
         sym.add_symbol(new_symbol);
         assert_eq!(sym.symbols.len(), 1);
         let v = sym.symbol_value("test");
-        assert_eq!(true, v.is_some());
+        assert!(v.is_some());
         let v = v.unwrap();
         assert_eq!(v, 12);
         let v = sym.symbol_value("does_not_exist");
-        assert_eq!(v.is_some(), false);
+        assert!(v.is_none());
     }
 
     #[test]
@@ -426,18 +1299,193 @@ mod tests {
         load $1 #1
         load $2 #0
         test: inc $0
+        load $3 @test
         neq $0 $2
-        jeq @test
+        jeq $3
+        hlt
+        ";
+        let program = asm.assemble(test_string).unwrap();
+        let mut vm = VM::new();
+        assert_eq!(program.len(), 125);
+        vm.add_bytes(program);
+        assert_eq!(vm.program.len(), 125);
+    }
+
+    #[test]
+    fn test_load_any_32_bit_value() {
+        let mut asm = Assembler::new();
+        let test_string = r"
+        .equ BIG, #0x7FFF_FFFF
+        .data
+        .code
+        load $0 #-1
+        load $1 #0x12345678
+        load $2 #0xFFFFFFFF
+        load $3 #40000
+        load $4 #BIG
+        load $5 #-32768
         hlt
         ";
         let program = asm.assemble(test_string).unwrap();
         let mut vm = VM::new();
-        assert_eq!(program.len(), 93); // somehow cannot get 96
         vm.add_bytes(program);
-        assert_eq!(vm.program.len(), 93);
+        vm.run().unwrap();
+        assert_eq!(
+            vm.registers[..6],
+            [-1, 0x1234_5678, -1, 40000, i32::MAX, -32768]
+        );
+    }
 
-        println!("{:?}", vm.program);
-        println!("{:?}", vm.program.len());
+    #[test]
+    fn test_conditional_branches() {
+        let mut asm = Assembler::new();
+        let test_string = r"
+        .data
+        .code
+        load $1 #5
+        loop: add $2 $0 $2
+        inc $0
+        eq $0 $1
+        jlt @loop
+        gt $0 $1
+        jge @equal
+        load $3 #1
+        equal: jneq @done
+        load $4 #1
+        done: load $5 @end
+        lt $1 $0
+        jle $5
+        load $6 #1
+        end: hlt
+        ";
+        let program = asm.assemble(test_string).unwrap();
+        let mut vm = VM::new();
+        vm.add_bytes(program);
+        vm.run().unwrap();
+        // Every branch after the loop skips the `load` after it
+        assert_eq!(vm.registers[..5], [5, 5, 10, 0, 0]);
+        assert_eq!(vm.registers[6], 0);
+    }
+
+    #[test]
+    fn test_overflow_branches() {
+        let mut asm = Assembler::new();
+        let test_string = r"
+        .data
+        .code
+        load $0 #0x7FFFFFFF
+        inc $0
+        jnov @skipped
+        load $1 #1
+        skipped: add $1 $1 $2
+        jov @end
+        load $3 #1
+        end: hlt
+        ";
+        let program = asm.assemble(test_string).unwrap();
+        let mut vm = VM::new();
+        vm.add_bytes(program);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[..4], [i32::MIN, 1, 2, 1]);
+    }
+
+    #[test]
+    fn test_floats() {
+        let mut asm = Assembler::new();
+        let test_string = r"
+        .data
+        pi: .float #3.141592653589793
+        count: .integer #7
+        half: .float #0.5
+        .code
+        loadf $f0 #3
+        loadf $f1 #-2.5e-3
+        ldrf $f2 @pi
+        ldrf $f3 @half
+        addf $f0 $f3 $f4
+        mulf $f2 $f0 $f5
+        divf $f0 $f3 $f6
+        subf $f1 $f1 $f7
+        ftoi $f5 $1
+        load $2 #-7
+        itof $2 $f8
+        ltf $f1 $f7
+        jlt @end
+        load $3 #1
+        end: hlt
+        ";
+        let program = asm.assemble(test_string).unwrap();
+        let mut vm = VM::new();
+        vm.add_bytes(program);
+        vm.run().unwrap();
+        assert_eq!(
+            vm.float_registers[..9],
+            [
+                3.0,
+                -2.5e-3,
+                std::f64::consts::PI,
+                0.5,
+                3.5,
+                3.0 * std::f64::consts::PI,
+                6.0,
+                0.0,
+                -7.0
+            ]
+        );
+        assert_eq!(vm.registers[1], 9);
+        assert_eq!(vm.registers[3], 0);
+    }
+
+    #[test]
+    fn test_jump_to_every_label() {
+        // Instructions of every width between the labels, after some read-only data, and every label starts with
+        // `inc $2` so landing anywhere else shows
+        let mut asm = Assembler::new();
+        let test_string = r"
+        .data
+        greeting: .asciiz 'Hello'
+        count: .integer #3
+        .code
+        load $1 @first
+        jmp $1
+        hlt
+        first: inc $2
+        prts @greeting
+        load $1 @second
+        jmp $1
+        load $3 #99
+        second: inc $2
+        eq $2 $2
+        load $1 @third
+        jeq $1
+        hlt
+        third: inc $2
+        call @fourth
+        hlt
+        fourth: inc $2
+        load $5 #4
+        aloc $5
+        lw $4 $0 #0
+        ret
+        ";
+        let program = asm.assemble(test_string).unwrap();
+        let header = pie::read_pie(&program).unwrap();
+        let code = header.section(SectionKind::Code).unwrap();
+        assert_eq!(code.offset, pie::sections_start(2) as u32 + 10);
+
+        for label in ["first", "second", "third", "fourth"] {
+            let address = asm.symbols.symbol_value(label).unwrap() as usize;
+            assert!(code.range().contains(&address), "{} is at {}", label, address);
+            assert_eq!(program[address..address + 2], [Opcode::INC as u8, 2], "{}", label);
+        }
+        assert_eq!(asm.symbols.symbol_value("greeting"), Some(0));
+        assert_eq!(asm.symbols.symbol_value("count"), Some(6));
+
+        let mut vm = VM::new();
+        vm.add_bytes(program);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[2], 4);
+        assert_eq!(vm.registers[3], 0);
     }
 
     #[test]
@@ -452,15 +1500,20 @@ mod tests {
         load $1 #1
         load $2 #0
         test: inc $0
+        load $3 @test
         neq $0 $2
-        jeq @test
+        jeq $3
         hlt
         ";
         let program = asm.assemble(test_string);
-        assert_eq!(program.is_ok(), true);
+        assert!(program.is_ok());
         let unwrapped = program.unwrap();
-        assert_eq!(unwrapped[64], 6);
-        println!("{:?}", unwrapped);
+        let header = pie::read_pie(&unwrapped).unwrap();
+        let ro = header.section(SectionKind::ReadOnly).unwrap();
+        assert_eq!(ro.length, 6);
+        let code = header.section(SectionKind::Code).unwrap();
+        assert_eq!(code.offset, ro.offset + 6);
+        assert_eq!(header.entry_point, code.offset);
     }
 
     #[test]
@@ -473,7 +1526,7 @@ mod tests {
         .code
         ";
         let program = asm.assemble(test_string);
-        assert_eq!(program.is_ok(), true);
+        assert!(program.is_ok());
     }
 
     #[test]
@@ -486,7 +1539,7 @@ mod tests {
         .code
         ";
         let program = asm.assemble(test_string);
-        assert_eq!(program.is_ok(), true);
+        assert!(program.is_ok());
     }
 
     #[test]
@@ -499,7 +1552,22 @@ mod tests {
         .wrong
         ";
         let program = asm.assemble(test_string);
-        assert_eq!(program.is_ok(), false);
+        assert!(program.is_err());
+    }
+
+    #[test]
+    /// Tests that an `.asciiz` of something other than a string is an error rather than being skipped
+    fn test_asciiz_without_string() {
+        let mut asm = Assembler::new();
+        let errors = asm
+            .assemble(".data\nhello: .asciiz #5\n.code\nhlt\n")
+            .unwrap_err();
+        assert_eq!(
+            errors,
+            vec![AssemblerError::ExpectedStringConstant {
+                span: Span::new(".data\nhello: .asciiz #5", 21, 23),
+            }]
+        );
     }
 
     #[test]
@@ -508,7 +1576,7 @@ mod tests {
         let mut asm = Assembler::new();
         let test_string = "hello: .asciiz 'Fail'";
         let result = program(test_string);
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (_, mut p) = result.unwrap();
         asm.process_first_phase(&mut p);
         assert_eq!(asm.errors.len(), 1);
@@ -523,9 +1591,360 @@ mod tests {
         test: .asciiz 'Hello'
         ";
         let result = program(test_string);
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (_, mut p) = result.unwrap();
         asm.process_first_phase(&mut p);
         assert_eq!(asm.errors.len(), 0);
     }
+
+    #[test]
+    /// Tests that every error in a program is reported in one run, each pointing at where it is
+    fn test_assemble_collects_all_errors() {
+        let mut asm = Assembler::new();
+        let test_string = ".data\nname: .asciiz 'a'\nname: .asciiz 'b'\n.code\nlod $0 #1\nadd $0 #5 $1\n";
+        let errors = asm.assemble(test_string).unwrap_err();
+        let positions: Vec<(usize, usize)> = errors
+            .iter()
+            .map(|e| e.span().map(|s| (s.line, s.column)).unwrap())
+            .collect();
+        assert_eq!(positions, vec![(3, 1), (5, 1), (6, 8)]);
+        assert_eq!(
+            errors[0],
+            AssemblerError::SymbolAlreadyDeclared {
+                name: "name".to_string(),
+                span: Span::new(test_string, 24, 29),
+            }
+        );
+    }
+
+    #[test]
+    /// Tests that a label outside of any section is reported at the label
+    fn test_no_segment_error_span() {
+        let mut asm = Assembler::new();
+        let errors = asm.assemble("hello: .asciiz 'Fail'").unwrap_err();
+        assert_eq!(
+            errors[0],
+            AssemblerError::NoSegmentDeclarationFound {
+                span: Span::new("hello: .asciiz 'Fail'", 0, 6),
+            }
+        );
+        assert_eq!(errors[1], AssemblerError::InsufficientSections);
+    }
+
+    #[test]
+    /// Tests that labels that are never declared are all reported instead of ending the process
+    fn test_assemble_undefined_symbols() {
+        let mut asm = Assembler::new();
+        let test_string = ".data\n.code\ncall @first\nload $0 @second\nhlt\n";
+        let errors = asm.assemble(test_string).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                AssemblerError::UndefinedSymbol {
+                    name: "first".to_string(),
+                    span: Span::new(test_string, 17, 23),
+                },
+                AssemblerError::UndefinedSymbol {
+                    name: "second".to_string(),
+                    span: Span::new(test_string, 32, 39),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    /// Tests that `.integer` takes any 32-bit value, signed or not, and nothing wider
+    fn test_ro_data_i32_range() {
+        let mut asm = Assembler::new();
+        asm.assemble(".data\nmask: .integer #0xFFFF_FFFF\nlow: .integer #-2_147_483_648\n.code\nhlt\n")
+            .unwrap();
+        assert_eq!(asm.ro, vec![255, 255, 255, 255, 0, 0, 0, 128]);
+
+        let mut asm = Assembler::new();
+        let errors = asm
+            .assemble(".data\nbig: .integer #0x1_0000_0000\n.code\nhlt\n")
+            .unwrap_err();
+        assert_eq!(
+            errors,
+            vec![AssemblerError::IntegerOutOfRange {
+                value: 0x1_0000_0000,
+                min: i32::MIN.into(),
+                max: u32::MAX.into(),
+                span: Span::new(".data\nbig: .integer #0x1_0000_0000", 20, 34),
+            }]
+        );
+    }
+
+    #[test]
+    /// Tests that constants and expressions are evaluated, including constants declared after they are used
+    fn test_constants_and_expressions() {
+        let mut asm = Assembler::new();
+        let test_string = r"
+        .equ BUFSIZE, #256
+        .data
+        msg: .asciiz 'Hello'
+        size: .integer #TWICE-1
+        .code
+        load $0 #TWICE
+        load $1 #BUFSIZE/2%100
+        prts @msg+1
+        load $2 @size-@msg
+        hlt
+        .set TWICE, #BUFSIZE*2+1
+        ";
+        let image = asm.assemble(test_string).unwrap();
+        let header = pie::read_pie(&image).unwrap();
+        let ro = &image[header.section(SectionKind::ReadOnly).unwrap().range()];
+        assert_eq!(&ro[6..], &[0, 2, 0, 0]);
+        let code = &image[header.section(SectionKind::Code).unwrap().range()];
+        assert_eq!(
+            code,
+            // Loads of labels and of constants declared further on take a `load` and a `lui`, loads of what is
+            // already known only take a `load`
+            &[
+                0, 0, 2, 1, 31, 0, 0, 0, 0, 1, 0, 28, 20, 0, 1, 0, 2, 0, 6, 31, 2, 0, 0, 5
+            ]
+        );
+    }
+
+    #[test]
+    /// Tests that a `load` of a constant that is already known takes the short form when its value fits
+    fn test_load_known_constant() {
+        let mut asm = Assembler::new();
+        let image = asm
+            .assemble(".equ K, #5\n.data\n.code\nload $0 #K\nhlt\n")
+            .unwrap();
+        assert_eq!(image.len(), 101);
+        let header = pie::read_pie(&image).unwrap();
+        assert_eq!(
+            &image[header.section(SectionKind::Code).unwrap().range()],
+            &[0, 0, 0, 5, 5]
+        );
+
+        // Too big for the short form, or using a label, it still takes the long one
+        let mut asm = Assembler::new();
+        let image = asm
+            .assemble(".equ K, #0x1_0000\n.data\n.code\nload $0 #K\nhlt\n")
+            .unwrap();
+        assert_eq!(image.len(), 105);
+        let mut asm = Assembler::new();
+        let image = asm
+            .assemble(".equ K, @end-@start\n.data\n.code\nstart: load $0 #K\nend: hlt\n")
+            .unwrap();
+        assert_eq!(image.len(), 105);
+    }
+
+    #[test]
+    /// Tests that only `.set` can declare a constant again, and neither can use the name of a label
+    fn test_constant_redeclared() {
+        let mut asm = Assembler::new();
+        let test_string = ".equ A, #1\n.equ A, #2\n.data\nB: .integer #1\n.code\n.set B, #3\nhlt\n";
+        let errors = asm.assemble(test_string).unwrap_err();
+        let names: Vec<_> = errors
+            .iter()
+            .map(|e| match e {
+                AssemblerError::SymbolAlreadyDeclared { name, .. } => name.as_str(),
+                _ => panic!("unexpected error {:?}", e),
+            })
+            .collect();
+        assert_eq!(names, vec!["A", "B"]);
+    }
+
+    #[test]
+    /// Tests that a constant declared again with `.set` has the new value from there on
+    fn test_constant_set_again() {
+        let mut asm = Assembler::new();
+        let test_string = r"
+        .equ A, #1
+        .data
+        first: .integer #A
+        .code
+        load $0 #A
+        .set A, #A+1
+        .equ B, #A*10
+        load $1 #A
+        prts #A
+        load $2 #B
+        .set A, #0x1_0000
+        load $3 #A
+        hlt
+        ";
+        let image = asm.assemble(test_string).unwrap();
+        let header = pie::read_pie(&image).unwrap();
+        let ro = &image[header.section(SectionKind::ReadOnly).unwrap().range()];
+        assert_eq!(ro, &[1, 0, 0, 0]);
+        let code = &image[header.section(SectionKind::Code).unwrap().range()];
+        assert_eq!(
+            code,
+            &[0, 0, 0, 1, 0, 1, 0, 2, 20, 0, 2, 0, 2, 0, 20, 0, 3, 0, 0, 31, 3, 0, 1, 5]
+        );
+    }
+
+    #[test]
+    /// Tests that errors in constants are reported once each, at the constant
+    fn test_constant_errors() {
+        let mut asm = Assembler::new();
+        let test_string = ".equ A, #B+1\n.equ B, #A\n.equ C, #A*2\n.equ D, #0x4000_0000_0000_0000*2\n.equ E, #F\n.data\n.code\nload $0 #G\nhlt\n";
+        let errors = asm.assemble(test_string).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                AssemblerError::CyclicConstant {
+                    name: "A".to_string(),
+                    span: Span::new(test_string, 5, 6),
+                },
+                AssemblerError::ExpressionOverflow {
+                    span: Span::new(test_string, 45, 69),
+                },
+                AssemblerError::UndefinedSymbol {
+                    name: "F".to_string(),
+                    span: Span::new(test_string, 78, 80),
+                },
+            ]
+        );
+
+        // Names used by instructions are only looked up in the second pass
+        let mut asm = Assembler::new();
+        let errors = asm.assemble(".data\n.code\nload $0 #G+1\nhlt\n").unwrap_err();
+        assert_eq!(
+            errors,
+            vec![AssemblerError::UndefinedSymbol {
+                name: "G".to_string(),
+                span: Span::new(".data\n.code\nload $0 #G+1", 20, 24),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_assemble_object() {
+        let source = ".data\nmessage: .asciiz 'Hi'\nend: .integer @finish+1\n.code\n.global start\n.extern print\nstart: prts @message\ncall @print\nfinish: load $0 @print-2\nhlt\n";
+        let object = Assembler::new().assemble_object(source).unwrap();
+        assert_eq!(object.ro, b"Hi\0\x01\0\0\0".to_vec());
+        assert_eq!(
+            object.code,
+            vec![20, 0, 0, 21, 0, 0, 0, 0, 0xFF, 0xFE, 31, 0, 0xFF, 0xFF, 5]
+        );
+        assert_eq!(
+            object.exports,
+            vec![Export {
+                name: "start".to_string(),
+                section: SectionKind::Code,
+                offset: 0,
+            }]
+        );
+        assert_eq!(object.imports, vec!["print".to_string()]);
+        let relocation = |section, offset, kind, target, addend| Relocation {
+            section,
+            offset,
+            kind,
+            target,
+            addend,
+        };
+        assert_eq!(
+            object.relocations,
+            vec![
+                relocation(
+                    SectionKind::ReadOnly,
+                    3,
+                    RelocationKind::Word32,
+                    RelocationTarget::Section(SectionKind::Code),
+                    7,
+                ),
+                relocation(
+                    SectionKind::Code,
+                    1,
+                    RelocationKind::Imm16,
+                    RelocationTarget::Section(SectionKind::ReadOnly),
+                    0,
+                ),
+                relocation(
+                    SectionKind::Code,
+                    4,
+                    RelocationKind::Imm16,
+                    RelocationTarget::Import("print".to_string()),
+                    0,
+                ),
+                relocation(
+                    SectionKind::Code,
+                    12,
+                    RelocationKind::High16,
+                    RelocationTarget::Import("print".to_string()),
+                    -2,
+                ),
+                relocation(
+                    SectionKind::Code,
+                    8,
+                    RelocationKind::Low16,
+                    RelocationTarget::Import("print".to_string()),
+                    -2,
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_object_errors() {
+        let source = ".equ SIZE, @end-@start\n.data\n.code\n.global missing\n.extern start\nstart: load $0 @start*2\nend: hlt\n";
+        let errors = Assembler::new().assemble_object(source).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                AssemblerError::UnrelocatableExpression {
+                    span: Span::new(source, 11, 22),
+                },
+                AssemblerError::UndefinedSymbol {
+                    name: "missing".to_string(),
+                    span: Span::new(source, 43, 50),
+                },
+                AssemblerError::SymbolAlreadyDeclared {
+                    name: "start".to_string(),
+                    span: Span::new(source, 59, 64),
+                },
+            ]
+        );
+
+        let source = ".data\n.code\nstart: load $0 @start*2\n";
+        assert_eq!(
+            Assembler::new().assemble_object(source),
+            Err(vec![AssemblerError::UnrelocatableExpression {
+                span: Span::new(source, 27, 35),
+            }])
+        );
+
+        // Imported labels are only known once linked
+        let errors = Assembler::new()
+            .assemble(".data\n.code\n.extern print\ncall @print\n")
+            .unwrap_err();
+        assert!(matches!(&errors[0], AssemblerError::UndefinedSymbol { name, .. } if name == "print"));
+    }
+
+    #[test]
+    fn test_macros() {
+        let source = ".macro countdown reg, from\nload \\reg \\from\nloop\\@: dec \\reg\n.endm\n.data\n.code\ncountdown $0, #3\ncountdown $1, #4\nhlt\n";
+        let expanded = ".data\n.code\nload $0 #3\nloop1: dec $0\nload $1 #4\nloop2: dec $1\nhlt\n";
+        assert_eq!(
+            Assembler::new().assemble(source),
+            Assembler::new().assemble(expanded)
+        );
+    }
+
+    #[test]
+    fn test_error_in_macro_expansion() {
+        let source = ".macro twice r\nadd \\r \\r\n.endm\n.data\n.code\ntwice $1\nhlt\n";
+        let errors = Assembler::new().assemble(source).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![AssemblerError::InMacroExpansion {
+                error: Box::new(AssemblerError::WrongOperandCount {
+                    opcode: Opcode::ADD,
+                    expected: 3,
+                    found: 2,
+                    span: Span::new(source, 15, 24),
+                }),
+                name: "twice".to_string(),
+                call: Span::new(source, 43, 51),
+                definition: Span::new(source, 0, 14),
+            }]
+        );
+    }
 }
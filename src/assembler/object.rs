@@ -0,0 +1,257 @@
+//! Relocatable objects, which hold code and read-only data whose addresses are not known until they are linked
+//! together with other objects into an image, see `linker`.
+//!
+//! An object is written as a PIE image with the `PIE_FLAG_OBJECT` flag set, a read-only section, a code section and
+//! these three sections, each a list of entries. Integers are little endian and names are a u16 length followed by
+//! the name.
+//!
+//! - Exports: the section the symbol is defined in (u8, the section kind), its offset in that section (u32) and its
+//!   name.
+//! - Imports: the name of every symbol the object uses from other objects.
+//! - Relocations: the section the address is written in (u8), its offset there (u32), the kind of relocation (u8,
+//!   0 for `Imm16`, 1 for `Word32`, 2 for `Low16` and 3 for `High16`), the addend (i32) and the target. The target is a section kind (u8) when it
+//!   is in this object, with the addend being the offset in that section, or a 0 followed by the name of an import.
+
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+
+use crate::assembler::pie::{self, PieError, SectionKind, PIE_FLAG_OBJECT};
+
+/// A relocatable object, as produced by `Assembler::assemble_object`
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Object {
+    pub ro: Vec<u8>,
+    pub code: Vec<u8>,
+    pub exports: Vec<Export>,
+    pub imports: Vec<String>,
+    pub relocations: Vec<Relocation>,
+}
+
+/// A symbol an object makes available to others with `.global`
+#[derive(Debug, PartialEq, Clone)]
+pub struct Export {
+    pub name: String,
+    /// `SectionKind::Code` or `SectionKind::ReadOnly`
+    pub section: SectionKind,
+    pub offset: u32,
+}
+
+/// An address to write once the object is linked, for a use of a label
+#[derive(Debug, PartialEq, Clone)]
+pub struct Relocation {
+    /// `SectionKind::Code` for an instruction operand or `SectionKind::ReadOnly` for an `.integer`
+    pub section: SectionKind,
+    pub offset: u32,
+    pub kind: RelocationKind,
+    pub target: RelocationTarget,
+    /// Added to the address of the target
+    pub addend: i32,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RelocationKind {
+    /// A 16-bit instruction operand, high byte first
+    Imm16,
+    /// A 32-bit little endian integer
+    Word32,
+    /// The low 16 bits of a 32-bit address, in an instruction operand
+    Low16,
+    /// The high 16 bits of a 32-bit address, in an instruction operand
+    High16,
+}
+
+/// What a relocation is the address of
+#[derive(Debug, PartialEq, Clone)]
+pub enum RelocationTarget {
+    /// The start of one of the object's own sections
+    Section(SectionKind),
+    /// A symbol another object exports
+    Import(String),
+}
+
+impl Object {
+    /// Encodes the object as a PIE image
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut exports = vec![];
+        for export in &self.exports {
+            exports.push(export.section.to_u32() as u8);
+            exports.write_u32::<LittleEndian>(export.offset).unwrap();
+            write_name(&mut exports, &export.name);
+        }
+        let mut imports = vec![];
+        for import in &self.imports {
+            write_name(&mut imports, import);
+        }
+        let mut relocations = vec![];
+        for relocation in &self.relocations {
+            relocations.push(relocation.section.to_u32() as u8);
+            relocations
+                .write_u32::<LittleEndian>(relocation.offset)
+                .unwrap();
+            relocations.push(match relocation.kind {
+                RelocationKind::Imm16 => 0,
+                RelocationKind::Word32 => 1,
+                RelocationKind::Low16 => 2,
+                RelocationKind::High16 => 3,
+            });
+            relocations
+                .write_i32::<LittleEndian>(relocation.addend)
+                .unwrap();
+            match &relocation.target {
+                RelocationTarget::Section(kind) => relocations.push(kind.to_u32() as u8),
+                RelocationTarget::Import(name) => {
+                    relocations.push(0);
+                    write_name(&mut relocations, name);
+                }
+            }
+        }
+
+        pie::write_pie(
+            PIE_FLAG_OBJECT,
+            0,
+            &[
+                (SectionKind::ReadOnly, &self.ro),
+                (SectionKind::Code, &self.code),
+                (SectionKind::Exports, &exports),
+                (SectionKind::Imports, &imports),
+                (SectionKind::Relocations, &relocations),
+            ],
+        )
+    }
+
+    /// Decodes an object written by `to_bytes`
+    pub fn from_bytes(image: &[u8]) -> Result<Object, PieError> {
+        let header = pie::read_pie(image)?;
+        if header.flags & PIE_FLAG_OBJECT == 0 {
+            return Err(PieError::NotObject);
+        }
+        let section = |kind| {
+            header
+                .section(kind)
+                .map_or(&[][..], |section| &image[section.range()])
+        };
+
+        let mut object = Object {
+            ro: section(SectionKind::ReadOnly).to_vec(),
+            code: section(SectionKind::Code).to_vec(),
+            ..Default::default()
+        };
+        let mut reader = Reader(section(SectionKind::Exports));
+        while !reader.0.is_empty() {
+            let section = reader.section()?;
+            let offset = reader.u32()?;
+            let name = reader.name()?;
+            object.exports.push(Export {
+                name,
+                section,
+                offset,
+            });
+        }
+        let mut reader = Reader(section(SectionKind::Imports));
+        while !reader.0.is_empty() {
+            object.imports.push(reader.name()?);
+        }
+        let mut reader = Reader(section(SectionKind::Relocations));
+        while !reader.0.is_empty() {
+            let section = reader.section()?;
+            let offset = reader.u32()?;
+            let kind = match reader.u8()? {
+                0 => RelocationKind::Imm16,
+                1 => RelocationKind::Word32,
+                2 => RelocationKind::Low16,
+                3 => RelocationKind::High16,
+                _ => return Err(PieError::MalformedObject),
+            };
+            let addend = reader.u32()? as i32;
+            let target = match reader.u8()? {
+                0 => RelocationTarget::Import(reader.name()?),
+                kind => RelocationTarget::Section(
+                    SectionKind::from_u32(kind as u32).ok_or(PieError::MalformedObject)?,
+                ),
+            };
+            object.relocations.push(Relocation {
+                section,
+                offset,
+                kind,
+                target,
+                addend,
+            });
+        }
+        Ok(object)
+    }
+}
+
+fn write_name(bytes: &mut Vec<u8>, name: &str) {
+    bytes.write_u16::<LittleEndian>(name.len() as u16).unwrap();
+    bytes.extend_from_slice(name.as_bytes());
+}
+
+/// Reads the entries of an object's sections, failing with `MalformedObject` when they end too early
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], PieError> {
+        if self.0.len() < length {
+            return Err(PieError::MalformedObject);
+        }
+        let (taken, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, PieError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, PieError> {
+        Ok(LittleEndian::read_u32(self.take(4)?))
+    }
+
+    fn section(&mut self) -> Result<SectionKind, PieError> {
+        SectionKind::from_u32(self.u8()? as u32).ok_or(PieError::MalformedObject)
+    }
+
+    fn name(&mut self) -> Result<String, PieError> {
+        let length = LittleEndian::read_u16(self.take(2)?) as usize;
+        String::from_utf8(self.take(length)?.to_vec()).map_err(|_| PieError::MalformedObject)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_object_roundtrip() {
+        let object = Object {
+            ro: b"Hi\0".to_vec(),
+            code: vec![21, 0, 0, 5],
+            exports: vec![Export {
+                name: "greet".to_string(),
+                section: SectionKind::Code,
+                offset: 0,
+            }],
+            imports: vec!["print".to_string()],
+            relocations: vec![
+                Relocation {
+                    section: SectionKind::Code,
+                    offset: 1,
+                    kind: RelocationKind::Imm16,
+                    target: RelocationTarget::Import("print".to_string()),
+                    addend: 0,
+                },
+                Relocation {
+                    section: SectionKind::ReadOnly,
+                    offset: 0,
+                    kind: RelocationKind::Word32,
+                    target: RelocationTarget::Section(SectionKind::Code),
+                    addend: -2,
+                },
+            ],
+        };
+        let bytes = object.to_bytes();
+        assert_eq!(Object::from_bytes(&bytes), Ok(object));
+
+        let image = pie::write_pie(0, 0, &[(SectionKind::Code, &[5])]);
+        assert_eq!(Object::from_bytes(&image), Err(PieError::NotObject));
+    }
+}
@@ -1,15 +1,11 @@
-use nom::{
-    bytes::complete::{tag, take_until}, character::complete::{alpha1, multispace0}, combinator::map, sequence::delimited, IResult
-};
+use nom::{character::complete::alpha1, combinator::map, IResult};
 
 use super::Token;
 use crate::instruction::Opcode;
 
 pub fn opcode_load(input: &str) -> IResult<&str, Token> {
-    map(delimited(multispace0, alpha1, multispace0), |code: &str| {
-        Token::Op {
-            code: Opcode::from(code),
-        }
+    map(alpha1, |code: &str| Token::Op {
+        code: Opcode::from(code),
     })(input)
 }
 
@@ -20,15 +16,14 @@ mod tests {
     fn test_opcode_load() {
         // First tests that the opcode is detected and parsed correctly
         let result = opcode_load("load");
-        println!("{:?}", result);
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (rest, token) = result.unwrap();
         assert_eq!(token, Token::Op { code: Opcode::LOAD });
         assert_eq!(rest, "");
 
         // Tests that an invalid opcode isn't recognized
         let result = opcode_load("aold");
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (rest, token) = result.unwrap();
         assert_eq!(token, Token::Op { code: Opcode::IGL });
         assert_eq!(rest, "");
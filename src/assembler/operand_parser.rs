@@ -1,57 +1,148 @@
-use crate::assembler::register_parser::register;
+use crate::assembler::register_parser::{float_register, register};
 use crate::assembler::Token;
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_until},
-    character::complete::{digit1, multispace0},
-    combinator::{map, map_res, opt},
-    sequence::{delimited, preceded, tuple},
+    bytes::complete::{tag, tag_no_case, take_until, take_while},
+    character::complete::{digit1, none_of, one_of, satisfy},
+    combinator::{map, map_res, not, opt, peek, recognize, value},
+    error::{Error, ErrorKind},
+    sequence::{delimited, pair, preceded, terminated, tuple},
     IResult,
 };
 
+use super::expression::{expression, Expression};
 use super::label_parsers::label_usage;
 
+/// An integer operand: `#` followed by a number or a character literal, such as `#-10`, `#0xFF`, `#0b1010`,
+/// `#0o17`, `#1_000_000` or `#'A'`. The value is only checked against the width of where it is used later on
 pub fn integer_operand(input: &str) -> IResult<&str, Token> {
-    // delimited(
-    //     multispace0,
-    //     preceded(
-    //         tag("#"),
-    //         map_res(digit1, |digits: &str| {
-    //             digits
-    //                 .parse::<u8>()
-    //                 .map(|value| Token::IntegerOperand { value: digits as i32 })
-    //         }),
-    //     ),
-    //     multispace0,
-    // )(input)
-
-    delimited(
-        multispace0,
+    map(preceded(tag("#"), integer_literal), |value| {
+        Token::IntegerOperand { value }
+    })(input)
+}
+
+/// A number or a character literal, without the `#`
+pub fn integer_literal(input: &str) -> IResult<&str, i64> {
+    alt((char_literal, number))(input)
+}
+
+/// A float operand: `#` followed by a decimal number with a fraction, an exponent or both, such as `#1.5`, `#-0.25`
+/// or `#6.02e23`. Numbers without either are integers
+pub fn float_operand(input: &str) -> IResult<&str, Token> {
+    let exponent = |input| recognize(tuple((one_of("eE"), opt(one_of("+-")), digit1)))(input);
+    map_res(
         preceded(
             tag("#"),
-            map_res(
-                tuple((opt(tag("-")), digit1)),
-                |(sign, digits): (Option<&str>, &str)| {
-                    let mut tmp = String::new();
-                    if sign.is_some() {
-                        tmp.push('-');
-                    }
-                    tmp.push_str(digits);
-                    tmp.parse::<i32>().map(|value: i32| Token::IntegerOperand {value})
-                },
+            terminated(
+                recognize(tuple((
+                    opt(tag("-")),
+                    digit1,
+                    alt((
+                        recognize(tuple((tag("."), digit1, opt(exponent)))),
+                        exponent,
+                    )),
+                ))),
+                not(satisfy(|c| c.is_alphanumeric() || c == '_' || c == '.')),
             ),
         ),
-        multispace0,
+        |number: &str| number.parse().map(|value| Token::FloatOperand { value }),
     )(input)
 }
 
-fn irstring(input: &str) -> IResult<&str, Token> {
+/// A constant expression, either after a `#` (`#BUFSIZE*2+1`) or starting with a label (`@table+8`,
+/// `@end-@start`). Expressions that are just a number or just a label are given as the simpler tokens
+pub fn expression_operand(input: &str) -> IResult<&str, Token> {
+    map(
+        alt((preceded(tag("#"), expression), preceded(peek(tag("@")), expression))),
+        |expression| match expression {
+            Expression::Number(value) => Token::IntegerOperand { value },
+            Expression::Label(name) => Token::LabelUsage { name },
+            expression => Token::Expression { expression },
+        },
+    )(input)
+}
+
+/// A decimal number, or a hex, binary or octal one with a `0x`, `0b` or `0o` prefix. Digits can be grouped with `_`.
+/// A number that does not fit in 64 bits fails with `ErrorKind::TooLarge` at its start, which `wide_number` can
+/// parse again to report it
+fn number(input: &str) -> IResult<&str, i64> {
+    let (rest, value) = wide_number(input)?;
+    match i64::try_from(value) {
+        Ok(value) => Ok((rest, value)),
+        Err(_) => Err(nom::Err::Failure(Error::new(input, ErrorKind::TooLarge))),
+    }
+}
+
+/// A number as `number` parses it, but on 128 bits, beyond which it saturates
+pub fn wide_number(input: &str) -> IResult<&str, i128> {
+    map(
+        terminated(
+            tuple((
+                opt(tag("-")),
+                alt((
+                    preceded(tag_no_case("0x"), digits(16)),
+                    preceded(tag_no_case("0b"), digits(2)),
+                    preceded(tag_no_case("0o"), digits(8)),
+                    digits(10),
+                )),
+            )),
+            // `#0b12` is a mistake, not `#0b1` followed by something else
+            not(satisfy(|c| c.is_alphanumeric() || c == '_')),
+        ),
+        |(sign, (radix, digits)): (Option<&str>, (u32, &str))| {
+            let digits: String = digits.chars().filter(|c| *c != '_').collect();
+            // The digits were all checked, so only overflowing can fail
+            match (i128::from_str_radix(&digits, radix), sign) {
+                (Ok(value), Some(_)) => -value,
+                (Ok(value), None) => value,
+                (Err(_), Some(_)) => i128::MIN,
+                (Err(_), None) => i128::MAX,
+            }
+        },
+    )(input)
+}
+
+/// Digits in `radix`, with `_` allowed after the first one
+fn digits(radix: u32) -> impl FnMut(&str) -> IResult<&str, (u32, &str)> {
+    move |input| {
+        map(
+            recognize(pair(
+                satisfy(|c| c.is_digit(radix)),
+                take_while(|c: char| c.is_digit(radix) || c == '_'),
+            )),
+            |digits| (radix, digits),
+        )(input)
+    }
+}
+
+/// A single character in quotes, whose value is its code point. `\n`, `\t`, `\r`, `\0`, `\\` and `\'` are escapes
+fn char_literal(input: &str) -> IResult<&str, i64> {
     map(
         delimited(
-            multispace0,
-            delimited(tag("'"), take_until("'"), tag("'")),
-            multispace0,
+            tag("'"),
+            alt((
+                preceded(
+                    tag("\\"),
+                    alt((
+                        value('\n', tag("n")),
+                        value('\t', tag("t")),
+                        value('\r', tag("r")),
+                        value('\0', tag("0")),
+                        value('\\', tag("\\")),
+                        value('\'', tag("'")),
+                    )),
+                ),
+                none_of("\\'"),
+            )),
+            tag("'"),
         ),
+        |c| c as i64,
+    )(input)
+}
+
+fn irstring(input: &str) -> IResult<&str, Token> {
+    map(
+        delimited(tag("'"), take_until("'"), tag("'")),
         |content: &str| Token::IrString {
             name: content.to_string(),
         },
@@ -59,7 +150,14 @@ fn irstring(input: &str) -> IResult<&str, Token> {
 }
 
 pub fn operand(intput: &str) -> IResult<&str, Token> {
-    alt((integer_operand, label_usage, register, irstring))(intput)
+    alt((
+        float_operand,
+        expression_operand,
+        label_usage,
+        float_register,
+        register,
+        irstring,
+    ))(intput)
 }
 
 #[test]
@@ -68,18 +166,59 @@ fn test_parse_integer_operand() {
 
     // Test a valid integer operand
     let result = integer_operand("#-10");
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
     let (rest, value) = result.unwrap();
     assert_eq!(rest, "");
     assert_eq!(value, Token::IntegerOperand { value: -10 });
 
     // Test an invalid one (missing the #)
     let result = integer_operand("10");
-    assert_eq!(result.is_ok(), false);
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_parse_integer_literal_forms() {
+    let value = |input| match integer_operand(input) {
+        Ok(("", Token::IntegerOperand { value })) => Some(value),
+        _ => None,
+    };
+    assert_eq!(value("#0xFF"), Some(255));
+    assert_eq!(value("#0Xff"), Some(255));
+    assert_eq!(value("#-0x10"), Some(-16));
+    assert_eq!(value("#0b1010"), Some(10));
+    assert_eq!(value("#0o17"), Some(15));
+    assert_eq!(value("#1_000_000"), Some(1_000_000));
+    assert_eq!(value("#0xFFFF_FFFF"), Some(0xFFFF_FFFF));
+    assert_eq!(value("#'A'"), Some(65));
+    assert_eq!(value("#'\\n'"), Some(10));
+    assert_eq!(value("#'\\''"), Some(39));
+
+    assert_eq!(value("#0b12"), None);
+    assert_eq!(value("#0x"), None);
+    assert_eq!(value("#_1"), None);
+    assert_eq!(value("#12abc"), None);
+    assert_eq!(value("#''"), None);
+    assert_eq!(value("#99999999999999999999"), None);
+}
+
+#[test]
+fn test_parse_float_operand() {
+    let value = |input| match operand(input) {
+        Ok(("", Token::FloatOperand { value })) => Some(value),
+        _ => None,
+    };
+    assert_eq!(value("#1.5"), Some(1.5));
+    assert_eq!(value("#-0.25"), Some(-0.25));
+    assert_eq!(value("#6.02e23"), Some(6.02e23));
+    assert_eq!(value("#1E-3"), Some(0.001));
+    assert_eq!(value("#1"), None);
+    assert_eq!(value("#1."), None);
+    assert_eq!(value("#1.5.2"), None);
+    assert_eq!(value("#1.5e"), None);
 }
 
 #[test]
 fn test_parse_string_operand() {
     let result = irstring("'This is a test'");
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
 }
@@ -0,0 +1,392 @@
+//! Reading and writing of PIE images, the binary format the assembler produces and the VM runs.
+//!
+//! An image starts with a fixed 64-byte header, followed by a section table and then the bytes of each section.
+//! All integers are little endian.
+//!
+//! | Offset | Size | Field                                                     |
+//! |--------|------|-----------------------------------------------------------|
+//! | 0      | 4    | magic, `PIE_HEADER_PREFIX`                                |
+//! | 4      | 2    | format version, `PIE_VERSION`                             |
+//! | 6      | 2    | number of entries in the section table                    |
+//! | 8      | 4    | flags, `PIE_FLAG_OBJECT` or zero                          |
+//! | 12     | 4    | entry point, as an offset from the start of the image     |
+//! | 16     | 48   | reserved, always zero                                     |
+//! | 64     | 16*n | section table                                             |
+//!
+//! Every section table entry is 16 bytes: the section kind, its offset from the start of the image, its length and
+//! the CRC-32 of its bytes, each a u32. The writer lays the sections out in the order they are given, directly after
+//! the section table.
+//!
+//! An image has exactly one code section and at most one of every other kind. The symbols section is a list of
+//! entries made of the symbol type (u8), the symbol offset (u32), the length of the name (u16) and the name itself.
+//!
+//! Relocatable objects, which are linked into an image rather than run, use the same layout with the
+//! `PIE_FLAG_OBJECT` flag set and three more sections, see `assembler::object`.
+
+use std::ops::Range;
+
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+
+use super::symbols::{Symbol, SymbolTable, SymbolType};
+
+pub const PIE_HEADER_PREFIX: [u8; 4] = [45, 50, 49, 45];
+pub const PIE_HEADER_LENGTH: usize = 64;
+/// The version of the format written by `write_pie`
+pub const PIE_VERSION: u16 = 1;
+/// The length of one entry in the section table
+pub const PIE_SECTION_ENTRY_LENGTH: usize = 16;
+/// Set in the flags of a relocatable object
+pub const PIE_FLAG_OBJECT: u32 = 1;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SectionKind {
+    Code,
+    ReadOnly,
+    Symbols,
+    Debug,
+    /// The symbols a relocatable object defines for other objects
+    Exports,
+    /// The symbols a relocatable object uses from other objects
+    Imports,
+    /// Where a relocatable object needs addresses patched in when it is linked
+    Relocations,
+}
+
+impl SectionKind {
+    pub(crate) fn to_u32(self) -> u32 {
+        match self {
+            SectionKind::Code => 1,
+            SectionKind::ReadOnly => 2,
+            SectionKind::Symbols => 3,
+            SectionKind::Debug => 4,
+            SectionKind::Exports => 5,
+            SectionKind::Imports => 6,
+            SectionKind::Relocations => 7,
+        }
+    }
+
+    pub(crate) fn from_u32(v: u32) -> Option<SectionKind> {
+        match v {
+            1 => Some(SectionKind::Code),
+            2 => Some(SectionKind::ReadOnly),
+            3 => Some(SectionKind::Symbols),
+            4 => Some(SectionKind::Debug),
+            5 => Some(SectionKind::Exports),
+            6 => Some(SectionKind::Imports),
+            7 => Some(SectionKind::Relocations),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum PieError {
+    /// The image does not start with `PIE_HEADER_PREFIX`
+    BadMagic,
+    UnsupportedVersion {
+        version: u16,
+    },
+    /// The image ends before the header or the section table does
+    Truncated,
+    UnknownSectionKind {
+        kind: u32,
+    },
+    DuplicateSection {
+        kind: SectionKind,
+    },
+    MissingCodeSection,
+    SectionOutOfBounds {
+        kind: SectionKind,
+    },
+    ChecksumMismatch {
+        kind: SectionKind,
+    },
+    EntryPointOutsideCode {
+        entry_point: u32,
+    },
+    /// The symbols section could not be decoded
+    MalformedSymbols,
+    /// A relocatable object was given where an image to run is needed
+    IsObject,
+    /// An image to run was given where a relocatable object is needed
+    NotObject,
+    /// The exports, imports or relocations of an object could not be decoded
+    MalformedObject,
+}
+
+/// One entry of the section table
+#[derive(Debug, PartialEq, Clone)]
+pub struct PieSection {
+    pub kind: SectionKind,
+    pub offset: u32,
+    pub length: u32,
+    pub checksum: u32,
+}
+
+impl PieSection {
+    /// The bytes of the image this section covers
+    pub fn range(&self) -> Range<usize> {
+        self.offset as usize..(self.offset + self.length) as usize
+    }
+}
+
+/// The decoded header and section table of an image
+#[derive(Debug, PartialEq, Clone)]
+pub struct PieHeader {
+    pub version: u16,
+    pub flags: u32,
+    pub entry_point: u32,
+    pub sections: Vec<PieSection>,
+}
+
+impl PieHeader {
+    pub fn section(&self, kind: SectionKind) -> Option<&PieSection> {
+        self.sections.iter().find(|s| s.kind == kind)
+    }
+}
+
+/// The offset the first section starts at when the table has `section_count` entries
+pub fn sections_start(section_count: usize) -> usize {
+    PIE_HEADER_LENGTH + section_count * PIE_SECTION_ENTRY_LENGTH
+}
+
+/// Writes an image made of `sections`, in the given order. `entry_point` is relative to the start of the code section
+pub fn write_pie(flags: u32, entry_point: u32, sections: &[(SectionKind, &[u8])]) -> Vec<u8> {
+    let mut offset = sections_start(sections.len());
+    let mut table = vec![];
+    let mut code_offset = 0;
+    for (kind, bytes) in sections {
+        if *kind == SectionKind::Code {
+            code_offset = offset;
+        }
+        table.write_u32::<LittleEndian>(kind.to_u32()).unwrap();
+        table.write_u32::<LittleEndian>(offset as u32).unwrap();
+        table.write_u32::<LittleEndian>(bytes.len() as u32).unwrap();
+        table.write_u32::<LittleEndian>(crc32(bytes)).unwrap();
+        offset += bytes.len();
+    }
+
+    let mut image = vec![];
+    image.extend_from_slice(&PIE_HEADER_PREFIX);
+    image.write_u16::<LittleEndian>(PIE_VERSION).unwrap();
+    image
+        .write_u16::<LittleEndian>(sections.len() as u16)
+        .unwrap();
+    image.write_u32::<LittleEndian>(flags).unwrap();
+    image
+        .write_u32::<LittleEndian>(code_offset as u32 + entry_point)
+        .unwrap();
+    image.resize(PIE_HEADER_LENGTH, 0);
+    image.append(&mut table);
+    for (_, bytes) in sections {
+        image.extend_from_slice(bytes);
+    }
+    image
+}
+
+/// Reads and validates the header and section table of `image`
+pub fn read_pie(image: &[u8]) -> Result<PieHeader, PieError> {
+    if !image.starts_with(&PIE_HEADER_PREFIX) {
+        return Err(PieError::BadMagic);
+    }
+    if image.len() < PIE_HEADER_LENGTH {
+        return Err(PieError::Truncated);
+    }
+    let version = LittleEndian::read_u16(&image[4..]);
+    if version != PIE_VERSION {
+        return Err(PieError::UnsupportedVersion { version });
+    }
+    let section_count = LittleEndian::read_u16(&image[6..]) as usize;
+    let flags = LittleEndian::read_u32(&image[8..]);
+    let entry_point = LittleEndian::read_u32(&image[12..]);
+    if image.len() < sections_start(section_count) {
+        return Err(PieError::Truncated);
+    }
+
+    let mut sections: Vec<PieSection> = vec![];
+    for entry in
+        image[PIE_HEADER_LENGTH..sections_start(section_count)].chunks(PIE_SECTION_ENTRY_LENGTH)
+    {
+        let raw_kind = LittleEndian::read_u32(entry);
+        let kind = SectionKind::from_u32(raw_kind)
+            .ok_or(PieError::UnknownSectionKind { kind: raw_kind })?;
+        if sections.iter().any(|s| s.kind == kind) {
+            return Err(PieError::DuplicateSection { kind });
+        }
+        let section = PieSection {
+            kind,
+            offset: LittleEndian::read_u32(&entry[4..]),
+            length: LittleEndian::read_u32(&entry[8..]),
+            checksum: LittleEndian::read_u32(&entry[12..]),
+        };
+        let end = section.offset as usize + section.length as usize;
+        if (section.offset as usize) < sections_start(section_count) || end > image.len() {
+            return Err(PieError::SectionOutOfBounds { kind });
+        }
+        if crc32(&image[section.range()]) != section.checksum {
+            return Err(PieError::ChecksumMismatch { kind });
+        }
+        sections.push(section);
+    }
+
+    let header = PieHeader {
+        version,
+        flags,
+        entry_point,
+        sections,
+    };
+    let code = header
+        .section(SectionKind::Code)
+        .ok_or(PieError::MissingCodeSection)?;
+    if entry_point < code.offset || entry_point > code.offset + code.length {
+        return Err(PieError::EntryPointOutsideCode { entry_point });
+    }
+    Ok(header)
+}
+
+/// Encodes `symbols` into the contents of a symbols section. Constants only exist while assembling and are left out
+pub fn write_symbols(symbols: &SymbolTable) -> Vec<u8> {
+    let mut bytes = vec![];
+    for symbol in &symbols.symbols {
+        let type_byte = match symbol.symbol_type() {
+            SymbolType::Label => 0,
+            SymbolType::Constant => continue,
+        };
+        bytes.push(type_byte);
+        bytes.write_u32::<LittleEndian>(symbol.offset()).unwrap();
+        bytes
+            .write_u16::<LittleEndian>(symbol.name().len() as u16)
+            .unwrap();
+        bytes.extend_from_slice(symbol.name().as_bytes());
+    }
+    bytes
+}
+
+/// Decodes the contents of a symbols section
+pub fn read_symbols(mut bytes: &[u8]) -> Result<SymbolTable, PieError> {
+    let mut symbols = SymbolTable::new();
+    while !bytes.is_empty() {
+        if bytes.len() < 7 {
+            return Err(PieError::MalformedSymbols);
+        }
+        let symbol_type = match bytes[0] {
+            0 => SymbolType::Label,
+            _ => return Err(PieError::MalformedSymbols),
+        };
+        let offset = LittleEndian::read_u32(&bytes[1..]);
+        let name_length = LittleEndian::read_u16(&bytes[5..]) as usize;
+        let name = bytes
+            .get(7..7 + name_length)
+            .and_then(|name| std::str::from_utf8(name).ok())
+            .ok_or(PieError::MalformedSymbols)?;
+        symbols.add_symbol(Symbol::new(name.to_string(), symbol_type, offset));
+        bytes = &bytes[7 + name_length..];
+    }
+    Ok(symbols)
+}
+
+/// CRC-32 (IEEE) of `bytes`, used for the section checksums
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_image() -> Vec<u8> {
+        write_pie(
+            0,
+            1,
+            &[
+                (SectionKind::ReadOnly, b"Hi\0"),
+                (SectionKind::Code, &[16, 0, 0, 0, 5]),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn test_write_read_roundtrip() {
+        let image = test_image();
+        let header = read_pie(&image).unwrap();
+        assert_eq!(header.version, PIE_VERSION);
+        assert_eq!(header.sections.len(), 2);
+
+        let ro = header.section(SectionKind::ReadOnly).unwrap();
+        assert_eq!(ro.offset as usize, sections_start(2));
+        assert_eq!(&image[ro.range()], b"Hi\0");
+
+        let code = header.section(SectionKind::Code).unwrap();
+        assert_eq!(code.offset as usize, sections_start(2) + 3);
+        assert_eq!(&image[code.range()], &[16, 0, 0, 0, 5]);
+        assert_eq!(header.entry_point, code.offset + 1);
+        assert_eq!(header.section(SectionKind::Symbols), None);
+    }
+
+    #[test]
+    fn test_read_rejects_bad_images() {
+        let image = test_image();
+
+        let mut bad = image.clone();
+        bad[0] = 0;
+        assert_eq!(read_pie(&bad), Err(PieError::BadMagic));
+
+        let mut bad = image.clone();
+        bad[4] = 2;
+        assert_eq!(
+            read_pie(&bad),
+            Err(PieError::UnsupportedVersion { version: 2 })
+        );
+
+        let mut bad = image.clone();
+        let last = bad.len() - 1;
+        bad[last] = 6;
+        assert_eq!(
+            read_pie(&bad),
+            Err(PieError::ChecksumMismatch {
+                kind: SectionKind::Code
+            })
+        );
+
+        let mut bad = image.clone();
+        bad.truncate(bad.len() - 1);
+        assert_eq!(
+            read_pie(&bad),
+            Err(PieError::SectionOutOfBounds {
+                kind: SectionKind::Code
+            })
+        );
+
+        assert_eq!(
+            read_pie(&image[..PIE_HEADER_LENGTH + 4]),
+            Err(PieError::Truncated)
+        );
+
+        let no_code = write_pie(0, 0, &[(SectionKind::ReadOnly, b"Hi\0")]);
+        assert_eq!(read_pie(&no_code), Err(PieError::MissingCodeSection));
+    }
+
+    #[test]
+    fn test_symbols_roundtrip() {
+        let mut symbols = SymbolTable::new();
+        symbols.add_symbol(Symbol::new("start".to_string(), SymbolType::Label, 96));
+        symbols.add_symbol(Symbol::new("hello".to_string(), SymbolType::Label, 0));
+        let bytes = write_symbols(&symbols);
+        assert_eq!(read_symbols(&bytes), Ok(symbols));
+        assert_eq!(read_symbols(&bytes[..5]), Err(PieError::MalformedSymbols));
+    }
+}
@@ -1,15 +1,10 @@
 use crate::assembler::instruction_parsers::{instruction, AssemblerInstruction};
-use nom::{
-    branch::alt,
-    bytes::complete::tag,
-    character::complete::{digit1, multispace0},
-    combinator::{eof, map, map_res, value},
-    multi::many1,
-    sequence::{delimited, preceded},
-    IResult,
-};
-
-use super::SymbolTable;
+use crate::assembler::comment_parsers::whitespace;
+use crate::assembler::operand_parser::wide_number;
+use crate::assembler::span::{LineIndex, Span};
+use nom::{combinator::map, error::ErrorKind, multi::many1, IResult};
+
+use super::{AssemblerError, SymbolTable};
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Program {
@@ -17,27 +12,88 @@ pub struct Program {
 }
 
 impl Program {
-    pub fn to_bytes(&self, symbols: &SymbolTable) -> Vec<u8> {
+    /// Checks the operands of every instruction, returning all the mismatches found
+    pub fn check_operands(&self) -> Vec<AssemblerError> {
+        self.instructions
+            .iter()
+            .filter(|i| i.is_opcode())
+            .flat_map(|i| i.check_operands())
+            .collect()
+    }
+
+    /// Encodes every instruction, returning all the errors found if any of them can't be
+    pub fn to_bytes(&self, symbols: &SymbolTable) -> Result<Vec<u8>, Vec<AssemblerError>> {
         let mut program = vec![];
+        let mut errors = vec![];
         for instruction in &self.instructions {
-            program.append(&mut instruction.to_bytes(symbols));
+            match instruction.to_bytes(symbols) {
+                Ok(mut bytes) => program.append(&mut bytes),
+                Err(e) => errors.push(e),
+            }
         }
 
-        program
+        if errors.is_empty() {
+            Ok(program)
+        } else {
+            Err(errors)
+        }
     }
 }
 
-pub fn program(input: &str) -> IResult<&str, Program> {
-    map(many1(instruction), |instructions| Program { instructions })(input)
+pub fn program<'a>(input: &'a str) -> IResult<&'a str, Program> {
+    let lines = LineIndex::new(input);
+    map(
+        many1(move |rest: &'a str| {
+            // Instructions only know where they are relative to their own start, so move them to where they are in
+            // the whole program
+            let offset = input.len() - rest.len();
+            let (rest, mut instruction) = instruction(rest)?;
+            instruction.relocate(input, offset, &lines);
+            Ok((rest, instruction))
+        }),
+        |instructions| Program { instructions },
+    )(input)
+}
+
+/// Parses a whole program. Unlike `program`, anything left over is an error, located where parsing stopped and
+/// spanning the rest of that line
+pub fn parse_program(input: &str) -> Result<Program, AssemblerError> {
+    let rest = match program(input) {
+        Ok(("", program)) => return Ok(program),
+        Ok((rest, _)) => rest,
+        Err(nom::Err::Failure(e)) if e.code == ErrorKind::TooLarge => {
+            // A number too big for 64 bits, which is reported like any other number too big for where it is
+            let start = input.len() - e.input.len();
+            let (rest, value) = wide_number(e.input).expect("only numbers fail as too large");
+            return Err(AssemblerError::IntegerOutOfRange {
+                value,
+                min: i64::MIN.into(),
+                max: i64::MAX.into(),
+                span: Span::new(input, start, input.len() - rest.len()),
+            });
+        }
+        Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => e.input,
+        Err(nom::Err::Incomplete(_)) => "",
+    };
+    // Point at what could not be parsed rather than the whitespace before it
+    let rest = whitespace(rest).map_or(rest, |(rest, _)| rest);
+    let start = input.len() - rest.len();
+    let end = start + rest.find('\n').unwrap_or(rest.len());
+    Err(AssemblerError::ParseError {
+        error: "expected an instruction or a directive".to_string(),
+        span: Span::new(input, start, end),
+    })
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
+    use crate::assembler::Token;
 
     #[test]
     fn test_parse_program() {
         let result = program("load $0 #100\nload $3 #120");
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (leftover, p) = result.unwrap();
         assert_eq!(leftover, "");
         assert_eq!(2, p.instructions.len());
@@ -47,18 +103,89 @@ mod tests {
     #[test]
     fn test_program_to_bytes() {
         let result = program("load $0 #100\n");
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (_, program) = result.unwrap();
         let symbols = SymbolTable::new();
-        let bytecode = program.to_bytes(&symbols);
+        let bytecode = program.to_bytes(&symbols).unwrap();
         assert_eq!(bytecode.len(), 4);
-        println!("{:?}", bytecode);
+    }
+
+    #[test]
+    fn test_program_spans() {
+        let source = ".data\nhello: .asciiz 'Hi'\n.code\n  load $0 #1\n";
+        let (_, p) = program(source).unwrap();
+        let load = &p.instructions[3];
+        assert_eq!(load.spans.instruction.start, 34);
+        assert_eq!(load.spans.instruction.end, 44);
+        assert_eq!(load.spans.instruction.line, 4);
+        assert_eq!(load.spans.instruction.column, 3);
+        let operand = load.spans.operands[1].unwrap();
+        assert_eq!(&source[operand.start..operand.end], "#1");
+        assert_eq!((operand.line, operand.column), (4, 11));
     }
 
     #[test]
     fn test_complete_program() {
         let test_program = ".data\nhello: .asciiz 'Hello everyone!'\n.code\nhlt";
         let result = program(test_program);
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_program_with_comments() {
+        let source = "; A comment before anything\n.data\n.code /* block */\nload $0 /* between\noperands */ #100 ; trailing\n#! shell-style\nhlt";
+        let (rest, p) = program(source).unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(p.instructions.len(), 4);
+        let load = &p.instructions[2];
+        assert_eq!(load.operand2, Some(Token::IntegerOperand { value: 100 }));
+        assert_eq!((load.spans.instruction.line, load.spans.instruction.column), (4, 1));
+        let symbols = SymbolTable::new();
+        assert_eq!(load.to_bytes(&symbols).unwrap(), vec![0, 0, 0, 100]);
+    }
+
+    #[test]
+    fn test_parse_program_rejects_leftover_input() {
+        let source = ".data\n.code\nload $0 #1\n  !!! $1\nhlt\n";
+        assert_eq!(
+            parse_program(source),
+            Err(AssemblerError::ParseError {
+                error: "expected an instruction or a directive".to_string(),
+                span: Span::new(source, 25, 31),
+            })
+        );
+        assert!(parse_program("load $0 #1 ; done\n").is_ok());
+    }
+
+    #[test]
+    fn test_parse_program_unclosed_comment() {
+        let source = "hlt\n/* never closed\nhlt";
+        let e = parse_program(source).unwrap_err();
+        assert_eq!(e.span(), Some(Span::new(source, 4, 19)));
+    }
+
+    #[test]
+    fn test_parse_program_nothing_to_parse() {
+        let source = "  ; only a comment\n  @";
+        let e = parse_program(source).unwrap_err();
+        assert_eq!(e.span().map(|s| (s.line, s.column)), Some((2, 3)));
+    }
+
+    #[test]
+    fn test_parse_program_number_beyond_64_bits() {
+        let source = ".data\n.code\nload $0 #99999999999999999999\nhlt\n";
+        assert_eq!(
+            parse_program(source),
+            Err(AssemblerError::IntegerOutOfRange {
+                value: 99999999999999999999,
+                min: i64::MIN.into(),
+                max: i64::MAX.into(),
+                span: Span::new(source, 21, 41),
+            })
+        );
+        let source = "load $0 #-0x8000_0000_0000_0001 + 1";
+        let e = parse_program(source).unwrap_err();
+        assert_eq!(e.span(), Some(Span::new(source, 9, 31)));
+        assert!(parse_program("load $0 #-9223372036854775808\n").is_ok());
     }
 }
@@ -2,24 +2,32 @@ use crate::assembler::Token;
 use nom::{
     branch::alt,
     bytes::complete::tag,
-    character::complete::{digit1, multispace0},
-    combinator::{eof, map_res, value},
-    sequence::{delimited, preceded},
+    character::complete::digit1,
+    combinator::map_res,
+    sequence::preceded,
     IResult,
 };
 
+/// A float register, such as `$f3`
+pub fn float_register(input: &str) -> IResult<&str, Token> {
+    preceded(
+        tag("$f"),
+        map_res(digit1, |digits: &str| {
+            digits
+                .parse::<u8>()
+                .map(|reg_num| Token::FloatRegister { reg_num })
+        }),
+    )(input)
+}
+
 pub fn register(input: &str) -> IResult<&str, Token> {
-    delimited(
-        multispace0,
-        preceded(
-            tag("$"),
-            map_res(digit1, |digits: &str| {
-                digits
-                    .parse::<u8>()
-                    .map(|reg_num| Token::Register { reg_num })
-            }),
-        ),
-        multispace0,
+    preceded(
+        tag("$"),
+        map_res(digit1, |digits: &str| {
+            digits
+                .parse::<u8>()
+                .map(|reg_num| Token::Register { reg_num })
+        }),
     )(input)
 }
 
@@ -29,12 +37,17 @@ mod tests {
     #[test]
     fn test_parse_register() {
         let result = register("$1");
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (_, token) = result.unwrap();
         assert_eq!(token, Token::Register { reg_num: 1 });
         let result = register("0");
-        assert_eq!(result.is_ok(), false);
+        assert!(result.is_err());
         let result = register("$a");
-        assert_eq!(result.is_ok(), false);
+        assert!(result.is_err());
+        assert_eq!(
+            float_register("$f31"),
+            Ok(("", Token::FloatRegister { reg_num: 31 }))
+        );
+        assert!(register("$f1").is_err());
     }
 }
@@ -0,0 +1,340 @@
+//! Programs spread over several files. `.include "lib/strings.iasm"` is replaced by the contents of that file, which
+//! is looked for next to the file including it first and then in each of the include paths in turn. The combined text
+//! is what gets assembled, and spans in it can be traced back to the file and place they come from.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::assembler::comment_parsers::ends_in_block_comment;
+use crate::assembler::span::Span;
+use crate::assembler::AssemblerError;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct SourceFile {
+    /// The path the file was read from, as it is shown in diagnostics
+    pub name: String,
+    pub text: String,
+}
+
+/// A part of the combined text copied from `file`, starting at `origin` in it
+#[derive(Debug, PartialEq, Clone)]
+struct Segment {
+    start: usize,
+    file: usize,
+    origin: usize,
+    len: usize,
+}
+
+/// A source file with its `.include`s put in place
+#[derive(Debug, PartialEq, Clone)]
+pub struct Sources {
+    /// The combined text of all the files
+    pub text: String,
+    files: Vec<SourceFile>,
+    segments: Vec<Segment>,
+}
+
+impl Sources {
+    /// A single source with no includes, such as one typed into the REPL
+    pub fn from_text(name: &str, text: &str) -> Sources {
+        Sources {
+            text: text.to_string(),
+            files: vec![SourceFile {
+                name: name.to_string(),
+                text: text.to_string(),
+            }],
+            segments: vec![Segment {
+                start: 0,
+                file: 0,
+                origin: 0,
+                len: text.len(),
+            }],
+        }
+    }
+
+    /// Reads the file at `path` and everything it includes. Only failing to read `path` itself is an `io::Error`;
+    /// problems with the files it includes are returned as assembler errors, together with what could be read
+    pub fn load(
+        path: &Path,
+        include_paths: &[PathBuf],
+    ) -> io::Result<(Sources, Vec<AssemblerError>)> {
+        let text = fs::read_to_string(path)?;
+        let mut loader = Loader {
+            include_paths,
+            sources: Sources {
+                text: String::new(),
+                files: vec![],
+                segments: vec![],
+            },
+            stack: vec![],
+            errors: vec![],
+        };
+        loader.add_file(path, text);
+        Ok((loader.sources, loader.errors))
+    }
+
+    /// The file `span` of the combined text is in, and the span in that file. Spans crossing from one file into
+    /// another are cut off at the end of the first
+    pub fn locate(&self, span: Span) -> (&SourceFile, Span) {
+        let index = self
+            .segments
+            .partition_point(|s| s.start <= span.start)
+            .saturating_sub(1);
+        let segment = &self.segments[index];
+        let file = &self.files[segment.file];
+        let offset = (span.start - segment.start).min(segment.len);
+        let start = segment.origin + offset;
+        let end = start
+            + span
+                .end
+                .saturating_sub(span.start)
+                .min(segment.len - offset);
+        (file, Span::new(&file.text, start, end))
+    }
+}
+
+struct Loader<'a> {
+    include_paths: &'a [PathBuf],
+    sources: Sources,
+    /// The files being included, innermost last, to find cycles
+    stack: Vec<PathBuf>,
+    errors: Vec<AssemblerError>,
+}
+
+impl<'a> Loader<'a> {
+    /// Appends `text`, read from `path`, to the combined text, including what it includes in place
+    fn add_file(&mut self, path: &Path, text: String) {
+        let file = self.sources.files.len();
+        self.sources.files.push(SourceFile {
+            name: path.display().to_string(),
+            text: text.clone(),
+        });
+        self.stack
+            .push(fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()));
+
+        let mut copied = 0;
+        let mut offset = 0;
+        let mut in_comment = false;
+        for raw in text.split_inclusive('\n') {
+            let line_offset = offset;
+            offset += raw.len();
+            let line = raw.strip_suffix('\n').unwrap_or(raw);
+            let trimmed = line.trim_start();
+            let commented = in_comment;
+            in_comment = ends_in_block_comment(line, in_comment);
+            if commented || trimmed.split_whitespace().next() != Some(".include") {
+                continue;
+            }
+
+            self.copy(file, &text, copied, line_offset);
+            copied = line_offset + line.len();
+            let directive = copied - trimmed.len();
+            let start = self.sources.text.len();
+            let span = Span::new(&self.sources.text, start, start + trimmed.len());
+            let included = match include_name(&trimmed[".include".len()..]) {
+                Some(name) => self.include(path, name, span),
+                None => Err(AssemblerError::ParseError {
+                    error: "expected a file name in quotes".to_string(),
+                    span,
+                }),
+            };
+            // A `.include` that fails stays in the combined text, so its error points there
+            if let Err(error) = included {
+                self.errors.push(error);
+                self.copy(file, &text, directive, copied);
+            }
+        }
+        self.copy(file, &text, copied, text.len());
+        self.stack.pop();
+    }
+
+    /// Finds the file `name` included from the file at `from`, and adds it to the combined text
+    fn include(&mut self, from: &Path, name: &str, span: Span) -> Result<(), AssemblerError> {
+        let directory = from.parent().unwrap_or_else(|| Path::new(""));
+        let candidates = std::iter::once(directory.join(name))
+            .chain(self.include_paths.iter().map(|path| path.join(name)));
+
+        for path in candidates {
+            match fs::read_to_string(&path) {
+                Ok(contents) => {
+                    let canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+                    if self.stack.contains(&canonical) {
+                        return Err(AssemblerError::IncludeCycle {
+                            path: path.display().to_string(),
+                            span,
+                        });
+                    }
+                    self.add_file(&path, contents);
+                    return Ok(());
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(AssemblerError::CannotReadInclude {
+                        path: path.display().to_string(),
+                        error: e.to_string(),
+                        span,
+                    })
+                }
+            }
+        }
+        Err(AssemblerError::IncludeNotFound {
+            path: name.to_string(),
+            span,
+        })
+    }
+
+    /// Copies `start..end` of `text`, the contents of `file`, to the combined text
+    fn copy(&mut self, file: usize, text: &str, start: usize, end: usize) {
+        if start >= end {
+            return;
+        }
+        self.sources.segments.push(Segment {
+            start: self.sources.text.len(),
+            file,
+            origin: start,
+            len: end - start,
+        });
+        self.sources.text.push_str(&text[start..end]);
+    }
+}
+
+/// The file name in `"name"` or `'name'`, which may be followed by a comment
+fn include_name(operand: &str) -> Option<&str> {
+    let operand = operand.trim_start();
+    let quote = operand.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let rest = &operand[1..];
+    let end = rest.find(quote)?;
+    let after = rest[end + 1..].trim_start();
+    if after.is_empty()
+        || after.starts_with(';')
+        || after.starts_with("#!")
+        || after.starts_with("/*")
+    {
+        Some(&rest[..end]).filter(|name| !name.is_empty())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A temporary directory, removed with everything in it when dropped
+    struct TempDir(PathBuf);
+
+    impl std::ops::Deref for TempDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// A directory of its own for each test, with the given files in it
+    fn directory(test: &str, files: &[(&str, &str)]) -> TempDir {
+        let directory =
+            std::env::temp_dir().join(format!("iridium-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        for (name, text) in files {
+            let path = directory.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, text).unwrap();
+        }
+        TempDir(directory)
+    }
+
+    #[test]
+    fn test_include_relative_and_search_paths() {
+        let root = directory(
+            "include",
+            &[
+                ("src/main.iasm", ".include \"lib/strings.iasm\"\nhlt\n"),
+                (
+                    "src/lib/strings.iasm",
+                    "hello: .asciiz 'Hello'\n.include 'numbers.iasm' ; from -I\n",
+                ),
+                ("shared/numbers.iasm", "one: .integer #1"),
+            ],
+        );
+        let (sources, errors) =
+            Sources::load(&root.join("src/main.iasm"), &[root.join("shared")]).unwrap();
+        assert_eq!(errors, vec![]);
+        assert_eq!(
+            sources.text,
+            "hello: .asciiz 'Hello'\none: .integer #1\n\nhlt\n"
+        );
+
+        let start = sources.text.find("#1").unwrap();
+        let (file, span) = sources.locate(Span::new(&sources.text, start, start + 2));
+        assert!(file.name.ends_with("numbers.iasm"));
+        assert_eq!(span, Span::new(&file.text, 14, 16));
+        let start = sources.text.find("hlt").unwrap();
+        let (file, span) = sources.locate(Span::new(&sources.text, start, start + 3));
+        assert!(file.name.ends_with("main.iasm"));
+        assert_eq!((span.line, span.column), (2, 1));
+    }
+
+    #[test]
+    fn test_include_errors() {
+        let root = directory(
+            "include-errors",
+            &[
+                (
+                    "a.iasm",
+                    ".include 'b.iasm'\n.include 'missing.iasm'\n.include\n",
+                ),
+                ("b.iasm", "  .include \"a.iasm\"\n"),
+            ],
+        );
+        let (sources, errors) = Sources::load(&root.join("a.iasm"), &[]).unwrap();
+        assert_eq!(errors.len(), 3);
+
+        assert!(
+            matches!(&errors[0], AssemblerError::IncludeCycle { path, .. } if path.ends_with("a.iasm"))
+        );
+        let (file, span) = sources.locate(errors[0].span().unwrap());
+        assert!(file.name.ends_with("b.iasm"));
+        assert_eq!(span, Span::new(&file.text, 2, 19));
+
+        assert!(
+            matches!(&errors[1], AssemblerError::IncludeNotFound { path, .. } if path == "missing.iasm")
+        );
+        let (_, span) = sources.locate(errors[1].span().unwrap());
+        assert_eq!((span.line, span.column), (2, 1));
+
+        assert!(matches!(&errors[2], AssemblerError::ParseError { .. }));
+    }
+
+    #[test]
+    fn test_include_in_block_comment() {
+        let root = directory(
+            "include-comment",
+            &[(
+                "main.iasm",
+                "/* not yet\n.include 'missing.iasm'\n*/\nhlt /* nor\n  .include 'missing.iasm' */\n",
+            )],
+        );
+        let (sources, errors) = Sources::load(&root.join("main.iasm"), &[]).unwrap();
+        assert_eq!(errors, vec![]);
+        assert_eq!(
+            sources.text,
+            "/* not yet\n.include 'missing.iasm'\n*/\nhlt /* nor\n  .include 'missing.iasm' */\n"
+        );
+    }
+
+    #[test]
+    fn test_from_text() {
+        let sources = Sources::from_text("repl", "load $0 #1\nhlt");
+        let (file, span) = sources.locate(Span::new(&sources.text, 11, 14));
+        assert_eq!(file.name, "repl");
+        assert_eq!(span, Span::new(&sources.text, 11, 14));
+    }
+}
@@ -0,0 +1,117 @@
+use nom::IResult;
+
+use crate::assembler::comment_parsers::whitespace;
+
+/// A region of the source code. `line` and `column` are where it starts, both counted from 1
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    /// The span from `start` to `end` in `source`. This scans everything before `start`, use a `LineIndex` when
+    /// locating many spans in a long source
+    pub fn new(source: &str, start: usize, end: usize) -> Span {
+        let before = &source[..start];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        Span {
+            start,
+            end,
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+
+    /// The span from the start of `self` to the end of `other`
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            end: other.end,
+            ..self
+        }
+    }
+}
+
+/// Where every line of a source starts, so spans can be given a line and column without rescanning the source
+pub struct LineIndex {
+    line_starts: Vec<usize>,
+}
+
+impl LineIndex {
+    pub fn new(source: &str) -> LineIndex {
+        let mut line_starts = vec![0];
+        line_starts.extend(source.match_indices('\n').map(|(i, _)| i + 1));
+        LineIndex { line_starts }
+    }
+
+    pub fn span(&self, source: &str, start: usize, end: usize) -> Span {
+        let line = self.line_starts.partition_point(|s| *s <= start);
+        let line_start = self.line_starts[line - 1];
+        Span {
+            start,
+            end,
+            line,
+            column: source[line_start..start].chars().count() + 1,
+        }
+    }
+}
+
+/// Skips leading whitespace and comments and runs `parser`, returning its output together with the span of the text it consumed.
+/// The span is relative to `base`, which must be the input of the instruction being parsed. Spans are made relative to
+/// the whole source once the instruction is done, see `AssemblerInstruction::relocate`
+pub fn token<'a, O, F>(
+    base: &'a str,
+    mut parser: F,
+) -> impl FnMut(&'a str) -> IResult<&'a str, (O, Span)>
+where
+    F: FnMut(&'a str) -> IResult<&'a str, O>,
+{
+    move |input: &'a str| {
+        let (input, _) = whitespace(input)?;
+        let (rest, output) = parser(input)?;
+        let span = Span::new(base, base.len() - input.len(), base.len() - rest.len());
+        Ok((rest, (output, span)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nom::character::complete::alpha1;
+
+    #[test]
+    fn test_line_index() {
+        let source = "load $0 #1\n\n  hlt";
+        let index = LineIndex::new(source);
+        assert_eq!(
+            index.span(source, 5, 7),
+            Span {
+                start: 5,
+                end: 7,
+                line: 1,
+                column: 6
+            }
+        );
+        assert_eq!(
+            index.span(source, 14, 17),
+            Span {
+                start: 14,
+                end: 17,
+                line: 3,
+                column: 3
+            }
+        );
+    }
+
+    #[test]
+    fn test_token_span() {
+        let base = "load\n  hlt";
+        let (rest, (word, span)) = token(base, alpha1)(&base[4..]).unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(word, "hlt");
+        assert_eq!(span, Span::new(base, 7, 10));
+        assert_eq!((span.line, span.column), (2, 3));
+    }
+}
@@ -1,12 +1,15 @@
 #[derive(Debug, PartialEq, Clone)]
 pub enum SymbolType {
     Label,
+    /// A named value declared with `.equ` or `.set`
+    Constant,
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Symbol {
     name: String,
-    offset: u32,
+    /// The offset of a label, or the value of a constant
+    value: i64,
     symbol_type: SymbolType,
 }
 
@@ -19,9 +22,33 @@ impl Symbol {
         Symbol {
             name,
             symbol_type,
-            offset,
+            value: offset as i64,
         }
     }
+
+    pub fn constant(name: String, value: i64) -> Symbol {
+        Symbol {
+            name,
+            symbol_type: SymbolType::Constant,
+            value,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn offset(&self) -> u32 {
+        self.value as u32
+    }
+
+    pub fn value(&self) -> i64 {
+        self.value
+    }
+
+    pub fn symbol_type(&self) -> &SymbolType {
+        &self.symbol_type
+    }
 }
 
 impl SymbolTable {
@@ -37,19 +64,32 @@ impl SymbolTable {
         self.symbols.push(s);
     }
 
+    /// The offset of the label `s`
     pub fn symbol_value(&self, s: &str) -> Option<u32> {
         for symbol in &self.symbols {
-            if symbol.name == s {
-                return Some(symbol.offset);
+            if symbol.name == s && symbol.symbol_type == SymbolType::Label {
+                return Some(symbol.offset());
             }
         }
         None
     }
 
+    /// The value of the constant `s`
+    pub fn constant_value(&self, s: &str) -> Option<i64> {
+        self.symbols
+            .iter()
+            .find(|symbol| symbol.name == s && symbol.symbol_type == SymbolType::Constant)
+            .map(|symbol| symbol.value)
+    }
+
     pub fn set_symbol_offset(&mut self, s: &str, offset: u32) -> bool {
+        self.set_symbol_value(s, offset as i64)
+    }
+
+    pub fn set_symbol_value(&mut self, s: &str, value: i64) -> bool {
         for symbol in &mut self.symbols {
             if symbol.name == s {
-                symbol.offset = offset;
+                symbol.value = value;
                 return true;
             }
         }
@@ -0,0 +1,73 @@
+//! Links relocatable objects written by `iridium -c` into an image:
+//!
+//! ```text
+//! iridium-link [-g] [-o image] object...
+//! ```
+//!
+//! `-g` writes the exported symbols into the image, for the debugger and disassembler.
+
+use std::{env, fs, path::PathBuf};
+
+use iridium::assembler::object::Object;
+use iridium::linker::Linker;
+
+fn main() {
+    let mut output = PathBuf::from("a.pie");
+    let mut objects = vec![];
+    let mut emit_symbols = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "-o" {
+            match args.next() {
+                Some(path) => output = PathBuf::from(path),
+                None => usage(),
+            }
+        } else if arg == "-g" {
+            emit_symbols = true;
+        } else {
+            objects.push(arg);
+        }
+    }
+    if objects.is_empty() {
+        usage();
+    }
+
+    let mut linker = Linker::new();
+    linker.emit_symbols = emit_symbols;
+    for name in &objects {
+        let bytes = match fs::read(name) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                eprintln!("There was an error reading {}: {}", name, e);
+                std::process::exit(1);
+            }
+        };
+        match Object::from_bytes(&bytes) {
+            Ok(object) => linker.add_object(name, object),
+            Err(e) => {
+                eprintln!("{} is not a valid object: {:?}", name, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    match linker.link() {
+        Ok(image) => {
+            if let Err(e) = fs::write(&output, image) {
+                eprintln!("There was an error writing {}: {}", output.display(), e);
+                std::process::exit(1);
+            }
+        }
+        Err(errors) => {
+            for error in errors {
+                eprintln!("error: {}", error);
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
+fn usage() {
+    eprintln!("Usage: iridium-link [-g] [-o image] object...");
+    std::process::exit(1);
+}
@@ -0,0 +1,454 @@
+//! Runs a program under control of a debugger: stepping through it, stopping at breakpoints and when watched
+//! registers or heap bytes change, and inspecting or changing the VM in between, through `Debugger::vm`.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::ops::Range;
+
+use crate::assembler::pie::{self, SectionKind};
+use crate::assembler::symbols::SymbolTable;
+use crate::instruction::{Instruction, Opcode, REGISTER_COUNT};
+use crate::vm::{ExitReason, VmError, VM};
+
+/// Something whose changes stop execution
+#[derive(Debug, PartialEq, Clone)]
+pub enum Watchpoint {
+    Register(u8),
+    FloatRegister(u8),
+    /// Bytes of the heap. Bytes past its end count as zero, like `ALOC` makes them when it grows the heap over them
+    Heap(Range<usize>),
+}
+
+/// Why the debugger handed control back
+#[derive(Debug, PartialEq, Clone)]
+pub enum StopReason {
+    /// The next instruction to execute is at a breakpoint
+    Breakpoint { address: usize },
+    /// The last instruction executed changed what `watchpoint` watches
+    Watchpoint { watchpoint: Watchpoint },
+    /// A step or step over is done
+    Stepped,
+    /// The program stopped
+    Exited(ExitReason),
+    /// `continue_for` executed all the instructions it was given
+    BudgetExhausted,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum DebugError {
+    /// A breakpoint was asked for at a symbol the debugger doesn't know
+    UndefinedSymbol { name: String },
+    /// A watchpoint was asked for on a register that doesn't exist
+    RegisterOutOfRange { register: u8 },
+}
+
+impl fmt::Display for DebugError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DebugError::UndefinedSymbol { name } => write!(f, "there is no symbol `{}`", name),
+            DebugError::RegisterOutOfRange { register } => {
+                write!(f, "register {} does not exist", register)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DebugError {}
+
+/// A VM with breakpoints and watchpoints. Execution starts at the entry point of the image the VM holds
+pub struct Debugger {
+    /// The VM being debugged, to read and change its registers, program counter, flags and heap between steps
+    pub vm: VM,
+    symbols: SymbolTable,
+    /// Code offsets to stop at
+    breakpoints: BTreeSet<usize>,
+    /// Every watchpoint with the bytes it watched after the last instruction
+    watchpoints: Vec<(Watchpoint, Vec<u8>)>,
+    /// The breakpoint execution was last reported stopped at, which resuming from there doesn't stop at again
+    resume_from: Option<usize>,
+}
+
+impl Debugger {
+    /// Debugs `vm`, taking symbols from the symbol section of its image if there is one
+    pub fn new(vm: VM) -> Debugger {
+        let symbols = pie::read_pie(&vm.program)
+            .ok()
+            .and_then(|header| header.section(SectionKind::Symbols).map(|s| s.range()))
+            .and_then(|range| pie::read_symbols(&vm.program[range]).ok())
+            .unwrap_or_else(SymbolTable::new);
+        Debugger {
+            vm,
+            symbols,
+            breakpoints: BTreeSet::new(),
+            watchpoints: vec![],
+            resume_from: None,
+        }
+    }
+
+    /// Finds breakpoints by name in `symbols` instead of the symbol section of the image, such as for an image
+    /// assembled without `Assembler::emit_symbols`
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = symbols;
+    }
+
+    /// Stops execution before the instruction at `address`. Returns false if there already was a breakpoint there
+    pub fn set_breakpoint(&mut self, address: usize) -> bool {
+        self.breakpoints.insert(address)
+    }
+
+    /// Returns false if there was no breakpoint at `address`
+    pub fn clear_breakpoint(&mut self, address: usize) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    /// Sets a breakpoint at the label `name`, returning its address
+    pub fn set_breakpoint_at_symbol(&mut self, name: &str) -> Result<usize, DebugError> {
+        let address = self.symbol_address(name)?;
+        self.breakpoints.insert(address);
+        Ok(address)
+    }
+
+    /// Clears the breakpoint at the label `name`, returning false if there was none
+    pub fn clear_breakpoint_at_symbol(&mut self, name: &str) -> Result<bool, DebugError> {
+        let address = self.symbol_address(name)?;
+        Ok(self.breakpoints.remove(&address))
+    }
+
+    /// The addresses of every breakpoint, in order
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Stops execution after any instruction that changes what `watchpoint` watches
+    pub fn watch(&mut self, watchpoint: Watchpoint) -> Result<(), DebugError> {
+        if let Watchpoint::Register(register) | Watchpoint::FloatRegister(register) = watchpoint {
+            if register as usize >= REGISTER_COUNT {
+                return Err(DebugError::RegisterOutOfRange { register });
+            }
+        }
+        if !self.watchpoints.iter().any(|(w, _)| *w == watchpoint) {
+            let watched = self.watched(&watchpoint);
+            self.watchpoints.push((watchpoint, watched));
+        }
+        Ok(())
+    }
+
+    /// Returns false if `watchpoint` was not set
+    pub fn unwatch(&mut self, watchpoint: &Watchpoint) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|(w, _)| w != watchpoint);
+        self.watchpoints.len() != count
+    }
+
+    /// Executes one instruction
+    pub fn step(&mut self) -> Result<StopReason, VmError> {
+        self.execute(Some(1), |_| true)
+    }
+
+    /// Executes one instruction, or a whole call when it is a `CALL`, stopping early at breakpoints and watchpoints
+    /// inside the call
+    pub fn step_over(&mut self) -> Result<StopReason, VmError> {
+        self.vm.start()?;
+        let pc = self.vm.pc();
+        let end = self.vm.code_end().min(self.vm.program.len());
+        match Instruction::decode(self.vm.program.get(pc..end).unwrap_or_default()) {
+            Ok(instruction) if instruction.opcode == Opcode::CALL => {
+                let return_address = pc + instruction.opcode.info().map_or(1, |info| info.width);
+                let depth = self.vm.stack_depth();
+                // Recursive calls come back to the same address deeper in the stack
+                self.execute(None, |vm| {
+                    vm.pc() == return_address && vm.stack_depth() <= depth
+                })
+            }
+            _ => self.step(),
+        }
+    }
+
+    /// Runs until a breakpoint, a watchpoint or the end of the program
+    pub fn continue_execution(&mut self) -> Result<StopReason, VmError> {
+        self.execute(None, |_| false)
+    }
+
+    /// Like `continue_execution`, executing at most `max_instructions` instructions
+    pub fn continue_for(&mut self, max_instructions: u64) -> Result<StopReason, VmError> {
+        self.execute(Some(max_instructions), |_| false)
+    }
+
+    /// Executes instructions until `done` holds after one, or something else stops execution first
+    fn execute(
+        &mut self,
+        max_instructions: Option<u64>,
+        done: impl Fn(&VM) -> bool,
+    ) -> Result<StopReason, VmError> {
+        self.vm.start()?;
+        // A finished or failed program stays that way, rather than running into whatever follows where it stopped
+        if let Some(error) = self.vm.faulted() {
+            return Err(error.clone());
+        }
+        if let Some(reason) = self.vm.finished() {
+            return Ok(StopReason::Exited(reason));
+        }
+        // Changes made while stopped don't trigger watchpoints
+        for index in 0..self.watchpoints.len() {
+            self.watchpoints[index].1 = self.watched(&self.watchpoints[index].0);
+        }
+
+        let resume_from = self.resume_from.take();
+        let mut executed = 0;
+        loop {
+            if max_instructions.is_some_and(|max| executed >= max) {
+                return Ok(StopReason::BudgetExhausted);
+            }
+            // Execution resuming from the breakpoint it was stopped at doesn't stop there again
+            let pc = self.vm.pc();
+            if self.breakpoints.contains(&pc) && !(executed == 0 && resume_from == Some(pc)) {
+                self.resume_from = Some(pc);
+                return Ok(StopReason::Breakpoint { address: pc });
+            }
+            match self.vm.run_once()? {
+                ExitReason::Continue => {}
+                reason => return Ok(StopReason::Exited(reason)),
+            }
+            executed += 1;
+            if let Some(watchpoint) = self.changed_watchpoint() {
+                return Ok(StopReason::Watchpoint { watchpoint });
+            }
+            if done(&self.vm) {
+                return Ok(StopReason::Stepped);
+            }
+        }
+    }
+
+    /// The first watchpoint whose bytes changed since the last instruction. Every watchpoint is brought up to date
+    fn changed_watchpoint(&mut self) -> Option<Watchpoint> {
+        let mut changed = None;
+        for index in 0..self.watchpoints.len() {
+            let watched = self.watched(&self.watchpoints[index].0);
+            if watched != self.watchpoints[index].1 {
+                self.watchpoints[index].1 = watched;
+                changed = changed.or_else(|| Some(self.watchpoints[index].0.clone()));
+            }
+        }
+        changed
+    }
+
+    /// The bytes `watchpoint` watches
+    fn watched(&self, watchpoint: &Watchpoint) -> Vec<u8> {
+        match watchpoint {
+            Watchpoint::Register(register) => {
+                self.vm.registers[*register as usize].to_le_bytes().to_vec()
+            }
+            Watchpoint::FloatRegister(register) => self.vm.float_registers[*register as usize]
+                .to_le_bytes()
+                .to_vec(),
+            Watchpoint::Heap(range) => {
+                let heap = self.vm.heap();
+                range
+                    .clone()
+                    .map(|address| heap.get(address).copied().unwrap_or(0))
+                    .collect()
+            }
+        }
+    }
+
+    fn symbol_address(&self, name: &str) -> Result<usize, DebugError> {
+        self.symbols
+            .symbol_value(name)
+            .map(|address| address as usize)
+            .ok_or_else(|| DebugError::UndefinedSymbol {
+                name: name.to_string(),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    /// A debugger for `source`, assembled with its symbols
+    fn debugger(source: &str) -> Debugger {
+        let mut asm = Assembler::new();
+        asm.emit_symbols = true;
+        let image = asm.assemble(source).unwrap();
+        let mut vm = VM::new();
+        vm.add_bytes(image);
+        Debugger::new(vm)
+    }
+
+    const PROGRAM: &str = r"
+        .data
+        .code
+        load $0 #3
+        loop: call @double
+        dec $0
+        load $2 #0
+        neq $0 $2
+        jeqi @loop
+        hlt
+        double: add $1 $1 $1
+        inc $1
+        ret
+        ";
+
+    #[test]
+    fn test_breakpoints() {
+        let mut debugger = debugger(PROGRAM);
+        let double = debugger.set_breakpoint_at_symbol("double").unwrap();
+        assert_eq!(
+            debugger.set_breakpoint_at_symbol("missing"),
+            Err(DebugError::UndefinedSymbol {
+                name: "missing".to_string()
+            })
+        );
+        for expected in [0, 1, 3] {
+            assert_eq!(
+                debugger.continue_execution(),
+                Ok(StopReason::Breakpoint { address: double })
+            );
+            assert_eq!(debugger.vm.pc(), double);
+            assert_eq!(debugger.vm.registers[1], expected);
+        }
+        assert_eq!(debugger.clear_breakpoint_at_symbol("double"), Ok(true));
+        assert_eq!(
+            debugger.continue_execution(),
+            Ok(StopReason::Exited(ExitReason::Halted))
+        );
+        assert_eq!(debugger.vm.registers[1], 7);
+    }
+
+    #[test]
+    fn test_debug_assembled_program() {
+        let mut debugger = debugger(
+            ".data\n.code\nload $0 #1\nadd $0 $0 $0\nmiddle: inc $0\nhlt\nload $0 #100\nhlt\n",
+        );
+        debugger.set_breakpoint_at_symbol("middle").unwrap();
+        assert!(matches!(
+            debugger.continue_execution(),
+            Ok(StopReason::Breakpoint { .. })
+        ));
+        assert_eq!(debugger.vm.registers[0], 2);
+        assert_eq!(debugger.step(), Ok(StopReason::Stepped));
+        assert_eq!(debugger.vm.registers[0], 3);
+        for _ in 0..2 {
+            assert_eq!(
+                debugger.continue_execution(),
+                Ok(StopReason::Exited(ExitReason::Halted))
+            );
+        }
+        assert_eq!(debugger.step(), Ok(StopReason::Exited(ExitReason::Halted)));
+        assert_eq!(debugger.vm.registers[0], 3);
+    }
+
+    #[test]
+    fn test_breakpoint_where_execution_starts() {
+        let mut debugger = debugger(".data\n.code\nstart: inc $0\nagain: inc $0\nhlt\n");
+        let start = debugger.set_breakpoint_at_symbol("start").unwrap();
+        let again = debugger.set_breakpoint_at_symbol("again").unwrap();
+        assert_eq!(
+            debugger.continue_execution(),
+            Ok(StopReason::Breakpoint { address: start })
+        );
+        assert_eq!(debugger.vm.registers[0], 0);
+        assert_eq!(
+            debugger.continue_execution(),
+            Ok(StopReason::Breakpoint { address: again })
+        );
+        // Moving back onto a breakpoint stops there before anything runs
+        debugger.vm.set_pc(start).unwrap();
+        assert_eq!(
+            debugger.continue_execution(),
+            Ok(StopReason::Breakpoint { address: start })
+        );
+        assert_eq!(debugger.vm.registers[0], 1);
+        assert_eq!(
+            debugger.continue_execution(),
+            Ok(StopReason::Breakpoint { address: again })
+        );
+        assert_eq!(debugger.vm.registers[0], 2);
+    }
+
+    #[test]
+    fn test_step_and_step_over() {
+        let mut debugger = debugger(PROGRAM);
+        assert_eq!(debugger.step(), Ok(StopReason::Stepped));
+        assert_eq!(debugger.vm.registers[0], 3);
+        let call = debugger.vm.pc();
+        // The whole call runs
+        assert_eq!(debugger.step_over(), Ok(StopReason::Stepped));
+        assert_eq!(debugger.vm.pc(), call + 3);
+        assert_eq!(debugger.vm.registers[1], 1);
+        assert_eq!(debugger.vm.stack_depth(), 0);
+        assert_eq!(debugger.step_over(), Ok(StopReason::Stepped));
+        assert_eq!(debugger.vm.registers[0], 2);
+
+        // Stepping into the call instead
+        debugger.vm.set_pc(call).unwrap();
+        assert_eq!(debugger.step(), Ok(StopReason::Stepped));
+        assert_eq!(
+            debugger.vm.pc() as u32,
+            debugger.symbols.symbol_value("double").unwrap()
+        );
+        assert_eq!(debugger.continue_for(2), Ok(StopReason::BudgetExhausted));
+        assert_eq!(debugger.vm.registers[1], 3);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut debugger = debugger(
+            ".data\n.code\nload $0 #8\naloc $0\nload $1 #7\nsb $1 $2 #4\nsw $1 $2 #0\nloadf $f0 #1.5\nhlt\n",
+        );
+        assert_eq!(
+            debugger.watch(Watchpoint::Register(32)),
+            Err(DebugError::RegisterOutOfRange { register: 32 })
+        );
+        debugger.watch(Watchpoint::Heap(4..6)).unwrap();
+        debugger.watch(Watchpoint::FloatRegister(0)).unwrap();
+        // Growing the heap over the range doesn't change it
+        assert_eq!(
+            debugger.continue_execution(),
+            Ok(StopReason::Watchpoint {
+                watchpoint: Watchpoint::Heap(4..6)
+            })
+        );
+        assert_eq!(debugger.vm.heap()[4], 7);
+        // Changes made while stopped don't count
+        debugger.vm.heap_mut()[5] = 1;
+        assert_eq!(
+            debugger.continue_execution(),
+            Ok(StopReason::Watchpoint {
+                watchpoint: Watchpoint::FloatRegister(0)
+            })
+        );
+        assert_eq!(debugger.vm.float_registers[0], 1.5);
+        assert!(debugger.unwatch(&Watchpoint::Heap(4..6)));
+        assert_eq!(
+            debugger.continue_execution(),
+            Ok(StopReason::Exited(ExitReason::Halted))
+        );
+    }
+
+    #[test]
+    fn test_modify_state() {
+        let mut debugger =
+            debugger(".data\n.code\neq $0 $0\nstart: jeqi @end\nload $3 #1\nend: hlt\n");
+        debugger.set_breakpoint_at_symbol("start").unwrap();
+        assert_eq!(
+            debugger.continue_execution(),
+            Ok(StopReason::Breakpoint {
+                address: debugger.symbols.symbol_value("start").unwrap() as usize
+            })
+        );
+        assert!(debugger.vm.equal_flag());
+        debugger.vm.set_equal_flag(false);
+        assert_eq!(
+            debugger.continue_execution(),
+            Ok(StopReason::Exited(ExitReason::Halted))
+        );
+        assert_eq!(debugger.vm.registers[3], 1);
+        assert_eq!(
+            debugger.vm.set_pc(usize::MAX),
+            Err(VmError::ProgramCounterOutOfBounds { pc: usize::MAX })
+        );
+    }
+}
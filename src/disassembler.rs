@@ -0,0 +1,521 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+
+use crate::assembler::pie::{self, PieError, PieHeader, SectionKind};
+use crate::instruction::{Instruction, Opcode, OperandKind, REGISTER_COUNT};
+
+/// A constant in the read-only section
+#[derive(Debug, PartialEq, Clone)]
+pub enum Data {
+    /// A null-terminated string, as declared with `.asciiz`
+    String(String),
+    /// A little endian 32-bit integer, as declared with `.integer`
+    Integer(i32),
+    /// Bytes that can't be split into strings and integers
+    Bytes(Vec<u8>),
+}
+
+/// One decoded position of the code section
+#[derive(Debug, PartialEq, Clone)]
+pub enum Code {
+    Instruction(Instruction),
+    /// A byte that does not start a valid instruction. Decoding carries on at the next byte
+    Invalid(u8),
+}
+
+/// A PIE image taken apart. `Display` gives a listing with the header and offsets, `source` gives assembly
+#[derive(Debug, PartialEq, Clone)]
+pub struct Disassembly {
+    pub header: PieHeader,
+    /// The read-only constants, by offset in the read-only section
+    pub data: Vec<(usize, Data)>,
+    /// The code, by offset in the image
+    pub code: Vec<(usize, Code)>,
+    /// Label names by the read-only offset they stand for
+    data_labels: BTreeMap<usize, String>,
+    /// Label names by the image offset they stand for
+    code_labels: BTreeMap<usize, String>,
+    /// Operands written as a label instead of a number, by position in `code` and operand slot
+    label_operands: HashMap<(usize, usize), String>,
+    /// Positions in `code` of `lui`s written together with the `load` before them, as a `load` of a label
+    folded: HashSet<usize>,
+}
+
+/// Takes a PIE image apart. Labels are recovered from the embedded symbol table if there is one, and from the
+/// targets of calls, jumps, string prints and `load`-`lui` pairs loading addresses that are then jumped to
+pub fn disassemble(image: &[u8]) -> Result<Disassembly, PieError> {
+    let header = pie::read_pie(image)?;
+    let ro = header
+        .section(SectionKind::ReadOnly)
+        .map_or(&[][..], |s| &image[s.range()]);
+    let code_section = header
+        .section(SectionKind::Code)
+        .ok_or(PieError::MissingCodeSection)?;
+    let symbols = match header.section(SectionKind::Symbols) {
+        Some(s) => Some(pie::read_symbols(&image[s.range()])?),
+        None => None,
+    };
+
+    let data = split_data(ro);
+    let code = decode_code(&image[code_section.range()], code_section.offset as usize);
+    let code_start = code_section.offset as usize;
+    let mut disassembly = Disassembly {
+        header,
+        data,
+        code,
+        data_labels: BTreeMap::new(),
+        code_labels: BTreeMap::new(),
+        label_operands: HashMap::new(),
+        folded: HashSet::new(),
+    };
+
+    if let Some(symbols) = symbols {
+        for symbol in &symbols.symbols {
+            let offset = symbol.offset() as usize;
+            if disassembly.is_instruction_start(offset) {
+                disassembly
+                    .code_labels
+                    .insert(offset, symbol.name().to_string());
+            } else if disassembly.is_data_start(offset) {
+                disassembly
+                    .data_labels
+                    .insert(offset, symbol.name().to_string());
+            }
+        }
+    }
+    // An entry point other than the start of the code is written as a directive naming it
+    let entry = disassembly.header.entry_point as usize;
+    if entry != code_start && disassembly.is_instruction_start(entry) {
+        disassembly.code_label(entry);
+    }
+    // Every constant needs a label to be declared with
+    for (offset, _) in &disassembly.data {
+        disassembly
+            .data_labels
+            .entry(*offset)
+            .or_insert_with(|| format!("data{}", offset));
+    }
+    disassembly.find_label_operands();
+
+    Ok(disassembly)
+}
+
+/// Splits the read-only section into strings and integers, preferring strings. Falls back to raw bytes when the
+/// section can't be split that way
+fn split_data(ro: &[u8]) -> Vec<(usize, Data)> {
+    // `next[i]` is where the constant starting at `i` ends, if the rest of the section can be split from `i` on
+    let mut next: Vec<Option<usize>> = vec![None; ro.len() + 1];
+    next[ro.len()] = Some(ro.len());
+    for i in (0..ro.len()).rev() {
+        let text_end = i + ro[i..].iter().take_while(|b| is_string_byte(**b)).count();
+        if text_end < ro.len() && ro[text_end] == 0 && next[text_end + 1].is_some() {
+            next[i] = Some(text_end + 1);
+        } else if i + 4 <= ro.len() && next[i + 4].is_some() {
+            next[i] = Some(i + 4);
+        }
+    }
+
+    if next[0].is_none() {
+        return vec![(0, Data::Bytes(ro.to_vec()))];
+    }
+    let mut data = vec![];
+    let mut i = 0;
+    while let Some(end) = next[i].filter(|_| i < ro.len()) {
+        let item = if ro[end - 1] == 0 && ro[i..end - 1].iter().all(|b| is_string_byte(*b)) {
+            Data::String(String::from_utf8_lossy(&ro[i..end - 1]).into_owned())
+        } else {
+            Data::Integer(i32::from_le_bytes([ro[i], ro[i + 1], ro[i + 2], ro[i + 3]]))
+        };
+        data.push((i, item));
+        i = end;
+    }
+    data
+}
+
+/// Bytes that can be written between the quotes of a string constant
+fn is_string_byte(byte: u8) -> bool {
+    (b' '..=b'~').contains(&byte) && byte != b'\''
+}
+
+fn decode_code(code: &[u8], start: usize) -> Vec<(usize, Code)> {
+    let mut decoded = vec![];
+    let mut pos = 0;
+    while pos < code.len() {
+        match Instruction::decode(&code[pos..]) {
+            Ok(instruction) => {
+                let width = instruction.opcode.info().map_or(1, |info| info.width);
+                decoded.push((start + pos, Code::Instruction(instruction)));
+                pos += width;
+            }
+            Err(_) => {
+                decoded.push((start + pos, Code::Invalid(code[pos])));
+                pos += 1;
+            }
+        }
+    }
+    decoded
+}
+
+impl Disassembly {
+    fn is_instruction_start(&self, offset: usize) -> bool {
+        self.code
+            .iter()
+            .any(|(o, c)| *o == offset && matches!(c, Code::Instruction(_)))
+    }
+
+    fn is_data_start(&self, offset: usize) -> bool {
+        self.data.iter().any(|(o, _)| *o == offset)
+    }
+
+    fn code_label(&mut self, offset: usize) -> String {
+        self.code_labels
+            .entry(offset)
+            .or_insert_with(|| format!("addr{}", offset))
+            .clone()
+    }
+
+    fn find_label_operands(&mut self) {
+        // The last `load` and `lui` pair into each register, so a jump through the register can label the loaded
+        // address. The assembler always loads labels with both
+        let mut loads: [Option<(usize, u32)>; REGISTER_COUNT] = [None; REGISTER_COUNT];
+        let mut last_load = None;
+        let mut found = vec![];
+        for (index, (_, code)) in self.code.iter().enumerate() {
+            let instruction = match code {
+                Code::Instruction(instruction) => instruction,
+                Code::Invalid(_) => continue,
+            };
+            let [a, b, _] = instruction.operands;
+            let previous = last_load.take();
+            let takes_label = instruction
+                .opcode
+                .info()
+                .is_some_and(|info| info.operands == [OperandKind::Label]);
+            match instruction.opcode {
+                Opcode::LOAD => {
+                    loads[a as usize] = None;
+                    last_load = Some((index, a, b));
+                }
+                Opcode::LUI => {
+                    loads[a as usize] = match previous {
+                        Some((load, register, low)) if load + 1 == index && register == a => {
+                            Some((load, (b as u32) << 16 | low as u32))
+                        }
+                        _ => None,
+                    };
+                }
+                opcode if opcode.direct_form().is_some() => {
+                    if let Some((load, target)) = loads[a as usize] {
+                        if self.is_instruction_start(target as usize) {
+                            found.push(((load, 1), Some(target as usize)));
+                            self.folded.insert(load + 1);
+                        }
+                    }
+                }
+                // Calls and direct jumps
+                _ if takes_label && self.is_instruction_start(a as usize) => {
+                    found.push(((index, 0), Some(a as usize)));
+                }
+                Opcode::PRTS if self.is_data_start(a as usize) => {
+                    found.push(((index, 0), None));
+                }
+                _ => {}
+            }
+        }
+
+        for (operand, code_target) in found {
+            let name = match code_target {
+                Some(target) => self.code_label(target),
+                None => {
+                    let Code::Instruction(instruction) = &self.code[operand.0].1 else {
+                        continue;
+                    };
+                    self.data_labels[&(instruction.operands[0] as usize)].clone()
+                }
+            };
+            self.label_operands.insert(operand, name);
+        }
+    }
+
+    /// Writes the program as assembly. Images written by the assembler re-assemble to identical bytes. Anything
+    /// assembly can't express, like invalid bytes or an entry point other than the start of the code, is written so
+    /// that assembling it fails instead of producing different bytes
+    pub fn source(&self) -> String {
+        let mut out = String::from(".data\n");
+        for (offset, data) in &self.data {
+            out.push_str(&format!("{}: {}\n", self.data_labels[offset], data));
+        }
+        out.push_str(".code\n");
+        if let Some(entry) = self.entry_line() {
+            out.push_str(&entry);
+            out.push('\n');
+        }
+        for index in 0..self.code.len() {
+            if !self.folded.contains(&index) {
+                out.push_str(&self.code_line(index));
+                out.push('\n');
+            }
+        }
+        out
+    }
+
+    /// The `.entry` directive for an image that doesn't start at the top of its code. The assembler has no such
+    /// directive, since what it writes always starts there
+    fn entry_line(&self) -> Option<String> {
+        let entry = self.header.entry_point as usize;
+        let code = self.header.section(SectionKind::Code)?;
+        if entry == code.offset as usize {
+            return None;
+        }
+        Some(match self.code_labels.get(&entry) {
+            Some(name) => format!(".entry @{}", name),
+            None => format!(".entry #{}", entry),
+        })
+    }
+
+    fn code_line(&self, index: usize) -> String {
+        let (offset, code) = &self.code[index];
+        let label = self
+            .code_labels
+            .get(offset)
+            .map_or(String::new(), |name| format!("{}: ", name));
+        let instruction = match code {
+            Code::Instruction(instruction) => instruction,
+            Code::Invalid(byte) => return format!("{}igl #{}", label, byte),
+        };
+        let info = match instruction.opcode.info() {
+            Some(info) => info,
+            None => return format!("{}igl", label),
+        };
+
+        let mut line = format!("{}{}", label, info.mnemonic);
+        for (slot, kind) in info.operands.iter().enumerate() {
+            let value = instruction.operands[slot];
+            let operand = match (kind, self.label_operands.get(&(index, slot))) {
+                (_, Some(name)) => format!("@{}", name),
+                (OperandKind::Register, None) => format!("${}", value),
+                (OperandKind::FloatRegister, None) => format!("$f{}", value),
+                (OperandKind::Float, None) => format!("#{}", value as i16),
+                // `load` sign-extends its immediate, and larger values would assemble to `load` and `lui`
+                (OperandKind::Imm16, None) if instruction.opcode == Opcode::LOAD => {
+                    format!("#{}", value as i16)
+                }
+                (OperandKind::Imm16, None) | (OperandKind::Label, None) => format!("#{}", value),
+            };
+            line.push(' ');
+            line.push_str(&operand);
+        }
+        line
+    }
+}
+
+impl fmt::Display for Data {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Data::String(s) => write!(f, ".asciiz '{}'", s),
+            Data::Integer(value) => write!(f, ".integer #{}", value),
+            Data::Bytes(bytes) => {
+                let bytes: Vec<String> = bytes.iter().map(|b| format!("#{}", b)).collect();
+                write!(f, ".bytes {}", bytes.join(" "))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "PIE version {}, flags {:#010x}, entry point {}",
+            self.header.version, self.header.flags, self.header.entry_point
+        )?;
+        for section in &self.header.sections {
+            writeln!(
+                f,
+                "section {:?}: offset {}, length {}, checksum {:#010x}",
+                section.kind, section.offset, section.length, section.checksum
+            )?;
+        }
+
+        writeln!(f, "\nread-only data:")?;
+        for (offset, data) in &self.data {
+            writeln!(f, "{:>8}  {}: {}", offset, self.data_labels[offset], data)?;
+        }
+        writeln!(f, "\ncode:")?;
+        for index in 0..self.code.len() {
+            if !self.folded.contains(&index) {
+                writeln!(f, "{:>8}  {}", self.code[index].0, self.code_line(index))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{Assembler, AssemblerError};
+    use crate::linker::Linker;
+
+    #[test]
+    fn test_round_trip() {
+        let source = r"
+        .data
+        hello: .asciiz 'Hello'
+        count: .integer #-300
+        .code
+        load $0 #100
+        load $1 #-1
+        load $2 #0x12345678
+        load $3 #40000
+        loadf $f0 #-3
+        loadf $f1 #1.5
+        addf $f0 $f1 $f2
+        prts @hello
+        add $0 $1 $2
+        sw $1 $2 #-4
+        eq $0 $1
+        nop
+        ret
+        hlt
+        ";
+        let image = Assembler::new().assemble(source).unwrap();
+        let disassembly = disassemble(&image).unwrap();
+        assert_eq!(
+            disassembly.data,
+            vec![
+                (0, Data::String("Hello".to_string())),
+                (6, Data::Integer(-300))
+            ]
+        );
+        let reassembled = Assembler::new().assemble(&disassembly.source()).unwrap();
+        assert_eq!(reassembled, image);
+    }
+
+    #[test]
+    fn test_labels_from_jump_targets() {
+        let code = [0, 3, 0, 91, 31, 3, 0, 0, 15, 3, 22, 5, 21, 0, 91];
+        let image = pie::write_pie(0, 0, &[(SectionKind::Code, &code)]);
+        let source = disassemble(&image).unwrap().source();
+        let lines: Vec<&str> = source.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                ".data",
+                ".code",
+                "load $3 @addr91",
+                "jeq $3",
+                "ret",
+                "addr91: hlt",
+                "call @addr91"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_labels_from_direct_jumps() {
+        let source =
+            ".data\n.code\nstart: load $0 #3\nloop: dec $0\neq $0 $1\njneq @loop\njmp @start\n";
+        let image = Assembler::new().assemble(source).unwrap();
+        let source = disassemble(&image).unwrap().source();
+        let lines: Vec<&str> = source.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                ".data",
+                ".code",
+                "addr96: load $0 #3",
+                "addr100: dec $0",
+                "eq $0 $1",
+                "jneqi @addr100",
+                "jmpi @addr96"
+            ]
+        );
+        let reassembled = Assembler::new().assemble(&source).unwrap();
+        assert_eq!(reassembled, image);
+    }
+
+    #[test]
+    fn test_labels_from_symbol_table() {
+        let source = ".data\ngreeting: .asciiz 'Hi'\n.code\nstart: load $0 #1\ndone: hlt\n";
+        let mut asm = Assembler::new();
+        asm.emit_symbols = true;
+        let image = asm.assemble(source).unwrap();
+        let disassembly = disassemble(&image).unwrap();
+        assert_eq!(disassembly.source(), source);
+
+        let mut asm = Assembler::new();
+        asm.emit_symbols = true;
+        assert_eq!(asm.assemble(&disassembly.source()).unwrap(), image);
+    }
+
+    #[test]
+    fn test_entry_point_not_at_start() {
+        let library = Assembler::new()
+            .assemble_object(".data\n.code\n.global helper\nhelper: ret\n")
+            .unwrap();
+        let main = Assembler::new()
+            .assemble_object(".data\n.code\n.global main\nmain: load $0 #7\nhlt\n")
+            .unwrap();
+        let mut linker = Linker::new();
+        linker.emit_symbols = true;
+        linker.add_object("library.o", library);
+        linker.add_object("main.o", main);
+        let image = linker.link().unwrap();
+
+        let source = disassemble(&image).unwrap().source();
+        let lines: Vec<&str> = source.lines().collect();
+        assert_eq!(
+            lines,
+            vec![".data", ".code", ".entry @main", "helper: ret", "main: load $0 #7", "hlt"]
+        );
+        assert!(matches!(
+            Assembler::new().assemble(&source).unwrap_err()[..],
+            [AssemblerError::UnknownDirectiveFound { .. }]
+        ));
+
+        // Without symbols the entry point still gets a label
+        let library = Assembler::new()
+            .assemble_object(".data\n.code\n.global helper\nhelper: ret\n")
+            .unwrap();
+        let main = Assembler::new()
+            .assemble_object(".data\n.code\n.global main\nmain: hlt\n")
+            .unwrap();
+        let mut linker = Linker::new();
+        linker.add_object("library.o", library);
+        linker.add_object("main.o", main);
+        let image = linker.link().unwrap();
+        let source = disassemble(&image).unwrap().source();
+        assert_eq!(source, ".data\n.code\n.entry @addr97\nret\naddr97: hlt\n");
+    }
+
+    #[test]
+    fn test_split_data() {
+        // An integer whose bytes start like a string has to be kept whole for the rest to split
+        let ro = [b'A', 0, 0x80, 1];
+        assert_eq!(split_data(&ro), vec![(0, Data::Integer(0x0180_0041))]);
+        assert_eq!(split_data(&[1, 2]), vec![(0, Data::Bytes(vec![1, 2]))]);
+    }
+
+    #[test]
+    fn test_invalid_bytes_do_not_reassemble() {
+        let image = pie::write_pie(0, 0, &[(SectionKind::Code, &[200, 5])]);
+        let disassembly = disassemble(&image).unwrap();
+        assert_eq!(
+            disassembly.code,
+            vec![
+                (80, Code::Invalid(200)),
+                (81, Code::Instruction(Instruction::new(Opcode::HLT)))
+            ]
+        );
+        assert!(Assembler::new().assemble(&disassembly.source()).is_err());
+    }
+
+    #[test]
+    fn test_listing() {
+        let image = pie::write_pie(0, 0, &[(SectionKind::Code, &[5])]);
+        let listing = disassemble(&image).unwrap().to_string();
+        assert!(listing.starts_with("PIE version 1, flags 0x00000000, entry point 80\n"));
+        assert!(listing.ends_with("code:\n      80  hlt\n"));
+    }
+}
@@ -1,64 +1,289 @@
+use std::fmt;
+
+/// The number of general purpose registers in the VM
+pub const REGISTER_COUNT: usize = 32;
+
+/// What an opcode expects in each of its operand slots
 #[derive(Debug, PartialEq, Clone, Copy)]
-pub enum Opcode {
-    LOAD,
-    ADD,
-    SUB,
-    MUL,
-    DIV,
-    HLT,
-    JMP,  // absolute
-    JMPF, // forward
-    JMPB, // backward
-    EQ,
-    NEQ,
-    GT,
-    LT,
-    GTE,
-    LTE,
-    JEQ,
-    NOP,
-    ALOC,
-    INC,
-    DEC,
-    PRTS,
-    IGL,
+pub enum OperandKind {
+    /// A register number, one byte
+    Register,
+    /// A 16-bit value, two bytes. Written as an integer (`#500`) or a label (`@name`) whose offset is used
+    Imm16,
+    /// The 16-bit offset of a label (`@name`), two bytes
+    Label,
+    /// A float register number (`$f0`), one byte
+    FloatRegister,
+    /// A number (`#1.5`) loaded into a float register, two bytes holding it as a 16-bit integer. The assembler uses
+    /// other instructions for numbers that don't fit
+    Float,
+}
+
+impl OperandKind {
+    /// How many bytes the operand takes in bytecode
+    pub fn width(self) -> usize {
+        match self {
+            OperandKind::Register | OperandKind::FloatRegister => 1,
+            OperandKind::Imm16 | OperandKind::Label | OperandKind::Float => 2,
+        }
+    }
 }
 
+impl fmt::Display for OperandKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OperandKind::Register => write!(f, "a register (`$0`)"),
+            OperandKind::Imm16 => write!(f, "an integer (`#1`) or a label (`@name`)"),
+            OperandKind::Label => write!(f, "a label (`@name`)"),
+            OperandKind::FloatRegister => write!(f, "a float register (`$f0`)"),
+            OperandKind::Float => write!(f, "a number (`#1.5`)"),
+        }
+    }
+}
+
+/// Everything the assembler and the VM need to know about an opcode
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct OpcodeInfo {
+    pub opcode: Opcode,
+    pub mnemonic: &'static str,
+    pub value: u8,
+    pub operands: &'static [OperandKind],
+    /// The number of bytes the encoded instruction takes. Anything after the opcode and its operands is zero padding
+    pub width: usize,
+}
+
+impl OpcodeInfo {
+    /// Where the operand in `slot` starts in the encoded instruction
+    pub fn operand_offset(&self, slot: usize) -> usize {
+        1 + self.operands[..slot]
+            .iter()
+            .map(|kind| kind.width())
+            .sum::<usize>()
+    }
+}
+
+/// Declares `Opcode` together with the `OPCODES` table describing it, so adding an opcode is a one line change here
+/// plus its behaviour in the VM
+macro_rules! opcodes {
+    ($($name:ident = $value:literal, $mnemonic:literal, [$($operand:ident),*], $width:literal;)*) => {
+        #[derive(Debug, PartialEq, Clone, Copy)]
+        pub enum Opcode {
+            $($name = $value,)*
+            /// Any byte or mnemonic that is not one of the opcodes above
+            IGL = 255,
+        }
+
+        /// Every opcode, ordered by value
+        pub const OPCODES: &[OpcodeInfo] = &[
+            $(OpcodeInfo {
+                opcode: Opcode::$name,
+                mnemonic: $mnemonic,
+                value: $value,
+                operands: &[$(OperandKind::$operand),*],
+                width: $width,
+            },)*
+        ];
+    };
+}
+
+opcodes! {
+    LOAD = 0, "load", [Register, Imm16], 4; // sign-extended
+    ADD = 1, "add", [Register, Register, Register], 4;
+    SUB = 2, "sub", [Register, Register, Register], 4;
+    MUL = 3, "mul", [Register, Register, Register], 4;
+    DIV = 4, "div", [Register, Register, Register], 4;
+    HLT = 5, "hlt", [], 1;
+    JMP = 6, "jmp", [Register], 2; // absolute
+    JMPF = 7, "jmpf", [Register], 2; // forward
+    JMPB = 8, "jmpb", [Register], 2; // backward
+    EQ = 9, "eq", [Register, Register], 4;
+    NEQ = 10, "neq", [Register, Register], 4;
+    GT = 11, "gt", [Register, Register], 4;
+    LT = 12, "lt", [Register, Register], 4;
+    GTE = 13, "gte", [Register, Register], 4;
+    LTE = 14, "lte", [Register, Register], 4;
+    JEQ = 15, "jeq", [Register], 2;
+    NOP = 16, "nop", [], 4;
+    ALOC = 17, "aloc", [Register], 2;
+    INC = 18, "inc", [Register], 2;
+    DEC = 19, "dec", [Register], 2;
+    PRTS = 20, "prts", [Imm16], 3;
+    CALL = 21, "call", [Label], 3;
+    RET = 22, "ret", [], 1;
+    PUSH = 23, "push", [Register], 2;
+    POP = 24, "pop", [Register], 2;
+    // heap loads and stores: register, base register, signed 16-bit offset
+    LB = 25, "lb", [Register, Register, Imm16], 5;
+    LH = 26, "lh", [Register, Register, Imm16], 5;
+    LW = 27, "lw", [Register, Register, Imm16], 5;
+    SB = 28, "sb", [Register, Register, Imm16], 5;
+    SH = 29, "sh", [Register, Register, Imm16], 5;
+    SW = 30, "sw", [Register, Register, Imm16], 5;
+    // replaces the upper 16 bits of the register, keeping the lower 16. `load` then `lui` loads any 32-bit value
+    LUI = 31, "lui", [Register, Imm16], 4;
+    AND = 32, "and", [Register, Register, Register], 4;
+    OR = 33, "or", [Register, Register, Register], 4;
+    XOR = 34, "xor", [Register, Register, Register], 4;
+    NOT = 35, "not", [Register, Register], 4;
+    // shifts by the low 5 bits of the second register: logical left, logical right, arithmetic right
+    SHL = 36, "shl", [Register, Register, Register], 4;
+    SHR = 37, "shr", [Register, Register, Register], 4;
+    SAR = 38, "sar", [Register, Register, Register], 4;
+    REM = 39, "rem", [Register, Register, Register], 4; // sign of the dividend, like `div` leaves
+    MOD = 40, "mod", [Register, Register, Register], 4; // never negative
+    NEG = 41, "neg", [Register, Register], 4;
+    MOVR = 42, "movr", [Register], 2; // the remainder of the last `div`
+    // `jeq` jumps when the flag is set and `jneq` when it is not. The others jump on how the two registers of the
+    // last comparison compared, whichever comparison it was
+    JNEQ = 43, "jneq", [Register], 2;
+    JGT = 44, "jgt", [Register], 2;
+    JLT = 45, "jlt", [Register], 2;
+    JGE = 46, "jge", [Register], 2;
+    JLE = 47, "jle", [Register], 2;
+    // the jumps above, to a label given directly instead of through a register
+    JMPI = 48, "jmpi", [Label], 3;
+    JEQI = 49, "jeqi", [Label], 3;
+    JNEQI = 50, "jneqi", [Label], 3;
+    JGTI = 51, "jgti", [Label], 3;
+    JLTI = 52, "jlti", [Label], 3;
+    JGEI = 53, "jgei", [Label], 3;
+    JLEI = 54, "jlei", [Label], 3;
+    // jump when the last arithmetic instruction overflowed, or did not
+    JOV = 55, "jov", [Register], 2;
+    JNOV = 56, "jnov", [Register], 2;
+    JOVI = 57, "jovi", [Label], 3;
+    JNOVI = 58, "jnovi", [Label], 3;
+    // floats: `loadf` loads a sign-extended 16-bit integer, `fset` replaces 16 bits of the register's bit pattern,
+    // counting from the lowest, and `ldrf` loads the little endian f64 at a read-only offset
+    LOADF = 59, "loadf", [FloatRegister, Float], 4;
+    FSET = 60, "fset", [FloatRegister, Imm16, Imm16], 6;
+    LDRF = 61, "ldrf", [FloatRegister, Imm16], 4;
+    ADDF = 62, "addf", [FloatRegister, FloatRegister, FloatRegister], 4;
+    SUBF = 63, "subf", [FloatRegister, FloatRegister, FloatRegister], 4;
+    MULF = 64, "mulf", [FloatRegister, FloatRegister, FloatRegister], 4;
+    DIVF = 65, "divf", [FloatRegister, FloatRegister, FloatRegister], 4;
+    EQF = 66, "eqf", [FloatRegister, FloatRegister], 4;
+    NEQF = 67, "neqf", [FloatRegister, FloatRegister], 4;
+    GTF = 68, "gtf", [FloatRegister, FloatRegister], 4;
+    LTF = 69, "ltf", [FloatRegister, FloatRegister], 4;
+    GTEF = 70, "gtef", [FloatRegister, FloatRegister], 4;
+    LTEF = 71, "ltef", [FloatRegister, FloatRegister], 4;
+    ITOF = 72, "itof", [Register, FloatRegister], 4;
+    FTOI = 73, "ftoi", [FloatRegister, Register], 4; // rounds toward zero, saturating, NaN is 0
+}
+
+impl Opcode {
+    /// The table entry for this opcode, `None` for `IGL`
+    pub fn info(self) -> Option<&'static OpcodeInfo> {
+        OPCODES
+            .get(self as usize)
+            .filter(|info| info.opcode == self)
+    }
+
+    /// The form of a jump through a register that takes a label instead, which the assembler uses for `jeq @label`
+    pub fn direct_form(self) -> Option<Opcode> {
+        match self {
+            Opcode::JMP => Some(Opcode::JMPI),
+            Opcode::JEQ => Some(Opcode::JEQI),
+            Opcode::JNEQ => Some(Opcode::JNEQI),
+            Opcode::JGT => Some(Opcode::JGTI),
+            Opcode::JLT => Some(Opcode::JLTI),
+            Opcode::JGE => Some(Opcode::JGEI),
+            Opcode::JLE => Some(Opcode::JLEI),
+            Opcode::JOV => Some(Opcode::JOVI),
+            Opcode::JNOV => Some(Opcode::JNOVI),
+            _ => None,
+        }
+    }
+}
+
+/// An instruction decoded from bytecode
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub struct Instruction {
-    opcode: Opcode,
+    pub opcode: Opcode,
+    /// Operand values in the order given by `OpcodeInfo::operands`, unused slots are zero
+    pub operands: [u16; 3],
+}
+
+/// Why bytecode could not be decoded into an `Instruction`
+#[derive(Debug, PartialEq, Clone)]
+pub enum DecodeError {
+    IllegalOpcode {
+        byte: u8,
+    },
+    /// The bytes end before the instruction does. `needed` is the width of the instruction
+    Truncated {
+        needed: usize,
+    },
+    RegisterOutOfRange {
+        register: u8,
+    },
 }
 
 impl Instruction {
     pub fn new(opcode: Opcode) -> Instruction {
-        Instruction { opcode: opcode }
+        Instruction {
+            opcode,
+            operands: [0; 3],
+        }
+    }
+
+    /// Decodes the instruction at the start of `bytes`. Its width is given by `opcode.info()`
+    pub fn decode(bytes: &[u8]) -> Result<Instruction, DecodeError> {
+        let byte = bytes
+            .first()
+            .copied()
+            .ok_or(DecodeError::Truncated { needed: 1 })?;
+        let info = Opcode::from(byte)
+            .info()
+            .ok_or(DecodeError::IllegalOpcode { byte })?;
+        if bytes.len() < info.width {
+            return Err(DecodeError::Truncated { needed: info.width });
+        }
+
+        let mut instruction = Instruction::new(info.opcode);
+        let mut offset = 1;
+        for (slot, kind) in info.operands.iter().enumerate() {
+            instruction.operands[slot] = match kind {
+                OperandKind::Register | OperandKind::FloatRegister => {
+                    let register = bytes[offset];
+                    if register as usize >= REGISTER_COUNT {
+                        return Err(DecodeError::RegisterOutOfRange { register });
+                    }
+                    register as u16
+                }
+                OperandKind::Imm16 | OperandKind::Label | OperandKind::Float => {
+                    ((bytes[offset] as u16) << 8) | bytes[offset + 1] as u16
+                }
+            };
+            offset += kind.width();
+        }
+        Ok(instruction)
+    }
+
+    /// Encodes the instruction, padded to the width of its opcode. `IGL` encodes as its single byte
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![self.opcode as u8];
+        if let Some(info) = self.opcode.info() {
+            for (kind, value) in info.operands.iter().zip(self.operands) {
+                match kind {
+                    OperandKind::Register | OperandKind::FloatRegister => bytes.push(value as u8),
+                    OperandKind::Imm16 | OperandKind::Label | OperandKind::Float => {
+                        bytes.push((value >> 8) as u8);
+                        bytes.push(value as u8);
+                    }
+                }
+            }
+            bytes.resize(info.width, 0);
+        }
+        bytes
     }
 }
 
 impl From<u8> for Opcode {
     fn from(v: u8) -> Self {
-        match v {
-            0 => Opcode::LOAD,
-            1 => Opcode::ADD,
-            2 => Opcode::SUB,
-            3 => Opcode::MUL,
-            4 => Opcode::DIV,
-            5 => Opcode::HLT,
-            6 => Opcode::JMP,
-            7 => Opcode::JMPF,
-            8 => Opcode::JMPB,
-            9 => Opcode::EQ,
-            10 => Opcode::NEQ,
-            11 => Opcode::GT,
-            12 => Opcode::LT,
-            13 => Opcode::GTE,
-            14 => Opcode::LTE,
-            15 => Opcode::JEQ,
-            16 => Opcode::NOP,
-            17 => Opcode::ALOC,
-            18 => Opcode::INC,
-            19 => Opcode::DEC,
-            20 => Opcode::PRTS,
+        match OPCODES.get(v as usize) {
+            Some(info) if info.value == v => info.opcode,
             _ => Opcode::IGL,
         }
     }
@@ -66,30 +291,10 @@ impl From<u8> for Opcode {
 
 impl<'a> From<&'a str> for Opcode {
     fn from(value: &'a str) -> Self {
-        match value {
-            "load" => Opcode::LOAD,
-            "add" => Opcode::ADD,
-            "sub" => Opcode::SUB,
-            "mul" => Opcode::MUL,
-            "div" => Opcode::DIV,
-            "hlt" => Opcode::HLT,
-            "jmp" => Opcode::JMP,
-            "jmpf" => Opcode::JMPF,
-            "jmpb" => Opcode::JMPB,
-            "jeq" => Opcode::JEQ,
-            "eq" => Opcode::EQ,
-            "neq" => Opcode::NEQ,
-            "gte" => Opcode::GTE,
-            "gt" => Opcode::GT,
-            "lte" => Opcode::LTE,
-            "lt" => Opcode::LT,
-            "nop" => Opcode::NOP,
-            "aloc" => Opcode::ALOC,
-            "inc" => Opcode::INC,
-            "dec" => Opcode::DEC,
-            "prts" => Opcode::PRTS,
-            _ => Opcode::IGL,
-        }
+        OPCODES
+            .iter()
+            .find(|info| info.mnemonic == value)
+            .map_or(Opcode::IGL, |info| info.opcode)
     }
 }
 
@@ -116,4 +321,65 @@ mod tests {
         let opcode = Opcode::from("illegal");
         assert_eq!(opcode, Opcode::IGL);
     }
+
+    #[test]
+    fn test_opcode_table_is_consistent() {
+        for (index, info) in OPCODES.iter().enumerate() {
+            assert_eq!(info.value as usize, index);
+            assert_eq!(info.opcode as u8, info.value);
+            assert_eq!(Opcode::from(info.value), info.opcode);
+            assert_eq!(Opcode::from(info.mnemonic), info.opcode);
+            assert_eq!(info.opcode.info(), Some(info));
+            let operand_width: usize = info.operands.iter().map(|kind| kind.width()).sum();
+            assert!(info.width > operand_width);
+            assert!(info.operands.len() <= 3);
+        }
+        assert_eq!(Opcode::IGL.info(), None);
+        assert_eq!(Opcode::from(200), Opcode::IGL);
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let instruction = Instruction {
+            opcode: Opcode::LOAD,
+            operands: [3, 500, 0],
+        };
+        let bytes = instruction.encode();
+        assert_eq!(bytes, vec![0, 3, 1, 244]);
+        assert_eq!(Instruction::decode(&bytes), Ok(instruction));
+
+        assert_eq!(Opcode::LB.info().unwrap().operand_offset(2), 3);
+
+        // Comparisons are padded to four bytes
+        let eq = Instruction {
+            opcode: Opcode::EQ,
+            operands: [1, 2, 0],
+        };
+        assert_eq!(eq.encode(), vec![9, 1, 2, 0]);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(
+            Instruction::decode(&[200]),
+            Err(DecodeError::IllegalOpcode { byte: 200 })
+        );
+        assert_eq!(
+            Instruction::decode(&[0, 1, 2]),
+            Err(DecodeError::Truncated { needed: 4 })
+        );
+        assert_eq!(
+            Instruction::decode(&[18, 32]),
+            Err(DecodeError::RegisterOutOfRange { register: 32 })
+        );
+    }
+
+    #[test]
+    fn test_call_stack_opcodes_roundtrip() {
+        for opcode in [Opcode::CALL, Opcode::RET, Opcode::PUSH, Opcode::POP] {
+            assert_eq!(Opcode::from(opcode as u8), opcode);
+        }
+        assert_eq!(Opcode::from("call"), Opcode::CALL);
+        assert_eq!(Opcode::from("ret"), Opcode::RET);
+    }
 }
@@ -2,3 +2,6 @@ pub mod repl;
 pub mod vm;
 pub mod instruction;
 pub mod assembler;
+pub mod debugger;
+pub mod disassembler;
+pub mod linker;
@@ -0,0 +1,399 @@
+//! Links relocatable objects into a PIE image the VM can run.
+//!
+//! The read-only sections of the objects are put one after another in a single read-only section, and their code
+//! sections likewise in a single code section, in the order the objects were added. Every export then has its final
+//! address: an offset in the read-only section for data, or an offset from the start of the image for code, the same
+//! as labels get when a program is assembled straight into an image. The relocations of each object are patched in
+//! with these addresses. Execution starts at the `main` export if there is one, or else at the start of the code.
+//! With `emit_symbols` set, the code exports are also written into a symbols section at the end of the image.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+
+use crate::assembler::object::{Object, RelocationKind, RelocationTarget};
+use crate::assembler::pie::{self, SectionKind};
+use crate::assembler::symbols::{Symbol, SymbolTable, SymbolType};
+
+/// The export execution starts at
+pub const ENTRY_SYMBOL: &str = "main";
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum LinkError {
+    /// Two objects export the same symbol
+    DuplicateSymbol {
+        name: String,
+        first: String,
+        second: String,
+    },
+    /// An object imports a symbol no object exports
+    UndefinedSymbol { name: String, object: String },
+    /// An address does not fit in the operand or integer it is patched into
+    AddressOutOfRange {
+        value: i64,
+        object: String,
+        offset: u32,
+    },
+    /// A relocation or export points outside of its section
+    OutOfSection { object: String, offset: u32 },
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkError::DuplicateSymbol {
+                name,
+                first,
+                second,
+            } => write!(f, "`{}` is exported by both {} and {}", name, first, second),
+            LinkError::UndefinedSymbol { name, object } => {
+                write!(
+                    f,
+                    "`{}` is imported by {} but not exported by any object",
+                    name, object
+                )
+            }
+            LinkError::AddressOutOfRange {
+                value,
+                object,
+                offset,
+            } => write!(
+                f,
+                "the address {} does not fit where it is used in {}, at offset {}",
+                value, object, offset
+            ),
+            LinkError::OutOfSection { object, offset } => {
+                write!(
+                    f,
+                    "{} refers to offset {}, outside of its section",
+                    object, offset
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for LinkError {}
+
+/// Collects objects and links them together
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Linker {
+    objects: Vec<(String, Object)>,
+    /// Whether `link` writes every code export into a symbols section of the image, for the debugger and
+    /// disassembler to find them by name
+    pub emit_symbols: bool,
+}
+
+/// Where the sections of one object end up in the image
+struct Placement {
+    /// The offset of the object's read-only data in the read-only section
+    ro: usize,
+    /// The offset of the object's code in the code section
+    code: usize,
+}
+
+impl Linker {
+    pub fn new() -> Linker {
+        Linker {
+            objects: vec![],
+            emit_symbols: false,
+        }
+    }
+
+    /// Adds an object to link. `name`, such as the file it was read from, is used in errors
+    pub fn add_object(&mut self, name: &str, object: Object) {
+        self.objects.push((name.to_string(), object));
+    }
+
+    /// Links every object added into an image, returning all the errors found if that fails
+    pub fn link(&self) -> Result<Vec<u8>, Vec<LinkError>> {
+        let mut errors = vec![];
+        let mut ro = vec![];
+        let mut code = vec![];
+        let mut placements = vec![];
+        for (_, object) in &self.objects {
+            placements.push(Placement {
+                ro: ro.len(),
+                code: code.len(),
+            });
+            ro.extend_from_slice(&object.ro);
+            code.extend_from_slice(&object.code);
+        }
+        let section_count = if self.emit_symbols { 3 } else { 2 };
+        let code_start = pie::sections_start(section_count) + ro.len();
+        // The address of the start of a section of an object, as labels in that section are given
+        let base = |placement: &Placement, section: SectionKind| match section {
+            SectionKind::Code => (code_start + placement.code) as i64,
+            _ => placement.ro as i64,
+        };
+
+        let mut symbols: HashMap<&str, (usize, i64)> = HashMap::new();
+        for (index, ((name, object), placement)) in self.objects.iter().zip(&placements).enumerate()
+        {
+            for export in &object.exports {
+                // An export can be at the very end of its section, like a label after the last instruction
+                let length = match export.section {
+                    SectionKind::Code => object.code.len(),
+                    _ => object.ro.len(),
+                };
+                if export.offset as usize > length {
+                    errors.push(LinkError::OutOfSection {
+                        object: name.clone(),
+                        offset: export.offset,
+                    });
+                    continue;
+                }
+                if let Some((first, _)) = symbols.get(export.name.as_str()) {
+                    errors.push(LinkError::DuplicateSymbol {
+                        name: export.name.clone(),
+                        first: self.objects[*first].0.clone(),
+                        second: name.clone(),
+                    });
+                    continue;
+                }
+                let address = base(placement, export.section) + export.offset as i64;
+                symbols.insert(&export.name, (index, address));
+            }
+        }
+
+        for ((name, object), placement) in self.objects.iter().zip(&placements) {
+            for import in &object.imports {
+                if !symbols.contains_key(import.as_str()) {
+                    errors.push(LinkError::UndefinedSymbol {
+                        name: import.clone(),
+                        object: name.clone(),
+                    });
+                }
+            }
+
+            for relocation in &object.relocations {
+                let target = match &relocation.target {
+                    RelocationTarget::Section(section) => base(placement, *section),
+                    RelocationTarget::Import(import) => match symbols.get(import.as_str()) {
+                        Some((_, address)) => *address,
+                        // Reported with the imports above
+                        None => continue,
+                    },
+                };
+                let value = target + relocation.addend as i64;
+                let (bytes, start, length) = match relocation.section {
+                    SectionKind::Code => (&mut code, placement.code, object.code.len()),
+                    _ => (&mut ro, placement.ro, object.ro.len()),
+                };
+                let (width, range) = match relocation.kind {
+                    RelocationKind::Imm16 => (2, i16::MIN as i64..=u16::MAX as i64),
+                    RelocationKind::Word32 => (4, i32::MIN as i64..=u32::MAX as i64),
+                    // The two halves of an address loaded with `load` and `lui`
+                    RelocationKind::Low16 | RelocationKind::High16 => {
+                        (2, i32::MIN as i64..=u32::MAX as i64)
+                    }
+                };
+                if relocation.offset as usize + width > length {
+                    errors.push(LinkError::OutOfSection {
+                        object: name.clone(),
+                        offset: relocation.offset,
+                    });
+                    continue;
+                }
+                if !range.contains(&value) {
+                    errors.push(LinkError::AddressOutOfRange {
+                        value,
+                        object: name.clone(),
+                        offset: relocation.offset,
+                    });
+                    continue;
+                }
+                let at = start + relocation.offset as usize;
+                match relocation.kind {
+                    // Instruction operands are stored high byte first
+                    RelocationKind::Imm16 => {
+                        bytes[at] = (value >> 8) as u8;
+                        bytes[at + 1] = value as u8;
+                    }
+                    RelocationKind::Word32 => {
+                        LittleEndian::write_u32(&mut bytes[at..at + 4], value as u32)
+                    }
+                    RelocationKind::Low16 => {
+                        BigEndian::write_u16(&mut bytes[at..at + 2], value as u16)
+                    }
+                    RelocationKind::High16 => {
+                        BigEndian::write_u16(&mut bytes[at..at + 2], (value >> 16) as u16)
+                    }
+                }
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        let entry_point = match symbols.get(ENTRY_SYMBOL) {
+            Some((_, address)) if *address >= code_start as i64 => {
+                (*address - code_start as i64) as u32
+            }
+            _ => 0,
+        };
+        let mut sections = vec![(SectionKind::ReadOnly, &ro[..]), (SectionKind::Code, &code[..])];
+        let exports;
+        if self.emit_symbols {
+            // In the order the objects were added, rather than the order of the map. Only code addresses go in, since
+            // the symbols section has no way to tell a read-only offset from one
+            let mut table = SymbolTable::new();
+            for (_, object) in &self.objects {
+                for export in &object.exports {
+                    if export.section != SectionKind::Code {
+                        continue;
+                    }
+                    let (_, address) = symbols[export.name.as_str()];
+                    table.add_symbol(Symbol::new(
+                        export.name.clone(),
+                        SymbolType::Label,
+                        address as u32,
+                    ));
+                }
+            }
+            exports = pie::write_symbols(&table);
+            sections.push((SectionKind::Symbols, &exports));
+        }
+        Ok(pie::write_pie(0, entry_point, &sections))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::object::Export;
+    use crate::assembler::Assembler;
+    use crate::vm::{ExitReason, VM};
+
+    fn object(source: &str) -> Object {
+        Assembler::new().assemble_object(source).unwrap()
+    }
+
+    #[test]
+    fn test_link_and_run() {
+        let main = object(
+            ".data\n.code\n.global main\n.extern double\nmain: load $0 #21\ncall @double\nhlt\n",
+        );
+        let library = object(
+            ".data\nmessage: .asciiz 'Hi'\n.code\n.global double\ndouble: add $0 $0 $0\nprts @message\nret\n",
+        );
+        assert_eq!(main.imports, vec!["double".to_string()]);
+        assert_eq!(library.exports.len(), 1);
+
+        let mut linker = Linker::new();
+        linker.add_object("main.o", main);
+        linker.add_object("library.o", library);
+        let image = linker.link().unwrap();
+
+        let mut vm = VM::new();
+        vm.add_bytes(image);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[0], 42);
+    }
+
+    #[test]
+    fn test_entry_point_is_main() {
+        let library = object(".data\n.code\n.global helper\nhelper: ret\n");
+        let main = object(".data\n.code\n.global main\nmain: load $0 #7\nhlt\n");
+        let mut linker = Linker::new();
+        linker.add_object("library.o", library);
+        linker.add_object("main.o", main);
+        let image = linker.link().unwrap();
+        let header = pie::read_pie(&image).unwrap();
+        let code = header.section(SectionKind::Code).unwrap();
+        // `ret` is one byte
+        assert_eq!(header.entry_point, code.offset + 1);
+
+        let mut vm = VM::new();
+        vm.add_bytes(image);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[0], 7);
+    }
+
+    #[test]
+    fn test_emit_symbols() {
+        let library = object(".data\n.code\n.global helper\nhelper: ret\n");
+        let main = object(".data\n.code\n.global main\n.extern helper\nmain: call @helper\nhlt\n");
+        let mut linker = Linker::new();
+        linker.emit_symbols = true;
+        linker.add_object("library.o", library);
+        linker.add_object("main.o", main);
+        let image = linker.link().unwrap();
+
+        let header = pie::read_pie(&image).unwrap();
+        let code = header.section(SectionKind::Code).unwrap().offset;
+        let symbols = header.section(SectionKind::Symbols).unwrap();
+        let symbols = pie::read_symbols(&image[symbols.range()]).unwrap();
+        assert_eq!(symbols.symbol_value("helper"), Some(code));
+        assert_eq!(symbols.symbol_value("main"), Some(code + 1));
+        assert_eq!(header.entry_point, code + 1);
+
+        let mut vm = VM::new();
+        vm.add_bytes(image);
+        assert_eq!(vm.run(), Ok(ExitReason::Halted));
+    }
+
+    #[test]
+    fn test_emit_symbols_only_code() {
+        let library =
+            object(".data\ntable: .integer #1\n.code\n.global table\n.global get\nget: ret\n");
+        let mut linker = Linker::new();
+        linker.emit_symbols = true;
+        linker.add_object("library.o", library);
+        let image = linker.link().unwrap();
+        let header = pie::read_pie(&image).unwrap();
+        let symbols = header.section(SectionKind::Symbols).unwrap();
+        let symbols = pie::read_symbols(&image[symbols.range()]).unwrap();
+        assert_eq!(symbols.symbol_value("table"), None);
+        assert!(symbols.symbol_value("get").is_some());
+    }
+
+    #[test]
+    fn test_export_out_of_section() {
+        let mut main = object(".data\n.code\n.global main\nmain: hlt\n");
+        main.exports.push(Export {
+            name: "past".to_string(),
+            section: SectionKind::Code,
+            offset: 2,
+        });
+        main.exports.push(Export {
+            name: "end".to_string(),
+            section: SectionKind::Code,
+            offset: 1,
+        });
+        let mut linker = Linker::new();
+        linker.add_object("main.o", main);
+        assert_eq!(
+            linker.link(),
+            Err(vec![LinkError::OutOfSection {
+                object: "main.o".to_string(),
+                offset: 2,
+            }])
+        );
+    }
+
+    #[test]
+    fn test_duplicate_and_undefined_symbols() {
+        let mut linker = Linker::new();
+        linker.add_object("a.o", object(".data\n.code\n.global f\nf: ret\n"));
+        linker.add_object(
+            "b.o",
+            object(".data\n.code\n.global f\n.extern g\nf: call @g\nret\n"),
+        );
+        assert_eq!(
+            linker.link(),
+            Err(vec![
+                LinkError::DuplicateSymbol {
+                    name: "f".to_string(),
+                    first: "a.o".to_string(),
+                    second: "b.o".to_string(),
+                },
+                LinkError::UndefinedSymbol {
+                    name: "g".to_string(),
+                    object: "b.o".to_string(),
+                },
+            ])
+        );
+    }
+}
@@ -1,58 +1,142 @@
-use std::{env, fs::File, io::Read, path::Path};
+use std::{env, fs, path::PathBuf};
+
+use assembler::sources::Sources;
 
 #[macro_use]
 extern crate nom;
 
 pub mod assembler;
+pub mod debugger;
+pub mod disassembler;
 pub mod instruction;
+pub mod linker;
 pub mod repl;
 pub mod vm;
 
+/// What the command line asks for
+#[derive(Default)]
+struct Options {
+    filename: Option<String>,
+    include_paths: Vec<PathBuf>,
+    /// `-c`: assemble into a relocatable object instead of running the program
+    object: bool,
+    /// `-o file`: where to write the object
+    output: Option<PathBuf>,
+}
+
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let options = parse_args(env::args().skip(1));
 
-    if args.len() > 1 {
-        let filename = args[1].as_str();
-        let program = read_file(filename);
+    if let Some(filename) = &options.filename {
+        // Images, such as the ones `iridium-link` writes, are run as they are
+        if let Ok(image) = fs::read(filename) {
+            if image.starts_with(&assembler::PIE_HEADER_PREFIX) && !options.object {
+                run(image);
+            }
+        }
+        let sources = read_sources(filename, &options.include_paths);
         let mut asm = assembler::Assembler::new();
-        let mut vm = vm::VM::new();
-        let program = asm.assemble(&program);
-        match program {
-            Ok(p) => {
-                vm.add_bytes(p);
-                vm.run();
-                std::process::exit(0);
+        if options.object {
+            let output = options
+                .output
+                .unwrap_or_else(|| PathBuf::from(filename).with_extension("o"));
+            match asm.assemble_object(&sources.text) {
+                Ok(object) => {
+                    if let Err(e) = fs::write(&output, object.to_bytes()) {
+                        eprintln!("There was an error writing {}: {}", output.display(), e);
+                        std::process::exit(1);
+                    }
+                    std::process::exit(0);
+                }
+                Err(errors) => {
+                    eprint!(
+                        "{}",
+                        assembler::diagnostics::render_all_in(&sources, &errors)
+                    );
+                    std::process::exit(1);
+                }
+            }
+        }
+        match asm.assemble(&sources.text) {
+            Ok(p) => run(p),
+            Err(errors) => {
+                eprint!(
+                    "{}",
+                    assembler::diagnostics::render_all_in(&sources, &errors)
+                );
+                std::process::exit(1);
             }
-            Err(_) => {}
         }
     }else {
         start_repl();
     }
 }
 
+/// Runs `image` and exits
+fn run(image: Vec<u8>) -> ! {
+    let mut vm = vm::VM::new();
+    vm.add_bytes(image);
+    if let Err(e) = vm.run() {
+        eprintln!("The VM stopped with an error: {}", e);
+        std::process::exit(1);
+    }
+    std::process::exit(0);
+}
+
 fn start_repl() {
     let mut repl = repl::REPL::new();
     repl.run();
 }
 
-fn read_file(tmp: &str) -> String {
-    let filename = Path::new(tmp);
-    match File::open(Path::new(&filename)) {
-        Ok(mut fh) => {
-            let mut contents = String::new();
-            match fh.read_to_string(&mut contents) {
-                Ok(_) => {
-                    return contents;
-                }
-                Err(e) => {
-                    println!("There was an error reading file: {:?}", e);
-                    std::process::exit(1);
-                }
+/// Reads the file to run, the directories given with `-I dir` or `-Idir` to look for included files in, and `-c` and
+/// `-o file` to write an object instead
+fn parse_args(mut args: impl Iterator<Item = String>) -> Options {
+    let usage = || {
+        eprintln!("Usage: iridium [-I dir]... [-c [-o file]] [file]");
+        std::process::exit(1);
+    };
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        if arg == "-I" {
+            match args.next() {
+                Some(path) => options.include_paths.push(PathBuf::from(path)),
+                None => usage(),
+            }
+        } else if let Some(path) = arg.strip_prefix("-I") {
+            options.include_paths.push(PathBuf::from(path));
+        } else if arg == "-c" {
+            options.object = true;
+        } else if arg == "-o" {
+            match args.next() {
+                Some(path) => options.output = Some(PathBuf::from(path)),
+                None => usage(),
             }
+        } else if options.filename.is_none() {
+            options.filename = Some(arg);
+        } else {
+            usage();
+        }
+    }
+    if options.output.is_some() && !options.object {
+        usage();
+    }
+    options
+}
+
+/// Reads the program in `filename` and the files it includes
+fn read_sources(filename: &str, include_paths: &[PathBuf]) -> Sources {
+    match Sources::load(filename.as_ref(), include_paths) {
+        Ok((sources, errors)) if errors.is_empty() => sources,
+        Ok((sources, errors)) => {
+            eprint!(
+                "{}",
+                assembler::diagnostics::render_all_in(&sources, &errors)
+            );
+            std::process::exit(1);
         }
         Err(e) => {
-            println!("File not found: {:?}", e);
-            std::process::exit(1)
+            eprintln!("There was an error reading {}: {}", filename, e);
+            std::process::exit(1);
         }
     }
 }
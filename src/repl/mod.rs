@@ -1,4 +1,5 @@
-use crate::assembler::program_parsers::program;
+use crate::assembler::program_parsers::parse_program;
+use crate::assembler::diagnostics::{render, render_all};
 use crate::assembler::symbols::SymbolTable;
 use crate::vm::VM;
 use std::fs::File;
@@ -79,31 +80,52 @@ impl REPL {
                     let mut contents = String::new();
                     f.read_to_string(&mut contents)
                         .expect("There was an error reading from the file");
-                    let program = match program(&contents) {
-                        // Rusts pattern matching is pretty powerful an can even be nested
-                        Ok((remainder, program)) => program,
+                    let program = match parse_program(&contents) {
+                        Ok(program) => program,
                         Err(e) => {
-                            println!("Unable to parse input: {:?}", e);
+                            print!("{}", render(&contents, &e));
                             continue;
                         }
                     };
+                    let errors = program.check_operands();
+                    if !errors.is_empty() {
+                        print!("{}", render_all(&contents, &errors));
+                        continue;
+                    }
                     let symbols = SymbolTable::new();
-                    self.vm.program.append(&mut program.to_bytes(&symbols));
+                    match program.to_bytes(&symbols) {
+                        Ok(mut bytes) => self.vm.program.append(&mut bytes),
+                        Err(errors) => print!("{}", render_all(&contents, &errors)),
+                    }
                 }
                 _ => {
-                    let parsed_program = program(buffer);
-                    if !parsed_program.is_ok() {
-                        println!("Unable to parse input");
+                    let result = match parse_program(buffer) {
+                        Ok(program) => program,
+                        Err(e) => {
+                            print!("{}", render(buffer, &e));
+                            continue;
+                        }
+                    };
+                    let errors = result.check_operands();
+                    if !errors.is_empty() {
+                        print!("{}", render_all(buffer, &errors));
                         continue;
                     }
-                    let (_, result) = parsed_program.unwrap();
                     let symbols = SymbolTable::new();
-                    let bytecode = result.to_bytes(&symbols);
+                    let bytecode = match result.to_bytes(&symbols) {
+                        Ok(bytecode) => bytecode,
+                        Err(errors) => {
+                            print!("{}", render_all(buffer, &errors));
+                            continue;
+                        }
+                    };
 
                     for byte in bytecode {
                         self.vm.add_byte(byte);
                     }
-                    self.vm.run_once();
+                    if let Err(e) = self.vm.run_once() {
+                        println!("The VM stopped with an error: {}", e);
+                    }
                 }
             }
         }
@@ -1,49 +1,348 @@
-use crate::assembler::{PIE_HEADER_LENGTH, PIE_HEADER_PREFIX};
-use crate::instruction::Opcode;
+use std::cmp::Ordering;
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::time::Instant;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::assembler::pie::{self, PieError, SectionKind, PIE_FLAG_OBJECT, PIE_VERSION};
+use crate::instruction::{DecodeError, Instruction, Opcode, REGISTER_COUNT};
+
+/// The largest size, in bytes, `ALOC` is allowed to grow the heap to
+pub const MAX_HEAP_SIZE: usize = 64 * 1024 * 1024;
+/// The largest number of values the call stack can hold, return addresses and saved frame pointers included
+pub const MAX_STACK_SIZE: usize = 64 * 1024;
+/// How many instructions `run_for_until` executes between looks at the clock
+pub const DEADLINE_CHECK_INTERVAL: u64 = 1024;
+
+/// Why the VM stopped executing when no error occurred
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ExitReason {
+    /// A `HLT` instruction was executed
+    Halted,
+    /// The program counter reached the end of the program
+    EndOfProgram,
+    /// A single instruction was executed and the VM can keep going. Only returned by `run_once`
+    Continue,
+}
+
+/// How a call to `run_for` or `run_for_until` ended when no error occurred
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RunOutcome {
+    /// The program stopped, for the reason given
+    Completed(ExitReason),
+    /// The instructions or the time given ran out first. Calling again carries on from where the VM stopped
+    BudgetExhausted,
+}
+
+/// What `ADD`, `SUB`, `MUL`, `DIV`, `INC`, `DEC` and `NEG` do when the result does not fit in 32 bits. Whatever the
+/// mode, each of these instructions sets the overflow flag to whether it overflowed, for `JOV` and `JNOV` to test
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum ArithmeticMode {
+    /// The result wraps around in two's complement, so `i32::MAX + 1` is `i32::MIN`
+    #[default]
+    Wrapping,
+    /// The VM stops with `VmError::ArithmeticOverflow`
+    Checked,
+    /// The result is clamped to `i32::MIN` or `i32::MAX`
+    Saturating,
+}
+
+/// Errors that stop the VM. `pc` is the offset of the instruction that caused them unless noted otherwise
+#[derive(Debug, PartialEq, Clone)]
+pub enum VmError {
+    /// The program is not a well-formed PIE image
+    BadHeader {
+        error: PieError,
+    },
+    /// The image was written in a version of the PIE format this VM cannot run
+    UnsupportedVersion {
+        version: u16,
+    },
+    IllegalOpcode {
+        pc: usize,
+        byte: u8,
+    },
+    RegisterOutOfRange {
+        pc: usize,
+        register: u8,
+    },
+    /// `pc` is the offset the VM tried to read from or jump to
+    ProgramCounterOutOfBounds {
+        pc: usize,
+    },
+    DivisionByZero {
+        pc: usize,
+    },
+    HeapOverflow {
+        pc: usize,
+        requested: i32,
+    },
+    ReadOnlyDataOutOfBounds {
+        pc: usize,
+        offset: usize,
+    },
+    /// A heap load or store touches bytes outside of the heap. `address` is the first byte it tried to access
+    HeapOutOfBounds {
+        pc: usize,
+        address: i64,
+        size: usize,
+    },
+    /// A `CALL` or `PUSH` would grow the stack past `MAX_STACK_SIZE`
+    StackOverflow {
+        pc: usize,
+    },
+    /// A `POP` past the start of the current frame, or a `RET` outside of any call
+    StackUnderflow {
+        pc: usize,
+    },
+    /// An arithmetic instruction overflowed in `ArithmeticMode::Checked`
+    ArithmeticOverflow {
+        pc: usize,
+    },
+    /// `PRTS` could not write to the output
+    Output {
+        pc: usize,
+        kind: io::ErrorKind,
+    },
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::BadHeader { error } => {
+                write!(f, "the program is not a valid PIE image: {:?}", error)
+            }
+            VmError::UnsupportedVersion { version } => write!(
+                f,
+                "PIE format version {} is not supported, expected {}",
+                version, PIE_VERSION
+            ),
+            VmError::IllegalOpcode { pc, byte } => {
+                write!(f, "illegal opcode {} at offset {}", byte, pc)
+            }
+            VmError::RegisterOutOfRange { pc, register } => {
+                write!(
+                    f,
+                    "register ${} does not exist (at offset {})",
+                    register, pc
+                )
+            }
+            VmError::ProgramCounterOutOfBounds { pc } => {
+                write!(f, "program counter {} is outside of the program", pc)
+            }
+            VmError::DivisionByZero { pc } => write!(f, "division by zero at offset {}", pc),
+            VmError::HeapOverflow { pc, requested } => write!(
+                f,
+                "allocating {} bytes at offset {} would overflow the heap",
+                requested, pc
+            ),
+            VmError::HeapOutOfBounds { pc, address, size } => write!(
+                f,
+                "accessing {} bytes at heap address {} is out of bounds (at offset {})",
+                size, address, pc
+            ),
+            VmError::StackOverflow { pc } => write!(f, "stack overflow at offset {}", pc),
+            VmError::StackUnderflow { pc } => write!(f, "stack underflow at offset {}", pc),
+            VmError::ArithmeticOverflow { pc } => {
+                write!(f, "arithmetic overflow at offset {}", pc)
+            }
+            VmError::ReadOnlyDataOutOfBounds { pc, offset } => write!(
+                f,
+                "read-only data offset {} is out of bounds (at offset {})",
+                offset, pc
+            ),
+            VmError::Output { pc, kind } => {
+                write!(f, "writing output failed at offset {}: {}", pc, kind)
+            }
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
 
 pub struct VM {
     // it could know at compile time as list type
-    pub registers: [i32; 32],
+    pub registers: [i32; REGISTER_COUNT],
+    pub float_registers: [f64; REGISTER_COUNT],
     // program counter
     pc: usize,
     pub program: Vec<u8>,
+    // what arithmetic instructions do when they overflow
+    pub arithmetic_mode: ArithmeticMode,
     heap: Vec<u8>,
     remainder: u32,
     // the result of the last comparison operation
     equal_flag: bool,
+    // whether the last arithmetic instruction overflowed
+    overflow_flag: bool,
+    // how the two registers of the last comparison operation compared, for `jgt` and the like. `None` when one of
+    // them was a NaN float
+    comparison: Option<Ordering>,
     ro_data: Vec<u8>,
+    // values pushed by PUSH, plus the return address and saved frame pointer of every CALL
+    stack: Vec<i32>,
+    // index into the stack where the current call frame starts
+    fp: usize,
+    // where the code section of a loaded image starts, so jumps can't run the header or the read-only data
+    code_start: usize,
+    // where the code section of a loaded image ends, the whole program is code when this is not set
+    code_end: Option<usize>,
+    // whether the header has been read and the program counter set to the entry point, so runs can be resumed
+    started: bool,
+    // why the program stopped once it has, so later runs report that again rather than executing what follows
+    finished: Option<ExitReason>,
+    // the error that stopped the program, so later runs return it again rather than carrying on past the fault
+    faulted: Option<VmError>,
+    // where PRTS writes what the program prints
+    output: Box<dyn Write + Send>,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VM {
     pub fn new() -> VM {
         VM {
-            registers: [0; 32],
+            registers: [0; REGISTER_COUNT],
+            float_registers: [0.0; REGISTER_COUNT],
             program: vec![],
+            arithmetic_mode: ArithmeticMode::default(),
             heap: vec![],
             pc: 0,
             remainder: 0,
             equal_flag: false,
+            overflow_flag: false,
+            comparison: Some(Ordering::Equal),
             ro_data: vec![],
+            stack: vec![],
+            fp: 0,
+            code_start: 0,
+            code_end: None,
+            started: false,
+            finished: None,
+            faulted: None,
+            output: Box::new(io::stdout()),
+        }
+    }
+
+    /// Sends what the program prints to `output` instead of standard output
+    pub fn set_output(&mut self, output: impl Write + Send + 'static) {
+        self.output = Box::new(output);
+    }
+
+    /// Decodes the instruction at the program counter and moves the program counter past it
+    fn decode_instruction(&mut self, pc: usize) -> Result<Instruction, VmError> {
+        let code_end = self.code_end();
+        match Instruction::decode(&self.program[pc..code_end]) {
+            Ok(instruction) => {
+                // decode only succeeds if the whole instruction is there, so this cannot go past the code
+                self.pc += instruction.opcode.info().map_or(1, |info| info.width);
+                Ok(instruction)
+            }
+            Err(DecodeError::IllegalOpcode { byte }) => {
+                self.pc += 1;
+                Err(VmError::IllegalOpcode { pc, byte })
+            }
+            Err(DecodeError::Truncated { .. }) => {
+                Err(VmError::ProgramCounterOutOfBounds { pc: code_end })
+            }
+            Err(DecodeError::RegisterOutOfRange { register }) => {
+                Err(VmError::RegisterOutOfRange { pc, register })
+            }
+        }
+    }
+
+    /// The value of the register named by a decoded register operand
+    fn register(&self, operand: u16) -> i32 {
+        self.registers[operand as usize]
+    }
+
+    fn set_register(&mut self, operand: u16, value: i32) {
+        self.registers[operand as usize] = value;
+    }
+
+    fn float_register(&self, operand: u16) -> f64 {
+        self.float_registers[operand as usize]
+    }
+
+    fn set_float_register(&mut self, operand: u16, value: f64) {
+        self.float_registers[operand as usize] = value;
+    }
+
+    /// Sets the flag for the comparison `opcode`, integer or float, given how its two registers compared
+    fn compare(&mut self, opcode: Opcode, ordering: Option<Ordering>) {
+        self.comparison = ordering;
+        let ordering = match ordering {
+            Some(ordering) => ordering,
+            // Only `neq` holds when a NaN is involved
+            None => {
+                self.equal_flag = opcode == Opcode::NEQF;
+                return;
+            }
+        };
+        self.equal_flag = match opcode {
+            Opcode::EQ | Opcode::EQF => ordering.is_eq(),
+            Opcode::NEQ | Opcode::NEQF => ordering.is_ne(),
+            Opcode::GT | Opcode::GTF => ordering.is_gt(),
+            Opcode::LT | Opcode::LTF => ordering.is_lt(),
+            Opcode::GTE | Opcode::GTEF => ordering.is_ge(),
+            _ => ordering.is_le(),
+        };
+    }
+
+    fn push(&mut self, pc: usize, value: i32) -> Result<(), VmError> {
+        if self.stack.len() >= MAX_STACK_SIZE {
+            return Err(VmError::StackOverflow { pc });
         }
+        self.stack.push(value);
+        Ok(())
     }
 
-    fn decode_opcode(&mut self) -> Opcode {
-        let opcode = Opcode::from(self.program[self.pc]);
-        self.pc += 1;
-        return opcode;
+    /// The result of an arithmetic instruction in the arithmetic mode, given the wrapped result with whether it
+    /// overflowed and the saturated result. Sets the overflow flag
+    fn arithmetic(
+        &mut self,
+        pc: usize,
+        (wrapped, overflowed): (i32, bool),
+        saturated: i32,
+    ) -> Result<i32, VmError> {
+        self.overflow_flag = overflowed;
+        match self.arithmetic_mode {
+            ArithmeticMode::Checked if overflowed => Err(VmError::ArithmeticOverflow { pc }),
+            ArithmeticMode::Saturating => Ok(saturated),
+            _ => Ok(wrapped),
+        }
     }
 
-    fn next_8_bits(&mut self) -> u8 {
-        let result = self.program[self.pc];
-        self.pc += 1;
-        return result;
+    /// The range of the heap a `size` byte access at the base register plus the signed offset covers
+    fn heap_range(
+        &self,
+        pc: usize,
+        base: u16,
+        offset: u16,
+        size: usize,
+    ) -> Result<Range<usize>, VmError> {
+        let address = self.register(base) as i64 + offset as i16 as i64;
+        if address < 0 || address as usize + size > self.heap.len() {
+            return Err(VmError::HeapOutOfBounds { pc, address, size });
+        }
+        Ok(address as usize..address as usize + size)
     }
 
-    fn next_16_bits(&mut self) -> u16 {
-        // read off 2 bytes from the stack, move the first byte up 8 bits
-        let result = ((self.program[self.pc] as u16) << 8) | self.program[self.pc + 1] as u16;
-        self.pc += 2;
-        return result;
+    /// Moves the program counter, refusing targets outside of the code
+    fn jump_to(&mut self, target: i64) -> Result<(), VmError> {
+        if target < self.code_start as i64 || target as usize > self.code_end() {
+            return Err(VmError::ProgramCounterOutOfBounds {
+                pc: target.max(0) as usize,
+            });
+        }
+        self.pc = target as usize;
+        Ok(())
     }
 
     pub fn add_byte(&mut self, byte: u8) {
@@ -56,207 +355,525 @@ impl VM {
         }
     }
 
-    /// Loops as long as instructions can be executed.
-    pub fn run(&mut self) {
+    /// Loops as long as instructions can be executed. Resumes a program started by `run_for`, and returns why the
+    /// program stopped, or the error it stopped with, again once it has
+    pub fn run(&mut self) -> Result<ExitReason, VmError> {
+        self.start()?;
+        if let Some(reason) = self.stopped()? {
+            return Ok(reason);
+        }
+
         // main exec loop, performance-critical
-        let mut is_not_done = self.verify_header();
+        loop {
+            match self.run_once()? {
+                ExitReason::Continue => {}
+                reason => return Ok(reason),
+            }
+        }
+    }
+
+    /// Executes at most `max_instructions` instructions, so a host can share its thread between many VMs. The first
+    /// call starts at the entry point and later calls resume where the last one stopped. Once the program has
+    /// finished, every call returns `Completed` with the same reason without executing anything, and once it has
+    /// failed, the same error
+    pub fn run_for(&mut self, max_instructions: u64) -> Result<RunOutcome, VmError> {
+        self.run_for_until(max_instructions, None)
+    }
+
+    /// Like `run_for`, also stopping with `BudgetExhausted` once `deadline` has passed. The clock is only looked at
+    /// every `DEADLINE_CHECK_INTERVAL` instructions, so a run can go a little past it
+    pub fn run_for_until(
+        &mut self,
+        max_instructions: u64,
+        deadline: Option<Instant>,
+    ) -> Result<RunOutcome, VmError> {
+        self.start()?;
+        if let Some(reason) = self.stopped()? {
+            return Ok(RunOutcome::Completed(reason));
+        }
+        for executed in 0..max_instructions {
+            if executed % DEADLINE_CHECK_INTERVAL == 0
+                && deadline.is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                return Ok(RunOutcome::BudgetExhausted);
+            }
+            match self.run_once()? {
+                ExitReason::Continue => {}
+                reason => return Ok(RunOutcome::Completed(reason)),
+            }
+        }
+        Ok(RunOutcome::BudgetExhausted)
+    }
 
-        if is_not_done {
-            self.pc += 65;
-        } else {
-            println!("The header is not current");
-            std::process::exit(1);
+    /// Reads the header and moves the program counter to the entry point, unless a run already did
+    pub fn start(&mut self) -> Result<(), VmError> {
+        if !self.started {
+            self.pc = self.load_header()?;
+            self.started = true;
         }
+        Ok(())
+    }
 
-        while is_not_done {
-            is_not_done = self.execute_instruction();
+    /// The offset of the next instruction to execute
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Moves the program counter, refusing offsets outside of the code. A finished or failed program runs again from
+    /// there
+    pub fn set_pc(&mut self, pc: usize) -> Result<(), VmError> {
+        if pc < self.code_start || pc > self.code_end() {
+            return Err(VmError::ProgramCounterOutOfBounds { pc });
         }
+        self.pc = pc;
+        self.finished = None;
+        self.faulted = None;
+        Ok(())
     }
 
-    /// Executes one instruction. Meant to allow for more controlled execution of the VM
-    pub fn run_once(&mut self) {
-        self.execute_instruction();
+    /// Why the program stopped, once a run or a step has executed its `HLT` or reached the end of the code
+    pub fn finished(&self) -> Option<ExitReason> {
+        self.finished
     }
 
-    fn execute_instruction(&mut self) -> bool {
-        if self.pc >= self.program.len() {
-            return false;
+    /// The error the program stopped with, once a run or a step has failed
+    pub fn faulted(&self) -> Option<&VmError> {
+        self.faulted.as_ref()
+    }
+
+    /// Why the program stopped, or the error it stopped with, if it has
+    fn stopped(&self) -> Result<Option<ExitReason>, VmError> {
+        match &self.faulted {
+            Some(error) => Err(error.clone()),
+            None => Ok(self.finished),
         }
+    }
+
+    /// The flag the last comparison set, which `jeq` and `jneq` test
+    pub fn equal_flag(&self) -> bool {
+        self.equal_flag
+    }
+
+    pub fn set_equal_flag(&mut self, flag: bool) {
+        self.equal_flag = flag;
+    }
+
+    /// Whether the last arithmetic instruction overflowed, which `jov` and `jnov` test
+    pub fn overflow_flag(&self) -> bool {
+        self.overflow_flag
+    }
+
+    pub fn set_overflow_flag(&mut self, flag: bool) {
+        self.overflow_flag = flag;
+    }
+
+    pub fn heap(&self) -> &[u8] {
+        &self.heap
+    }
 
-        match self.decode_opcode() {
+    /// The heap, to change its bytes. Only `ALOC` changes its size
+    pub fn heap_mut(&mut self) -> &mut [u8] {
+        &mut self.heap
+    }
+
+    /// The number of values on the call stack, which grows with every `CALL` and `PUSH`
+    pub fn stack_depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// Where the code starts, the whole program being code until the header has been read
+    pub fn code_start(&self) -> usize {
+        self.code_start
+    }
+
+    /// Where the code ends, the whole program being code until the header has been read
+    pub fn code_end(&self) -> usize {
+        self.code_end.unwrap_or(self.program.len())
+    }
+
+    /// Executes one instruction. Meant to allow for more controlled execution of the VM. Unlike the other runs, this
+    /// executes whatever is at the program counter even after the program has stopped, such as code the REPL added
+    /// after an error
+    pub fn run_once(&mut self) -> Result<ExitReason, VmError> {
+        match self.execute_instruction() {
+            Ok(ExitReason::Continue) => Ok(ExitReason::Continue),
+            Ok(reason) => {
+                self.finished = Some(reason);
+                Ok(reason)
+            }
+            Err(error) => {
+                self.faulted = Some(error.clone());
+                Err(error)
+            }
+        }
+    }
+
+    fn execute_instruction(&mut self) -> Result<ExitReason, VmError> {
+        if self.pc >= self.code_end() {
+            return Ok(ExitReason::EndOfProgram);
+        }
+
+        let pc = self.pc;
+        let instruction = self.decode_instruction(pc)?;
+        // Operands in the order the opcode table lists them, registers have already been range checked
+        let [a, b, c] = instruction.operands;
+        match instruction.opcode {
             Opcode::HLT => {
-                println!("HLT encountered");
-                return false;
+                return Ok(ExitReason::Halted);
             }
             Opcode::LOAD => {
-                let register = self.next_8_bits() as usize; // We cast to usize so we can use it as an index into the array
-                let number = self.next_16_bits() as u16;
-                self.registers[register] = number as i32; // Our registers are i32s, so we need to cast it. We'll cover that later.
+                // The immediate is sign-extended, so `load $0 #-1` gives -1
+                self.set_register(a, b as i16 as i32);
             }
             Opcode::ADD => {
-                let reg1 = self.registers[self.next_8_bits() as usize];
-                let reg2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = reg1 + reg2;
+                let (reg1, reg2) = (self.register(a), self.register(b));
+                let value =
+                    self.arithmetic(pc, reg1.overflowing_add(reg2), reg1.saturating_add(reg2))?;
+                self.set_register(c, value);
             }
             Opcode::SUB => {
-                let reg1 = self.registers[self.next_8_bits() as usize];
-                let reg2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = reg1 - reg2;
+                let (reg1, reg2) = (self.register(a), self.register(b));
+                let value =
+                    self.arithmetic(pc, reg1.overflowing_sub(reg2), reg1.saturating_sub(reg2))?;
+                self.set_register(c, value);
             }
             Opcode::MUL => {
-                let reg1 = self.registers[self.next_8_bits() as usize];
-                let reg2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = reg1 * reg2;
+                let (reg1, reg2) = (self.register(a), self.register(b));
+                let value =
+                    self.arithmetic(pc, reg1.overflowing_mul(reg2), reg1.saturating_mul(reg2))?;
+                self.set_register(c, value);
             }
             Opcode::DIV => {
-                let reg1 = self.registers[self.next_8_bits() as usize];
-                let reg2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = reg1 / reg2;
-                self.remainder = (reg1 % reg2) as u32;
-            }
-            Opcode::JMP => {
-                let target = self.registers[self.next_8_bits() as usize];
-                self.pc = target as usize;
+                let reg1 = self.register(a);
+                let reg2 = self.register(b);
+                if reg2 == 0 {
+                    return Err(VmError::DivisionByZero { pc });
+                }
+                // Only `i32::MIN / -1` overflows
+                let value =
+                    self.arithmetic(pc, reg1.overflowing_div(reg2), reg1.saturating_div(reg2))?;
+                self.set_register(c, value);
+                self.remainder = reg1.wrapping_rem(reg2) as u32;
             }
             Opcode::JMPF => {
-                let value = self.registers[self.next_8_bits() as usize];
-                self.pc += value as usize;
+                self.jump_to(self.pc as i64 + self.register(a) as i64)?;
             }
             Opcode::JMPB => {
-                let value = self.registers[self.next_8_bits() as usize];
-                self.pc -= value as usize;
+                self.jump_to(self.pc as i64 - self.register(a) as i64)?;
             }
             // $EQ r0, r1, None
-            Opcode::EQ => {
-                let reg1 = self.registers[self.next_8_bits() as usize];
-                let reg2 = self.registers[self.next_8_bits() as usize];
-
-                self.equal_flag = reg1 == reg2;
-                self.next_8_bits();
-            }
-            Opcode::NEQ => {
-                let reg1 = self.registers[self.next_8_bits() as usize];
-                let reg2 = self.registers[self.next_8_bits() as usize];
-
-                self.equal_flag = reg1 != reg2;
-                self.next_8_bits();
+            Opcode::EQ | Opcode::NEQ | Opcode::GT | Opcode::LT | Opcode::GTE | Opcode::LTE => {
+                self.compare(
+                    instruction.opcode,
+                    Some(self.register(a).cmp(&self.register(b))),
+                );
             }
-            Opcode::GT => {
-                let reg1 = self.registers[self.next_8_bits() as usize];
-                let reg2 = self.registers[self.next_8_bits() as usize];
-
-                self.equal_flag = reg1 > reg2;
-                self.next_8_bits();
-            }
-            Opcode::LT => {
-                let reg1 = self.registers[self.next_8_bits() as usize];
-                let reg2 = self.registers[self.next_8_bits() as usize];
-
-                self.equal_flag = reg1 < reg2;
-                self.next_8_bits();
-            }
-            Opcode::GTE => {
-                let reg1 = self.registers[self.next_8_bits() as usize];
-                let reg2 = self.registers[self.next_8_bits() as usize];
-
-                self.equal_flag = reg1 >= reg2;
-                self.next_8_bits();
+            Opcode::EQF
+            | Opcode::NEQF
+            | Opcode::GTF
+            | Opcode::LTF
+            | Opcode::GTEF
+            | Opcode::LTEF => {
+                let ordering = self.float_register(a).partial_cmp(&self.float_register(b));
+                self.compare(instruction.opcode, ordering);
             }
-            Opcode::LTE => {
-                let reg1 = self.registers[self.next_8_bits() as usize];
-                let reg2 = self.registers[self.next_8_bits() as usize];
-
-                self.equal_flag = reg1 <= reg2;
-                self.next_8_bits();
-            }
-            Opcode::JEQ => {
-                let reg = self.next_8_bits() as usize;
-                let target = self.registers[reg];
-                if self.equal_flag {
-                    self.pc = target as usize;
+            Opcode::JMP
+            | Opcode::JEQ
+            | Opcode::JNEQ
+            | Opcode::JGT
+            | Opcode::JLT
+            | Opcode::JGE
+            | Opcode::JLE
+            | Opcode::JMPI
+            | Opcode::JEQI
+            | Opcode::JNEQI
+            | Opcode::JGTI
+            | Opcode::JLTI
+            | Opcode::JGEI
+            | Opcode::JLEI
+            | Opcode::JOV
+            | Opcode::JNOV
+            | Opcode::JOVI
+            | Opcode::JNOVI => {
+                let taken = match instruction.opcode {
+                    Opcode::JOV | Opcode::JOVI => self.overflow_flag,
+                    Opcode::JNOV | Opcode::JNOVI => !self.overflow_flag,
+                    Opcode::JEQ | Opcode::JEQI => self.equal_flag,
+                    Opcode::JNEQ | Opcode::JNEQI => !self.equal_flag,
+                    Opcode::JGT | Opcode::JGTI => self.comparison.is_some_and(Ordering::is_gt),
+                    Opcode::JLT | Opcode::JLTI => self.comparison.is_some_and(Ordering::is_lt),
+                    Opcode::JGE | Opcode::JGEI => self.comparison.is_some_and(Ordering::is_ge),
+                    Opcode::JLE | Opcode::JLEI => self.comparison.is_some_and(Ordering::is_le),
+                    _ => true,
+                };
+                if taken {
+                    // The direct forms hold the target itself, the others the register it is in
+                    let target = match instruction.opcode.direct_form() {
+                        Some(_) => self.register(a) as i64,
+                        None => a as i64,
+                    };
+                    self.jump_to(target)?;
                 }
             }
-            Opcode::NOP => {
-                self.next_8_bits();
-                self.next_8_bits();
-                self.next_8_bits();
-            }
+            Opcode::NOP => {}
             Opcode::ALOC => {
-                let reg = self.next_8_bits() as usize;
-                let bytes = self.registers[reg];
-                let new_end = self.heap.len() as i32 + bytes;
+                let bytes = self.register(a);
+                let new_end = self.heap.len() as i64 + bytes as i64;
+                if new_end < 0 || new_end as usize > MAX_HEAP_SIZE {
+                    return Err(VmError::HeapOverflow {
+                        pc,
+                        requested: bytes,
+                    });
+                }
                 self.heap.resize(new_end as usize, 0);
             }
             Opcode::INC => {
-                let reg = self.next_8_bits() as usize;
-                let target = self.registers[reg];
-                self.registers[reg] = target + 1;
+                let reg = self.register(a);
+                let value = self.arithmetic(pc, reg.overflowing_add(1), reg.saturating_add(1))?;
+                self.set_register(a, value);
             }
             Opcode::DEC => {
-                let reg = self.next_8_bits() as usize;
-                let target = self.registers[reg];
-                self.registers[reg] = target - 1;
+                let reg = self.register(a);
+                let value = self.arithmetic(pc, reg.overflowing_sub(1), reg.saturating_sub(1))?;
+                self.set_register(a, value);
             }
             Opcode::PRTS => {
                 // PRTS takes one operand, either a starting index in the read-only section of the bytecode
                 // or a symbol (in the form of @symbol_name), which will look up the offset in the symbol table.
                 // This instruction then reads each byte and prints it, until it comes to a 0x00 byte, which indicates
                 // termination of the string
-                let starting_offset = self.next_16_bits() as usize;
-                let mut ending_offset = starting_offset;
+                let starting_offset = a as usize;
                 let slice = self.ro_data.as_slice();
                 // TODO: Find a better way to do this. Maybe we can store the byte length and not null terminate? Or some form of caching where we
                 // go through the entire ro_data on VM startup and find every string and its ending byte location?
-                while slice[ending_offset] != 0 {
-                    ending_offset += 1;
+                let length = slice
+                    .get(starting_offset..)
+                    .and_then(|rest| rest.iter().position(|byte| *byte == 0))
+                    .ok_or(VmError::ReadOnlyDataOutOfBounds {
+                        pc,
+                        offset: starting_offset,
+                    })?;
+                let ending_offset = starting_offset + length;
+                self.output
+                    .write_all(&slice[starting_offset..ending_offset])
+                    .map_err(|e| VmError::Output { pc, kind: e.kind() })?;
+            }
+            Opcode::CALL => {
+                // The return address and the caller's frame pointer go on the stack, and the callee's frame
+                // starts right after them
+                self.push(pc, self.pc as i32)?;
+                self.push(pc, self.fp as i32)?;
+                self.fp = self.stack.len();
+                self.jump_to(a as i64)?;
+            }
+            Opcode::RET => {
+                // Everything the callee pushed is dropped along with its frame
+                if self.fp < 2 {
+                    return Err(VmError::StackUnderflow { pc });
+                }
+                self.stack.truncate(self.fp);
+                self.fp = self.stack.pop().unwrap_or_default() as usize;
+                let return_address = self.stack.pop().unwrap_or_default();
+                self.jump_to(return_address as i64)?;
+            }
+            Opcode::PUSH => {
+                self.push(pc, self.register(a))?;
+            }
+            Opcode::POP => {
+                if self.stack.len() <= self.fp {
+                    return Err(VmError::StackUnderflow { pc });
                 }
-                let result = std::str::from_utf8(&slice[starting_offset..ending_offset]);
-                match result {
-                    Ok(s) => {
-                        print!("{}", s);
-                    }
-                    Err(e) => {
-                        println!("Error decoding string for prts instruction: {:#?}", e)
-                    }
+                let value = self.stack.pop().unwrap_or_default();
+                self.set_register(a, value);
+            }
+            // Heap words and halfwords are little endian, loads of less than a word are zero extended
+            Opcode::LB => {
+                let range = self.heap_range(pc, b, c, 1)?;
+                self.set_register(a, self.heap[range.start] as i32);
+            }
+            Opcode::LH => {
+                let range = self.heap_range(pc, b, c, 2)?;
+                self.set_register(a, LittleEndian::read_u16(&self.heap[range]) as i32);
+            }
+            Opcode::LW => {
+                let range = self.heap_range(pc, b, c, 4)?;
+                self.set_register(a, LittleEndian::read_i32(&self.heap[range]));
+            }
+            Opcode::SB => {
+                let range = self.heap_range(pc, b, c, 1)?;
+                self.heap[range.start] = self.register(a) as u8;
+            }
+            Opcode::SH => {
+                let range = self.heap_range(pc, b, c, 2)?;
+                let value = self.register(a) as u16;
+                LittleEndian::write_u16(&mut self.heap[range], value);
+            }
+            Opcode::SW => {
+                let range = self.heap_range(pc, b, c, 4)?;
+                let value = self.register(a);
+                LittleEndian::write_i32(&mut self.heap[range], value);
+            }
+            Opcode::LUI => {
+                let low = self.register(a) as u32 & 0xFFFF;
+                self.set_register(a, ((b as u32) << 16 | low) as i32);
+            }
+            Opcode::AND => {
+                self.set_register(c, self.register(a) & self.register(b));
+            }
+            Opcode::OR => {
+                self.set_register(c, self.register(a) | self.register(b));
+            }
+            Opcode::XOR => {
+                self.set_register(c, self.register(a) ^ self.register(b));
+            }
+            Opcode::NOT => {
+                self.set_register(b, !self.register(a));
+            }
+            // Shift amounts are taken modulo 32
+            Opcode::SHL => {
+                let shift = self.register(b) as u32;
+                self.set_register(c, self.register(a).wrapping_shl(shift));
+            }
+            Opcode::SHR => {
+                let shift = self.register(b) as u32;
+                self.set_register(c, (self.register(a) as u32).wrapping_shr(shift) as i32);
+            }
+            Opcode::SAR => {
+                let shift = self.register(b) as u32;
+                self.set_register(c, self.register(a).wrapping_shr(shift));
+            }
+            Opcode::REM | Opcode::MOD => {
+                let reg1 = self.register(a);
+                let reg2 = self.register(b);
+                if reg2 == 0 {
+                    return Err(VmError::DivisionByZero { pc });
+                }
+                let result = match instruction.opcode {
+                    Opcode::REM => reg1.wrapping_rem(reg2),
+                    _ => reg1.wrapping_rem_euclid(reg2),
                 };
+                self.set_register(c, result);
+            }
+            Opcode::NEG => {
+                let reg = self.register(a);
+                let value = self.arithmetic(pc, reg.overflowing_neg(), reg.saturating_neg())?;
+                self.set_register(b, value);
+            }
+            Opcode::MOVR => {
+                self.set_register(a, self.remainder as i32);
+            }
+            Opcode::LOADF => {
+                self.set_float_register(a, b as i16 as f64);
+            }
+            Opcode::FSET => {
+                let shift = (c % 4) * 16;
+                let bits = self.float_register(a).to_bits() & !(0xFFFF << shift);
+                self.set_float_register(a, f64::from_bits(bits | (b as u64) << shift));
+            }
+            Opcode::LDRF => {
+                let offset = b as usize;
+                let bytes = self
+                    .ro_data
+                    .get(offset..offset + 8)
+                    .ok_or(VmError::ReadOnlyDataOutOfBounds { pc, offset })?;
+                self.set_float_register(a, LittleEndian::read_f64(bytes));
+            }
+            // Floats follow IEEE 754, so dividing by zero gives an infinity or NaN rather than an error
+            Opcode::ADDF => {
+                self.set_float_register(c, self.float_register(a) + self.float_register(b));
+            }
+            Opcode::SUBF => {
+                self.set_float_register(c, self.float_register(a) - self.float_register(b));
+            }
+            Opcode::MULF => {
+                self.set_float_register(c, self.float_register(a) * self.float_register(b));
+            }
+            Opcode::DIVF => {
+                self.set_float_register(c, self.float_register(a) / self.float_register(b));
             }
-            _ => {
-                println!("Unrecognized opcode found! Terminating!");
-                return false;
+            Opcode::ITOF => {
+                self.set_float_register(b, self.register(a) as f64);
+            }
+            Opcode::FTOI => {
+                self.set_register(b, self.float_register(a) as i32);
+            }
+            Opcode::IGL => {
+                // decode_instruction never returns IGL, it reports the byte as an error instead
+                return Err(VmError::IllegalOpcode {
+                    pc,
+                    byte: Opcode::IGL as u8,
+                });
             }
         }
 
-        true
+        Ok(ExitReason::Continue)
     }
 
-    fn verify_header(&self) -> bool {
-        if self.program[0..4] != PIE_HEADER_PREFIX {
-            return false;
+    /// Reads the PIE header, copies the read-only section into `ro_data` and returns the entry point
+    fn load_header(&mut self) -> Result<usize, VmError> {
+        let header = pie::read_pie(&self.program).map_err(|error| match error {
+            PieError::UnsupportedVersion { version } => VmError::UnsupportedVersion { version },
+            error => VmError::BadHeader { error },
+        })?;
+        // Objects have to be linked before their addresses mean anything
+        if header.flags & PIE_FLAG_OBJECT != 0 {
+            return Err(VmError::BadHeader {
+                error: PieError::IsObject,
+            });
         }
-        true
+
+        self.ro_data = match header.section(SectionKind::ReadOnly) {
+            Some(section) => self.program[section.range()].to_vec(),
+            None => vec![],
+        };
+        // read_pie makes sure there is a code section
+        if let Some(code) = header.section(SectionKind::Code) {
+            self.code_start = code.range().start;
+            self.code_end = Some(code.range().end);
+        }
+        Ok(header.entry_point as usize)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::assembler::{Assembler, PIE_HEADER_LENGTH};
+    use std::sync::{Arc, Mutex};
 
     fn get_test_vm() -> VM {
         VM::new()
     }
 
-    fn prepend_header(mut b: Vec<u8>) -> Vec<u8> {
-        let mut prepension = vec![];
-        for byte in PIE_HEADER_PREFIX.into_iter() {
-            prepension.push(byte.clone());
+    /// An output the test can read back after the VM wrote to it
+    #[derive(Clone, Default)]
+    struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
         }
-        while prepension.len() <= PIE_HEADER_LENGTH {
-            prepension.push(0);
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
         }
-        prepension.append(&mut b);
-        prepension
     }
 
+    fn prepend_header(b: Vec<u8>) -> Vec<u8> {
+        prepend_header_with_ro(&[], b)
+    }
+
+    fn prepend_header_with_ro(ro: &[u8], b: Vec<u8>) -> Vec<u8> {
+        pie::write_pie(
+            0,
+            0,
+            &[(SectionKind::ReadOnly, ro), (SectionKind::Code, &b)],
+        )
+    }
+
+    /// Where the code of a test image starts
+    const CODE_START: usize = PIE_HEADER_LENGTH + 2 * pie::PIE_SECTION_ENTRY_LENGTH;
+
     #[test]
     fn test_create_vm() {
         let test_vm = VM::new();
@@ -269,8 +886,8 @@ mod tests {
         let test_bytes = vec![5, 0, 0, 0];
         test_vm.program = test_bytes;
         test_vm.program = prepend_header(test_vm.program);
-        test_vm.run();
-        assert_eq!(test_vm.pc, 66);
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.pc, CODE_START + 1);
     }
 
     #[test]
@@ -279,8 +896,98 @@ mod tests {
         let test_bytes = vec![200, 0, 0, 0];
         test_vm.program = test_bytes;
         test_vm.program = prepend_header(test_vm.program);
-        test_vm.run();
-        assert_eq!(test_vm.pc, 66);
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::IllegalOpcode {
+                pc: CODE_START,
+                byte: 200
+            })
+        );
+        assert_eq!(test_vm.pc, CODE_START + 1);
+    }
+
+    #[test]
+    fn test_bad_header() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![5, 0, 0, 0];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::BadHeader {
+                error: PieError::BadMagic
+            })
+        );
+    }
+
+    #[test]
+    fn test_truncated_header() {
+        let mut test_vm = VM::new();
+        let mut program = prepend_header_with_ro(&[1, 2, 3], vec![5]);
+        program.truncate(PIE_HEADER_LENGTH + 9);
+        test_vm.program = program;
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::BadHeader {
+                error: PieError::Truncated
+            })
+        );
+    }
+
+    #[test]
+    fn test_refuses_objects() {
+        let mut test_vm = VM::new();
+        test_vm.program = Assembler::new()
+            .assemble_object(".data\n.code\nhlt\n")
+            .unwrap()
+            .to_bytes();
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::BadHeader {
+                error: PieError::IsObject
+            })
+        );
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let mut test_vm = VM::new();
+        test_vm.program = prepend_header(vec![5]);
+        test_vm.program[4] = 99;
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::UnsupportedVersion { version: 99 })
+        );
+    }
+
+    #[test]
+    fn test_stops_at_end_of_code() {
+        let mut test_vm = VM::new();
+        // The symbols section after the code must not be executed
+        test_vm.program = pie::write_pie(
+            0,
+            0,
+            &[
+                (SectionKind::Code, &[18, 0]),
+                (SectionKind::Symbols, &[200]),
+            ],
+        );
+        assert_eq!(test_vm.run(), Ok(ExitReason::EndOfProgram));
+        assert_eq!(test_vm.registers[0], 1);
+    }
+
+    #[test]
+    fn test_ro_data_loaded() {
+        let mut test_vm = VM::new();
+        test_vm.program = prepend_header_with_ro(b"Hi\0", vec![5]);
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.ro_data, b"Hi\0".to_vec());
+        assert_eq!(test_vm.pc, CODE_START + 3 + 1);
+    }
+
+    #[test]
+    fn test_end_of_program() {
+        let mut test_vm = VM::new();
+        test_vm.program = prepend_header(vec![0, 0, 1, 244]);
+        assert_eq!(test_vm.run(), Ok(ExitReason::EndOfProgram));
     }
 
     #[test]
@@ -288,16 +995,29 @@ mod tests {
         let mut test_vm = get_test_vm();
         test_vm.program = vec![0, 0, 1, 244]; // Remember, this is how we represent 500 using two u8s in little endian format
         test_vm.program = prepend_header(test_vm.program);
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[0], 500);
     }
 
+    #[test]
+    fn test_load_sign_extends_and_lui() {
+        let mut test_vm = get_test_vm();
+        // load $0 #-1, load $1 #0x5678, lui $1 #0x1234, load $2 #-2, lui $2 #0
+        test_vm.program = prepend_header(vec![
+            0, 0, 255, 255, 0, 1, 0x56, 0x78, 31, 1, 0x12, 0x34, 0, 2, 255, 254, 31, 2, 0, 0,
+        ]);
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], -1);
+        assert_eq!(test_vm.registers[1], 0x1234_5678);
+        assert_eq!(test_vm.registers[2], 0xFFFE);
+    }
+
     #[test]
     fn test_add_opcode() {
         let mut test_vm = get_test_vm();
         test_vm.program = vec![0, 0, 1, 244, 0, 1, 1, 244, 1, 0, 1, 2]; // Remember, this is how we represent 500 using two u8s in little endian format
         test_vm.program = prepend_header(test_vm.program);
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[2], 1000);
     }
 
@@ -306,7 +1026,7 @@ mod tests {
         let mut test_vm = get_test_vm();
         test_vm.registers[0] = 1;
         test_vm.program = vec![6, 0, 0, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pc, 1);
     }
 
@@ -315,7 +1035,7 @@ mod tests {
         let mut test_vm = get_test_vm();
         test_vm.registers[0] = 2;
         test_vm.program = vec![7, 0, 0, 0, 6, 0, 0, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pc, 4);
     }
 
@@ -325,11 +1045,11 @@ mod tests {
         test_vm.registers[0] = 10;
         test_vm.registers[1] = 10;
         test_vm.program = vec![9, 0, 1, 0, 9, 0, 1, 0];
-        test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, true);
+        test_vm.run_once().unwrap();
+        assert!(test_vm.equal_flag);
         test_vm.registers[1] = 20;
-        test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, false);
+        test_vm.run_once().unwrap();
+        assert!(!test_vm.equal_flag);
     }
 
     #[test]
@@ -338,7 +1058,7 @@ mod tests {
         test_vm.registers[0] = 7;
         test_vm.equal_flag = true;
         test_vm.program = vec![15, 0, 0, 0, 17, 0, 0, 0, 17, 0, 0, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pc, 7);
     }
 
@@ -347,7 +1067,7 @@ mod tests {
         let mut test_vm = get_test_vm();
         test_vm.registers[0] = 1024;
         test_vm.program = vec![17, 0, 0, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.heap.len(), 1024);
     }
 
@@ -356,7 +1076,473 @@ mod tests {
         let mut test_vm = get_test_vm();
         test_vm.program = vec![0, 0, 0, 2, 0, 1, 0, 25, 3, 0, 1, 2];
         test_vm.program = prepend_header(test_vm.program);
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[2], 50);
     }
+
+    #[test]
+    fn test_prts_assembled_string() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble(
+                r"
+        .data
+        hello: .asciiz 'Hello'
+        world: .asciiz 'World'
+        .code
+        prts @world
+        hlt
+        ",
+            )
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        let output = SharedOutput::default();
+        test_vm.set_output(output.clone());
+        test_vm.add_bytes(program);
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.ro_data, b"Hello\0World\0".to_vec());
+        assert_eq!(*output.0.lock().unwrap(), b"World".to_vec());
+    }
+
+    #[test]
+    fn test_call_ret_opcodes() {
+        let mut test_vm = get_test_vm();
+        // call 5; hlt; (pad) inc $0; ret
+        test_vm.program = vec![21, 0, 5, 5, 0, 18, 0, 22];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 5);
+        assert_eq!(test_vm.stack, vec![3, 0]);
+        assert_eq!(test_vm.fp, 2);
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 3);
+        assert_eq!(test_vm.fp, 0);
+        assert!(test_vm.stack.is_empty());
+        assert_eq!(test_vm.run_once(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.registers[0], 1);
+    }
+
+    #[test]
+    fn test_ret_drops_callee_frame() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[1] = 42;
+        // push $1; call 6; hlt; push $1; push $1; ret
+        test_vm.program = vec![23, 1, 21, 0, 6, 5, 23, 1, 23, 1, 22];
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.stack.len(), 5);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.stack, vec![42]);
+        assert_eq!(test_vm.pc, 5);
+    }
+
+    #[test]
+    fn test_push_pop_opcodes() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = 7;
+        test_vm.program = vec![23, 0, 24, 1];
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[1], 7);
+        assert!(test_vm.stack.is_empty());
+    }
+
+    #[test]
+    fn test_stack_underflow() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![24, 0];
+        assert_eq!(test_vm.run_once(), Err(VmError::StackUnderflow { pc: 0 }));
+
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![22];
+        assert_eq!(test_vm.run_once(), Err(VmError::StackUnderflow { pc: 0 }));
+
+        // A callee cannot pop the caller's values
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![23, 0, 21, 0, 5, 24, 0];
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.run_once(), Err(VmError::StackUnderflow { pc: 5 }));
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        let mut test_vm = get_test_vm();
+        // A function that calls itself forever
+        test_vm.program = vec![21, 0, 0];
+        let result = loop {
+            if let Err(e) = test_vm.run_once() {
+                break e;
+            }
+        };
+        assert_eq!(result, VmError::StackOverflow { pc: 0 });
+        assert_eq!(test_vm.stack.len(), MAX_STACK_SIZE);
+    }
+
+    #[test]
+    fn test_heap_store_and_load() {
+        let mut test_vm = get_test_vm();
+        test_vm.heap = vec![0; 8];
+        test_vm.registers[0] = 0x1234_5678;
+        test_vm.registers[1] = 2;
+        // sw $0 $1 #2; lw $2 $1 #2; lh $3 $1 #2; lb $4 $1 #-2; sb $0 $1 #-2
+        test_vm.program = vec![
+            30, 0, 1, 0, 2, 27, 2, 1, 0, 2, 26, 3, 1, 0, 2, 25, 4, 1, 255, 254, 28, 0, 1, 255, 254,
+        ];
+        for _ in 0..5 {
+            test_vm.run_once().unwrap();
+        }
+        assert_eq!(test_vm.heap, vec![0x78, 0, 0, 0, 0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(test_vm.registers[2], 0x1234_5678);
+        assert_eq!(test_vm.registers[3], 0x5678);
+        assert_eq!(test_vm.registers[4], 0);
+    }
+
+    #[test]
+    fn test_heap_out_of_bounds() {
+        let mut test_vm = get_test_vm();
+        test_vm.heap = vec![0; 4];
+        test_vm.registers[1] = 2;
+        // lw $0 $1 #0; sb $0 $1 #-3
+        test_vm.program = vec![27, 0, 1, 0, 0, 28, 0, 1, 255, 253];
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::HeapOutOfBounds {
+                pc: 0,
+                address: 2,
+                size: 4
+            })
+        );
+        test_vm.pc = 5;
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::HeapOutOfBounds {
+                pc: 5,
+                address: -1,
+                size: 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_register_out_of_range() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![18, 32];
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::RegisterOutOfRange {
+                pc: 0,
+                register: 32
+            })
+        );
+    }
+
+    #[test]
+    fn test_truncated_instruction() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![0, 0, 1];
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::ProgramCounterOutOfBounds { pc: 3 })
+        );
+    }
+
+    #[test]
+    fn test_jmp_out_of_bounds() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = 100;
+        test_vm.program = vec![6, 0, 0, 0];
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::ProgramCounterOutOfBounds { pc: 100 })
+        );
+    }
+
+    #[test]
+    fn test_jmp_before_code() {
+        let program = Assembler::new()
+            .assemble(".data\nhi: .asciiz 'Hi'\n.code\nload $0 #0\njmp $0\n")
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        test_vm.add_bytes(program);
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::ProgramCounterOutOfBounds { pc: 0 })
+        );
+        let code_start = test_vm.code_start();
+        assert_eq!(
+            test_vm.set_pc(code_start - 1),
+            Err(VmError::ProgramCounterOutOfBounds { pc: code_start - 1 })
+        );
+        assert_eq!(test_vm.set_pc(code_start), Ok(()));
+    }
+
+    #[test]
+    fn test_bitwise_opcodes() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = 0b1100;
+        test_vm.registers[1] = 0b1010;
+        // and, or, xor, not
+        test_vm.program = prepend_header(vec![32, 0, 1, 2, 33, 0, 1, 3, 34, 0, 1, 4, 35, 0, 5, 0]);
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2..6], [0b1000, 0b1110, 0b0110, !0b1100]);
+    }
+
+    #[test]
+    fn test_shift_opcodes() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = -16;
+        test_vm.registers[1] = 2;
+        test_vm.registers[2] = 33;
+        // shl, shr, sar, and shl by 33 which shifts by 1
+        test_vm.program = prepend_header(vec![36, 0, 1, 3, 37, 0, 1, 4, 38, 0, 1, 5, 36, 0, 2, 6]);
+        test_vm.run().unwrap();
+        assert_eq!(
+            test_vm.registers[3..7],
+            [-64, (-16i32 as u32 >> 2) as i32, -4, -32]
+        );
+    }
+
+    #[test]
+    fn test_remainder_opcodes() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = -7;
+        test_vm.registers[1] = 3;
+        // rem, mod, neg, div then movr
+        test_vm.program = prepend_header(vec![
+            39, 0, 1, 2, 40, 0, 1, 3, 41, 0, 4, 0, 4, 0, 1, 5, 42, 6,
+        ]);
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2..7], [-1, 2, 7, -2, -1]);
+
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = 10;
+        test_vm.program = vec![40, 0, 1, 2];
+        assert_eq!(test_vm.run_once(), Err(VmError::DivisionByZero { pc: 0 }));
+    }
+
+    #[test]
+    fn test_arithmetic_modes() {
+        for (program, left, right, wrapped, saturated) in [
+            (vec![1, 0, 1, 2], i32::MAX, 1, i32::MIN, i32::MAX),
+            (vec![2, 0, 1, 2], i32::MIN, 1, i32::MAX, i32::MIN),
+            (vec![3, 0, 1, 2], i32::MAX, 2, -2, i32::MAX),
+            (vec![4, 0, 1, 2], i32::MIN, -1, i32::MIN, i32::MAX),
+            (vec![41, 0, 2, 0], i32::MIN, 0, i32::MIN, i32::MAX),
+        ] {
+            for (mode, expected) in [
+                (ArithmeticMode::Wrapping, Ok(wrapped)),
+                (ArithmeticMode::Saturating, Ok(saturated)),
+                (
+                    ArithmeticMode::Checked,
+                    Err(VmError::ArithmeticOverflow { pc: 0 }),
+                ),
+            ] {
+                let mut test_vm = get_test_vm();
+                test_vm.arithmetic_mode = mode;
+                test_vm.registers[0] = left;
+                test_vm.registers[1] = right;
+                test_vm.program = program.clone();
+                let result = test_vm.run_once().map(|_| test_vm.registers[2]);
+                assert_eq!(result, expected, "{:?} in {:?}", program, mode);
+                assert!(test_vm.overflow_flag);
+            }
+        }
+
+        let mut test_vm = get_test_vm();
+        test_vm.arithmetic_mode = ArithmeticMode::Checked;
+        test_vm.registers[0] = i32::MAX - 1;
+        // inc, then dec twice, which clears the flag
+        test_vm.program = vec![18, 0, 19, 0, 19, 0];
+        test_vm.run_once().unwrap();
+        assert!(!test_vm.overflow_flag);
+        assert_eq!(test_vm.registers[0], i32::MAX);
+        test_vm.registers[0] = i32::MIN;
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::ArithmeticOverflow { pc: 2 })
+        );
+    }
+
+    #[test]
+    fn test_float_comparisons_with_nan() {
+        let mut test_vm = get_test_vm();
+        test_vm.float_registers[0] = f64::NAN;
+        test_vm.float_registers[1] = 1.0;
+        // eqf, then neqf
+        test_vm.program = vec![66, 0, 1, 0, 67, 0, 1, 0];
+        test_vm.run_once().unwrap();
+        assert!(!test_vm.equal_flag);
+        assert_eq!(test_vm.comparison, None);
+        test_vm.run_once().unwrap();
+        assert!(test_vm.equal_flag);
+    }
+
+    #[test]
+    fn test_fset_and_ftoi() {
+        let mut test_vm = get_test_vm();
+        let bits = 1.25e300f64.to_bits();
+        let mut program = vec![];
+        for index in 0..4u16 {
+            let chunk = (bits >> (16 * index)) as u16;
+            program.extend_from_slice(&[60, 2, (chunk >> 8) as u8, chunk as u8, 0, index as u8]);
+        }
+        // ftoi saturates
+        program.extend_from_slice(&[73, 2, 0, 0]);
+        test_vm.program = prepend_header(program);
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.float_registers[2], 1.25e300);
+        assert_eq!(test_vm.registers[0], i32::MAX);
+    }
+
+    #[test]
+    fn test_run_for_resumes() {
+        let program = Assembler::new()
+            .assemble(".data\n.code\nload $1 #10\nloop: inc $0\neq $0 $1\njneq @loop\nhlt\n")
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        test_vm.add_bytes(program);
+        assert_eq!(test_vm.run_for(5), Ok(RunOutcome::BudgetExhausted));
+        assert_eq!(test_vm.registers[0], 2);
+        assert_eq!(test_vm.run_for(0), Ok(RunOutcome::BudgetExhausted));
+        assert_eq!(
+            test_vm.run_for(1000),
+            Ok(RunOutcome::Completed(ExitReason::Halted))
+        );
+        assert_eq!(test_vm.registers[0], 10);
+    }
+
+    #[test]
+    fn test_run_for_after_error() {
+        let program = Assembler::new()
+            .assemble(".data\n.code\nload $0 #1\ndiv $0 $1 $2\ninc $3\nhlt\n")
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        test_vm.add_bytes(program);
+        let error = test_vm.run_for(100).unwrap_err();
+        assert!(matches!(error, VmError::DivisionByZero { .. }));
+        assert_eq!(test_vm.run_for(100), Err(error.clone()));
+        assert_eq!(test_vm.run(), Err(error.clone()));
+        assert_eq!(test_vm.faulted(), Some(&error));
+        assert_eq!(test_vm.registers[3], 0);
+    }
+
+    #[test]
+    fn test_run_for_after_completion() {
+        let program = Assembler::new()
+            .assemble(".data\n.code\ninc $0\nhlt\ninc $0\nhlt\n")
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        test_vm.add_bytes(program);
+        assert_eq!(
+            test_vm.run_for(100),
+            Ok(RunOutcome::Completed(ExitReason::Halted))
+        );
+        assert_eq!(
+            test_vm.run_for(100),
+            Ok(RunOutcome::Completed(ExitReason::Halted))
+        );
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.registers[0], 1);
+    }
+
+    #[test]
+    fn test_run_resumes_run_for() {
+        let program = Assembler::new()
+            .assemble(".data\n.code\ninc $0\ninc $0\ninc $0\nhlt\n")
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        test_vm.add_bytes(program);
+        assert_eq!(test_vm.run_for(2), Ok(RunOutcome::BudgetExhausted));
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.registers[0], 3);
+    }
+
+    #[test]
+    fn test_run_for_until_deadline() {
+        let program = Assembler::new()
+            .assemble(".data\n.code\nloop: jmp @loop\n")
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        test_vm.add_bytes(program);
+        assert_eq!(test_vm.run_for(10_000), Ok(RunOutcome::BudgetExhausted));
+        let deadline = Instant::now() + std::time::Duration::from_millis(20);
+        assert_eq!(
+            test_vm.run_for_until(u64::MAX, Some(deadline)),
+            Ok(RunOutcome::BudgetExhausted)
+        );
+        assert!(Instant::now() >= deadline);
+    }
+
+    #[test]
+    fn test_div_by_zero() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = 10;
+        test_vm.program = vec![4, 0, 1, 2];
+        assert_eq!(test_vm.run_once(), Err(VmError::DivisionByZero { pc: 0 }));
+    }
+
+    #[test]
+    fn test_aloc_overflow() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = -1;
+        test_vm.program = vec![17, 0, 0, 0];
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::HeapOverflow {
+                pc: 0,
+                requested: -1
+            })
+        );
+        assert_eq!(test_vm.heap.len(), 0);
+    }
+
+    #[test]
+    fn test_prts_output() {
+        let mut test_vm = get_test_vm();
+        let output = SharedOutput::default();
+        test_vm.set_output(output.clone());
+        test_vm.ro_data = b"hi\0".to_vec();
+        test_vm.program = vec![20, 0, 0];
+        assert_eq!(test_vm.run_once(), Ok(ExitReason::Continue));
+        assert_eq!(*output.0.lock().unwrap(), b"hi".to_vec());
+    }
+
+    #[test]
+    fn test_prts_output_error() {
+        struct Closed;
+        impl Write for Closed {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::BrokenPipe.into())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut test_vm = get_test_vm();
+        test_vm.set_output(Closed);
+        test_vm.ro_data = b"hi\0".to_vec();
+        test_vm.program = vec![20, 0, 0];
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::Output {
+                pc: 0,
+                kind: io::ErrorKind::BrokenPipe
+            })
+        );
+    }
+
+    #[test]
+    fn test_prts_out_of_bounds() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![20, 0, 5];
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::ReadOnlyDataOutOfBounds { pc: 0, offset: 5 })
+        );
+    }
 }